@@ -0,0 +1,414 @@
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use bigdecimal::num_bigint::BigInt;
+use crate::lexer::{TokenValue, Span};
+use crate::units::{Unit, Dimension};
+use crate::natives::Registry;
+use crate::errors::LoadError;
+use crate::vm::{self, VM};
+use crate::verifier;
+
+/// first bytes of every compiled program
+pub const MAGIC: &[u8; 4] = b"MSC\0";
+
+/// version of the format `serialize` writes, `deserialize` rejects every other version
+pub const FORMAT_VERSION: u16 = 2;
+
+// largest scale and number of digits of a decimal in a compiled program, far beyond what scripts
+// write or compute but small enough to print
+const MAX_SCALE: i64 = 4096;
+const MAX_DIGITS: usize = 4096;
+
+// tags of the values in the constant pool
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_BOOL: u8 = 2;
+
+/// Writer struct, appends little-endian fields to a compiled program
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    /// an exact decimal, as the digits of its integer part and its scale
+    fn decimal(&mut self, value: &BigDecimal) {
+        let (int, scale) = value.as_bigint_and_exponent();
+        self.str(&int.to_string());
+        self.i64(scale);
+    }
+}
+
+/// Reader struct, reads the fields of a compiled program, failing rather than reading past its end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.position.checked_add(len).filter(|end| *end <= self.bytes.len()).ok_or(LoadError::Truncated)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, LoadError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, LoadError> {
+        let value = self.u64()?;
+        usize::try_from(value).map_err(|_| LoadError::Malformed(format!("'{}' does not fit this platform", value)))
+    }
+
+    /// the number of items in a section, each at least `size` bytes long
+    fn count(&mut self, size: usize) -> Result<usize, LoadError> {
+        let count = self.usize()?;
+        // rejects counts the rest of the file cannot hold before allocating for them
+        if count.saturating_mul(size) > self.bytes.len() - self.position {
+            return Err(LoadError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.count(1)?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::Malformed(String::from("a name is not valid UTF-8")))
+    }
+
+    fn decimal(&mut self) -> Result<BigDecimal, LoadError> {
+        let digits = self.string()?;
+        if digits.len() > MAX_DIGITS {
+            return Err(LoadError::Malformed(format!("a number has {} digits", digits.len())));
+        }
+        let int = BigInt::from_str(&digits).map_err(|_| LoadError::Malformed(format!("'{}' is not a number", digits)))?;
+        let scale = self.i64()?;
+        if !(-MAX_SCALE..=MAX_SCALE).contains(&scale) {
+            return Err(LoadError::Malformed(format!("a number has scale {}", scale)));
+        }
+        Ok(BigDecimal::new(int, scale))
+    }
+
+    /// a global slot, which must be one the program declared
+    fn slot(&mut self, globals: usize) -> Result<usize, LoadError> {
+        let slot = self.usize()?;
+        if slot >= globals {
+            return Err(LoadError::Malformed(format!("global slot {} is out of bounds", slot)));
+        }
+        Ok(slot)
+    }
+}
+
+/// writes a compiled program: the header, constant pool, instruction stream, line and span table,
+/// global symbols, the natives and constants it refers to by name and the units it defined
+pub fn serialize(vm: &VM) -> Vec<u8> {
+    let mut w = Writer { bytes: Vec::new() };
+    w.bytes.extend_from_slice(MAGIC);
+    w.u16(FORMAT_VERSION);
+
+    w.usize(vm.consts.len());
+    for value in vm.consts.iter() {
+        match value {
+            TokenValue::Number(n) => {
+                w.u8(TAG_NUMBER);
+                w.decimal(n);
+            }
+            TokenValue::String(s) => {
+                w.u8(TAG_STRING);
+                w.str(s);
+            }
+            TokenValue::Bool(b) => {
+                w.u8(TAG_BOOL);
+                w.u8(*b as u8);
+            }
+        }
+    }
+
+    w.usize(vm.instructions.len());
+    w.bytes.extend_from_slice(&vm.instructions);
+    for (line, span) in vm.lines.iter().zip(vm.spans.iter()) {
+        w.usize(*line);
+        for field in [span.start, span.end, span.line, span.column] {
+            w.usize(field);
+        }
+    }
+
+    w.usize(vm.globals);
+    let symbols = vm.global_symbols();
+    w.usize(symbols.len());
+    for (name, slot) in symbols {
+        w.str(name);
+        w.usize(slot);
+    }
+    w.usize(vm.natives.len());
+    for (slot, native) in vm.natives.iter() {
+        w.str(native.name);
+        w.usize(*slot);
+    }
+    w.usize(vm.constants.len());
+    for (slot, constant) in vm.constants.iter() {
+        w.str(constant.name);
+        w.usize(*slot);
+    }
+
+    let units = vm.units.defined();
+    w.usize(units.len());
+    for (name, unit, prefixable) in units {
+        w.str(name);
+        w.decimal(&unit.factor);
+        w.u8(prefixable as u8);
+        let exponents: Vec<(&str, i64)> = unit.dim.exponents().collect();
+        w.usize(exponents.len());
+        for (base, exp) in exponents {
+            w.str(base);
+            w.i64(exp);
+        }
+    }
+    w.bytes
+}
+
+/// checks for the magic bytes of a compiled program
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// reads a compiled program written by `serialize`, resolving the natives and constants it refers
+/// to in `registry`
+pub fn deserialize(bytes: &[u8], registry: &Registry) -> Result<VM, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+    let mut r = Reader { bytes, position: MAGIC.len() };
+    let version = r.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let mut vm = VM::new();
+    vm.registry = registry.clone();
+
+    for _ in 0..r.count(2)? {
+        let value = match r.u8()? {
+            TAG_NUMBER => { TokenValue::Number(r.decimal()?) }
+            TAG_STRING => { TokenValue::String(r.string()?) }
+            TAG_BOOL => {
+                match r.u8()? {
+                    0 => { TokenValue::Bool(false) }
+                    1 => { TokenValue::Bool(true) }
+                    flag => { return Err(LoadError::Malformed(format!("constant {} is not a boolean", flag))); }
+                }
+            }
+            tag => { return Err(LoadError::Malformed(format!("unknown constant tag {}", tag))); }
+        };
+        vm.consts.push(value);
+    }
+
+    let len = r.count(1)?;
+    vm.instructions = r.take(len)?.to_vec();
+    vm.starts = vm::decode(&vm.instructions)
+        .map_err(|at| LoadError::Malformed(format!("unknown or incomplete instruction at byte {}", at)))?
+        .iter().map(|(start, _)| *start).collect();
+    // every instruction has a line and a span, which need 40 bytes
+    if vm.starts.len().saturating_mul(40) > r.bytes.len() - r.position {
+        return Err(LoadError::Truncated);
+    }
+    for _ in 0..vm.starts.len() {
+        vm.lines.push(r.usize()?);
+        let (start, end, line, column) = (r.usize()?, r.usize()?, r.usize()?, r.usize()?);
+        vm.spans.push(Span { start, end, line, column });
+    }
+
+    // every global slot is named in the symbol table that follows
+    vm.globals = r.count(16)?;
+    for _ in 0..r.count(16)? {
+        let name = r.string()?;
+        let slot = r.slot(vm.globals)?;
+        vm.declare_global(name, slot);
+    }
+    for _ in 0..r.count(16)? {
+        let name = r.string()?;
+        let native = registry.get(&name).ok_or(LoadError::UnknownNative(name))?;
+        vm.natives.push((r.slot(vm.globals)?, native));
+    }
+    for _ in 0..r.count(16)? {
+        let name = r.string()?;
+        let constant = registry.constant(&name).ok_or(LoadError::UnknownNative(name))?;
+        vm.constants.push((r.slot(vm.globals)?, constant));
+    }
+
+    for _ in 0..r.count(25)? {
+        let name = r.string()?;
+        let factor = r.decimal()?;
+        let prefixable = match r.u8()? {
+            0 => { false }
+            1 => { true }
+            flag => { return Err(LoadError::Malformed(format!("unit flag {} is not a boolean", flag))); }
+        };
+        let mut dim = Dimension::default();
+        for _ in 0..r.count(16)? {
+            let base = r.string()?;
+            dim = dim.mul(&Dimension::base(&base).powi(r.i64()?).map_err(LoadError::Malformed)?);
+        }
+        vm.units.restore(&name, Unit { factor, dim }, prefixable).map_err(LoadError::Malformed)?;
+    }
+
+    if r.position != bytes.len() {
+        return Err(LoadError::Malformed(format!("{} bytes after the end of the program", bytes.len() - r.position)));
+    }
+    verifier::verify(&vm).map_err(LoadError::Unverified)?;
+    Ok(vm)
+}
+
+/// writes a compiled program to a file
+pub fn save(vm: &VM, path: impl AsRef<Path>) -> io::Result<()> {
+    fs::write(path, serialize(vm))
+}
+
+/// reads a compiled program from a file
+pub fn load(path: impl AsRef<Path>, registry: &Registry) -> Result<VM, LoadError> {
+    let bytes = fs::read(path).map_err(|err| LoadError::Io(err.to_string()))?;
+    deserialize(&bytes, registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::Interpreter;
+    use crate::natives::Arity;
+    use crate::stdlib;
+    use crate::vm::{InterpretResult, OpCode};
+    use crate::errors::ErrorCode;
+
+    fn compile(source: &str) -> VM {
+        let mut c = Compiler::new(source);
+        c.compile();
+        assert!(c.diagnostics.is_empty(), "{:?}", c.diagnostics);
+        c.vm
+    }
+
+    fn run(vm: VM) -> (InterpretResult, Vec<(usize, String)>) {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.interpret(vm);
+        (result, interpreter.output)
+    }
+
+    const PROGRAM: &str = "unit furlong = 201.168 m\ndimension money\nx = 3 furlong\nx -> m\n\
+        f(n) = n <= 1 ? 1 : n * f(n - 1)\nf(20)\nA = [1, 2; 3, 4.5]\ndet(A)\nsin(pi / 6)\n\"text\"\n2 kmoney / 4\n!true";
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = serialize(&compile(PROGRAM));
+        assert!(is_bytecode(&bytes));
+        let vm = deserialize(&bytes, &Registry::standard()).unwrap();
+        assert_eq!(serialize(&vm), bytes);
+        assert_eq!(vm.global_symbols(), compile(PROGRAM).global_symbols());
+        assert_eq!(run(vm), run(compile(PROGRAM)));
+
+        let path = std::env::temp_dir().join("mathscript_round_trip.msc");
+        save(&compile(PROGRAM), &path).unwrap();
+        let (result, output) = run(load(&path, &Registry::standard()).unwrap());
+        assert_eq!(Interpreter::new().interpret_file(&path), Ok(InterpretResult::Ok));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(output[1].1, "603.504 m");
+        assert_eq!(output[3].1, "2432902008176640000");
+    }
+
+    #[test]
+    fn test_validation() {
+        let registry = Registry::standard();
+        let bytes = serialize(&compile(PROGRAM));
+        assert_eq!(deserialize(b"x = 1", &registry).err(), Some(LoadError::NotBytecode));
+        let mut newer = bytes.clone();
+        newer[4] = 3;
+        assert_eq!(deserialize(&newer, &registry).err(), Some(LoadError::UnsupportedVersion(3)));
+        // every prefix of a program is rejected rather than read past its end
+        for len in MAGIC.len()..bytes.len() {
+            assert!(deserialize(&bytes[..len], &registry).is_err(), "loaded {} bytes", len);
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(deserialize(&longer, &registry), Err(LoadError::Malformed(_))));
+        // the interpreter allocates every global slot, so a count the file cannot name is rejected
+        let mut vm = compile(PROGRAM);
+        vm.globals = usize::MAX / 64;
+        assert_eq!(deserialize(&serialize(&vm), &registry).err(), Some(LoadError::Truncated));
+
+        let mut vm = compile("1");
+        vm.consts[0] = TokenValue::Number(BigDecimal::new(BigInt::from(1), 1 << 40));
+        assert!(matches!(deserialize(&serialize(&vm), &registry), Err(LoadError::Malformed(_))));
+        vm.consts[0] = TokenValue::Number(BigDecimal::from_str(&"9".repeat(MAX_DIGITS + 1)).unwrap());
+        assert!(matches!(deserialize(&serialize(&vm), &registry), Err(LoadError::Malformed(_))));
+        // a program with flipped bits is rejected or runs within the limits, but never aborts
+        let small = serialize(&compile("unit furlong = 201.168 m\nx = 3 furlong\nx -> m\nf(n) = n <= 1 ? 1 : n * f(n - 1)\nf(5)"));
+        for at in MAGIC.len() + 2..small.len() {
+            for mask in [0x01, 0x80, 0xff] {
+                let mut flipped = small.clone();
+                flipped[at] ^= mask;
+                if let Ok(vm) = deserialize(&flipped, &registry) {
+                    let mut interpreter = Interpreter::new();
+                    interpreter.limits.instructions = 10_000;
+                    interpreter.interpret(vm);
+                }
+            }
+        }
+
+        // natives are stored by name and resolved by whoever loads the program
+        let mut registry = Registry::new();
+        registry.register("sin", Arity::Exact(1), stdlib::sin);
+        let bytes = serialize(&compile("sin(1)\ndet([1])"));
+        assert_eq!(deserialize(&bytes, &registry).err(), Some(LoadError::UnknownNative(String::from("det"))));
+        assert!(matches!(load("/nonexistent/program.msc", &registry), Err(LoadError::Io(_))));
+
+        // a program that decodes is still verified before it is handed out
+        let mut vm = compile("x = 2 > 1 ? 3 : 4");
+        let jump = *vm.starts.iter().find(|&&start| vm.opcode_at(start) == Some(OpCode::Jump)).unwrap();
+        vm.set_operand(jump + 1, 1);
+        match deserialize(&serialize(&vm), &Registry::standard()) {
+            Err(LoadError::Unverified(err)) => { assert_eq!(err.code, ErrorCode::InvalidInstruction); }
+            result => { panic!("expected a verification error, found {:?}", result.err()); }
+        }
+    }
+}
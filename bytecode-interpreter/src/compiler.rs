@@ -1,20 +1,51 @@
 use std::collections::HashMap;
-use crate::lexer::{TokenType, Lexer, TokenValue};
+use crate::lexer::{TokenType, Lexer, TokenValue, Token, Span};
+use crate::errors::Diagnostic;
+use crate::natives::Registry;
 use lazy_static::lazy_static;
-use crate::vm::{VM, OpCode};
+use crate::vm::{VM, OpCode, Offset};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use crate::vm::OpCode::Return;
 use crate::compiler::Precedence::Eof;
+use bigdecimal::BigDecimal;
 
+// deepest expression nesting the compiler accepts
+const MAX_DEPTH: usize = 256;
 
 pub struct Compiler {
     source: String,
     lexer: Lexer,
     pub vm: VM,
+    pub diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+    // number of blocks enclosing the code being compiled
+    blocks: usize,
+    // span of the expression whose infix or prefix rule is being compiled
+    expr_start: Span,
+    // number of prefix and infix rules currently being compiled
+    depth: usize,
+    // loops enclosing the code being compiled, innermost last
+    loops: Vec<Loop>,
+    // end of the last `Index` of a variable, which an assignment can turn into an `IndexAssign`
+    index_target: Option<usize>,
+    // whether a `:` ends the first branch of a ternary rather than making a range, brackets
+    // clear it so `c ? (1:2) : 3` is a range in a branch
+    in_branch: bool,
     last_pointer: usize,
 }
 
+/// Loop struct, an enclosing loop that `break` and `continue` jump out of
+struct Loop {
+    // first instruction of an iteration
+    start: usize,
+    // stack height at the start of an iteration and before the loop
+    base: usize,
+    outer: usize,
+    // `break` jumps, patched once the end of the loop is known
+    breaks: Vec<usize>,
+}
+
 #[derive(Copy, Clone, PartialEq, Debug, Eq, Hash, FromPrimitive)]
 enum Precedence {
     Eof = 0,
@@ -22,6 +53,8 @@ enum Precedence {
     None,
     // =
     Assignment,
+    // ->
+    Convert,
     // ,
     Tuple,
     // []
@@ -37,6 +70,8 @@ enum Precedence {
     Equality,
     // < > <= >=
     Comparison,
+    // :
+    Range,
     // + -
     Term,
     // * /
@@ -64,7 +99,21 @@ type PRF = ParseRuleFn;
 
 impl Compiler {
     pub fn new(text: &str) -> Compiler {
-        return Compiler { source: String::from(text), lexer: Lexer::new(text), vm: VM::new(), last_pointer: 0 };
+        Compiler::resume(text, VM::new())
+    }
+
+    /// a compiler that continues the program of `vm`, appending its code after the instructions
+    /// already there so it can use their globals, functions and units
+    pub fn resume(text: &str, vm: VM) -> Compiler {
+        let last_pointer = vm.instructions.len();
+        Compiler { source: String::from(text), lexer: Lexer::new(text), vm, diagnostics: Vec::new(), panic_mode: false, blocks: 0, expr_start: Span::default(), depth: 0, loops: Vec::new(), index_target: None, in_branch: false, last_pointer }
+    }
+
+    /// a compiler whose programs can call the natives of `registry` instead of the standard ones
+    pub fn with_registry(text: &str, registry: Registry) -> Compiler {
+        let mut compiler = Compiler::new(text);
+        compiler.vm.registry = registry;
+        compiler
     }
 
     fn parse_rules(&self, token: TokenType) -> ParseRule {
@@ -75,16 +124,22 @@ impl Compiler {
             TokenType::Minus => { ParseRule { prefix: PRF::Func(Compiler::unary), infix: PRF::Func(Compiler::binary), prec: Precedence::Term } }
             TokenType::Mul => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Factor } }
             TokenType::Div => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Factor } }
+            TokenType::IntDiv => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Factor } }
+            TokenType::Mod => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Factor } }
+            TokenType::DotMul => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Factor } }
+            TokenType::DotDiv => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Factor } }
+            TokenType::DotPow => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Unary } }
             TokenType::Pow => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Unary } }
             TokenType::Assign => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Assignment } }
+            TokenType::Convert => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::convert), prec: Precedence::Convert } }
             TokenType::Comma => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::tuple), prec: Precedence::Tuple } }
-            TokenType::LBracket => { ParseRule { prefix: PRF::Func(Compiler::matrix), infix: PRF::Null, prec: Precedence::Matrix } }
+            TokenType::LBracket => { ParseRule { prefix: PRF::Func(Compiler::matrix), infix: PRF::Func(Compiler::index), prec: Precedence::Call } }
             TokenType::LParen => { ParseRule { prefix: PRF::Func(Compiler::grouping), infix: PRF::Func(Compiler::fn_decl), prec: Precedence::Call } }
             TokenType::DQuote => { ParseRule { prefix: PRF::Func(Compiler::string), infix: PRF::Null, prec: Precedence::None } }
             TokenType::EndL => { ParseRule { prefix: PRF::Null, infix: PRF::Null, prec: Precedence::EndLine } }
             TokenType::LBrace => { ParseRule { prefix: PRF::Func(Compiler::block), infix: PRF::Null, prec: Precedence::None } }
             TokenType::Ternary => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::ternary), prec: Precedence::Ternary } }
-            TokenType::Colon => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::ternary), prec: Precedence::Ternary } }
+            TokenType::Colon if !self.in_branch => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::range), prec: Precedence::Range } }
             TokenType::LArrow => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Comparison } }
             TokenType::RArrow => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Comparison } }
             TokenType::LessEq => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Comparison } }
@@ -92,9 +147,12 @@ impl Compiler {
             TokenType::Eq => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Equality } }
             TokenType::NotEq => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Equality } }
             TokenType::Eof => { ParseRule { prefix: PRF::Null, infix: PRF::Null, prec: Precedence::Eof } }
-            TokenType::AndBool => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::And } }
-            TokenType::OrBool => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::binary), prec: Precedence::Or } }
+            TokenType::AndBool => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::and), prec: Precedence::And } }
+            TokenType::OrBool => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::or), prec: Precedence::Or } }
             TokenType::NotUnary => { ParseRule { prefix: PRF::Func(Compiler::unary), infix: PRF::Func(Compiler::factorial), prec: Precedence::Unary } }
+            TokenType::SQuote => { ParseRule { prefix: PRF::Null, infix: PRF::Func(Compiler::transpose), prec: Precedence::Call } }
+            TokenType::ReservedValue => { ParseRule { prefix: PRF::Func(Compiler::boolean), infix: PRF::Null, prec: Precedence::None } }
+            TokenType::Reserved => { ParseRule { prefix: PRF::Func(Compiler::conditional), infix: PRF::Null, prec: Precedence::None } }
             _ => { ParseRule { prefix: PRF::Null, infix: PRF::Null, prec: Precedence::None } }
         };
     }
@@ -102,110 +160,207 @@ impl Compiler {
     fn empty(&mut self) {}
 
     fn factorial(&mut self) {
+        self.mark(self.expr_start);
         self.vm.emit_instruction(OpCode::Factorial, self.lexer.prev_token.line);
     }
 
+    fn transpose(&mut self) {
+        self.mark(self.expr_start);
+        self.vm.emit_instruction(OpCode::Transpose, self.lexer.prev_token.line);
+    }
+
     fn ternary(&mut self) {
-        self.vm.emit_instruction(OpCode::Branch, self.lexer.prev_token.line);
-        self.vm.emit_instruction(OpCode::Jump, self.lexer.prev_token.line);
-        let loc_jump_in_branch = self.vm.instructions.len() - 1;
+        self.mark(self.expr_start);
+        let loc_jump_in_branch = self.vm.emit_jump(OpCode::Branch, 0, self.lexer.prev_token.line);
 
         let mut table = self.vm.offsets.clone();
         // collect left side of ternary
+        let in_branch = std::mem::replace(&mut self.in_branch, true);
         self.compile_precedence(FromPrimitive::from_usize(Precedence::Ternary as usize + 1).unwrap());
+        self.in_branch = in_branch;
         self.vm.offsets = table.clone();
 
         // set jump branch
-        self.vm.emit_instruction(OpCode::Jump, self.lexer.prev_token.line);
-        self.vm.emit_instruction(OpCode::Jump, self.lexer.prev_token.line);
-        let loc_jump_out_branch = self.vm.instructions.len() - 1;
-
-        self.vm.instructions[loc_jump_in_branch] = self.vm.instructions.len();
+        let loc_jump_out_branch = self.vm.emit_jump(OpCode::Jump, 0, self.lexer.prev_token.line);
+        self.vm.patch_jump(loc_jump_in_branch);
 
         // consume colon
-        self.consume(TokenType::Colon);
+        if !self.consume(TokenType::Colon, "':'") {
+            return;
+        }
 
         // collect right side of ternary
-        self.compile_precedence(Precedence::None);
-        self.vm.instructions[loc_jump_out_branch] = self.vm.instructions.len();
+        self.compile_precedence(Precedence::Ternary);
+        self.vm.patch_jump(loc_jump_out_branch);
+    }
+
+    /// compiles `start:end` and `start:step:end` into a row vector
+    fn range(&mut self) {
+        let start = self.expr_start;
+        let line = self.lexer.prev_token.line;
+        let mut parts = 2;
+        self.compile_precedence(Precedence::Term);
+        if self.lexer.cur_token.token == TokenType::Colon {
+            // the middle operand was the step
+            self.advance();
+            self.compile_precedence(Precedence::Term);
+            parts = 3;
+        }
+        self.mark(start);
+        self.vm.emit_range(parts, line);
+    }
+
+    /// compiles the contents of brackets, where a `:` makes a range even inside a ternary branch
+    fn nested(&mut self, compile: impl FnOnce(&mut Compiler)) {
+        let in_branch = std::mem::replace(&mut self.in_branch, false);
+        compile(self);
+        self.in_branch = in_branch;
+    }
+
+    /// records a diagnostic, further errors are suppressed once the compiler is in panic mode
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// skips the rest of a broken statement, up to the end of its line or enclosing block,
+    /// so that the next statement can be checked for independent errors
+    fn synchronize(&mut self) {
+        loop {
+            match self.lexer.cur_token.token {
+                TokenType::EndL | TokenType::Eof => { break; }
+                TokenType::RBrace if self.blocks > 0 => { break; }
+                _ => { self.advance(); }
+            }
+        }
+        self.panic_mode = false;
+    }
+
+    /// records a compile error at the last consumed token
+    fn error(&mut self, message: String) {
+        let diagnostic = Diagnostic::at(&self.lexer.prev_token, message);
+        self.report(diagnostic);
     }
 
-    fn consume(&mut self, token: TokenType) -> bool {
-        self.lexer.next_token();
+    /// records an expected-vs-found error at the current token
+    fn error_expected(&mut self, expected: &str) {
+        let diagnostic = Diagnostic::expected(&self.lexer.cur_token, expected);
+        self.report(diagnostic);
+    }
+
+    /// advances the lexer by one token, reporting and skipping unexpected symbols
+    fn advance(&mut self) {
+        while let Err(message) = self.lexer.next_token() {
+            let diagnostic = Diagnostic::new(self.lexer.line, self.lexer.column(), message);
+            self.report(diagnostic);
+            self.lexer.skip();
+        }
+    }
 
+    /// consumes the current token if it matches, otherwise reports what was expected
+    fn consume(&mut self, token: TokenType, expected: &str) -> bool {
         if self.lexer.cur_token.token == token {
+            self.advance();
             return true;
         }
-        return false;
+        self.error_expected(expected);
+        false
+    }
+
+    /// sets the span of emitted instructions to run from `start` to the last consumed token
+    fn mark(&mut self, start: Span) {
+        self.vm.span = start.to(self.lexer.prev_token.span());
+    }
+
+    /// checks if the instructions in `start..end` are a single symbol, optionally loaded
+    fn is_symbol(&self, start: usize, end: usize) -> bool {
+        let symbol = OpCode::Symbol.size();
+        let loaded = end - start == symbol + 1 && self.vm.opcode_at(end - 1) == Some(OpCode::Load);
+        (end - start == symbol || loaded) && self.vm.opcode_at(start) == Some(OpCode::Symbol)
+    }
+
+    /// checks if the last instructions emitted are a single symbol, optionally loaded
+    fn ends_with_symbol(&self, loaded: bool) -> bool {
+        let len = self.vm.instructions.len();
+        let size = OpCode::Symbol.size() + loaded as usize;
+        len >= size && self.is_symbol(len - size, len)
+    }
+
+    /// removes the last emitted symbol instruction and returns its name
+    fn pop_symbol(&mut self) -> String {
+        let len = self.vm.instructions.len() - OpCode::Symbol.size();
+        let name_ptr = self.vm.operand(len, 2);
+        self.vm.truncate(len);
+        self.vm.consts[name_ptr].to_string()
     }
 
     fn fn_decl(&mut self) {
+        let start = self.expr_start;
         let table = self.vm.offsets.clone();
+        let callee = self.vm.instructions.len();
         let mut num_args = 0;
-        loop {
-            if self.lexer.cur_token.token == TokenType::RParen {
-                break;
-            } else if self.lexer.cur_token.token == TokenType::Eof {
-                return;
-            }
-            self.compile_precedence(FromPrimitive::from_usize(Precedence::Tuple as usize + 1).unwrap());
-            num_args += 1;
-            if self.lexer.cur_token.token != TokenType::RParen {
-                self.lexer.next_token();
+        let mut params = true;
+        if self.lexer.cur_token.token != TokenType::RParen {
+            loop {
+                let start = self.vm.instructions.len();
+                self.nested(|c| c.compile_precedence(FromPrimitive::from_usize(Precedence::Tuple as usize + 1).unwrap()));
+                num_args += 1;
+                params = params && self.is_symbol(start, self.vm.instructions.len());
+                if self.lexer.cur_token.token != TokenType::Comma {
+                    break;
+                }
+                self.advance();
             }
         }
-        self.consume(TokenType::RParen);
+        if !self.consume(TokenType::RParen, "',' or ')'") {
+            return;
+        }
 
         // function declaration, otherwise function call
         if self.lexer.cur_token.token == TokenType::Assign {
+            let name_start = callee.wrapping_sub(OpCode::Symbol.size() + 1);
+            if !params || callee <= OpCode::Symbol.size() || !self.is_symbol(name_start, callee) {
+                let message = String::from("invalid function declaration, expected a name and parameter names before '='");
+                let diagnostic = Diagnostic::at(&self.lexer.cur_token, message);
+                self.report(diagnostic);
+                return;
+            }
             self.vm.offsets = table;
             let mut ids = Vec::new();
-            for i in 0..num_args {
+            for _ in 0..num_args {
                 self.remove_last_load();
-                let name_ptr = self.vm.instructions.pop().unwrap();
-                let name = self.vm.consts[name_ptr].clone();
-                if let TokenValue::String(name) = name {
-                    ids.push(name);
-                }
-                self.vm.instructions.pop();
-                self.vm.instructions.pop();
-                self.vm.instructions.pop();
-                self.vm.lines.pop();
-                self.vm.lines.pop();
-                self.vm.lines.pop();
+                ids.push(self.pop_symbol());
             }
             self.remove_last_load();
             ids.reverse();
-            self.vm.emit_func_decl(ids, self.lexer.prev_token.line);
-
-            self.vm.emit_instruction(OpCode::Jump, self.lexer.prev_token.line);
-            self.vm.emit_instruction(OpCode::Jump, self.lexer.prev_token.line);
-            let instr_partial = self.vm.instructions.len() - 1;
-            self.lexer.next_token();
-            let mut ptr = self.vm.instructions.len() - 1;
-            while self.lexer.prev_token.token == TokenType::EndL {
-                self.lexer.next_token();
+            let name = self.vm.consts[self.vm.operand(name_start, 2)].to_string();
+            self.mark(start);
+            self.vm.emit_func_decl(name, ids, self.lexer.prev_token.line);
+
+            let instr_partial = self.vm.emit_jump(OpCode::Jump, 0, self.lexer.prev_token.line);
+            self.advance();
+            while self.lexer.cur_token.token == TokenType::EndL {
+                self.advance();
             }
             if self.lexer.cur_token.token != TokenType::LBrace {
                 self.vm.emit_instruction(OpCode::BeginScope, self.lexer.cur_token.line);
             }
+            // `break` and `continue` cannot leave the function
+            let loops = std::mem::take(&mut self.loops);
             self.compile_precedence(FromPrimitive::from_usize(Precedence::None as usize + 1).unwrap());
-            if self.vm.instructions.last().unwrap().clone() != OpCode::EndScope as usize {
+            self.loops = loops;
+            if self.vm.last_opcode() != Some(OpCode::EndScope) {
                 self.vm.emit_instruction(OpCode::EndScope, self.lexer.cur_token.line);
             }
 
-
-            while ptr < self.vm.instructions.len() {
-                if self.vm.instructions[ptr] == OpCode::Print as usize {
-                    self.vm.instructions.remove(ptr);
-                    self.vm.lines.remove(ptr);
-                } else {
-                    ptr += 1;
-                }
-            }
+            self.mark(start);
             self.vm.emit_instruction(OpCode::Return, self.lexer.prev_token.line);
-            self.vm.instructions[instr_partial] = self.vm.instructions.len();
+            self.vm.patch_jump(instr_partial);
         } else {
+            self.mark(start);
             self.vm.emit_func_call(num_args, self.lexer.prev_token.line);
         }
         //self.vm.emit_instruction(OpCode::Print, self.lexer.prev_token.line);
@@ -213,39 +368,240 @@ impl Compiler {
 
     fn block(&mut self) {
         self.vm.emit_instruction(OpCode::BeginScope, self.lexer.prev_token.line);
+        self.nested(Compiler::statements);
+        self.vm.emit_instruction(OpCode::EndScope, self.lexer.prev_token.line);
+    }
 
-        while self.lexer.prev_token.token != TokenType::RBrace {
-            self.compile_precedence(Precedence::EndLine);
-            if self.lexer.prev_token.token == TokenType::Eof {
-                break;
+    /// compiles the statements of a block up to and including its closing brace
+    fn statements(&mut self) {
+        self.blocks += 1;
+
+        loop {
+            match self.lexer.cur_token.token {
+                TokenType::EndL => { self.advance(); }
+                TokenType::RBrace => {
+                    self.advance();
+                    break;
+                }
+                TokenType::Eof => {
+                    self.error_expected("'}'");
+                    break;
+                }
+                _ => {
+                    self.statement();
+                    let end = self.lexer.cur_token.token;
+                    if end != TokenType::EndL && end != TokenType::RBrace {
+                        self.error_expected("end of line");
+                    }
+                    if self.panic_mode {
+                        self.synchronize();
+                    }
+                }
             }
         }
-        self.vm.emit_instruction(OpCode::EndScope, self.lexer.prev_token.line);
+        self.blocks -= 1;
+    }
+
+    /// compiles a braced body in the current scope, returning the number of values it leaves on the stack
+    fn body(&mut self) -> usize {
+        let height = self.vm.stack_height();
+        if self.consume(TokenType::LBrace, "'{'") {
+            self.statements();
+        }
+        self.vm.stack_height().saturating_sub(height)
+    }
+
+    /// checks if the current token is the given reserved word
+    fn check_word(&self, word: &str) -> bool {
+        self.lexer.cur_token.token == TokenType::Reserved && self.lexer.cur_token.value.to_string() == word
+    }
+
+    /// consumes the current token if it is the given reserved word
+    fn match_word(&mut self, word: &str) -> bool {
+        if self.check_word(word) {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    /// consumes the current token if it is a name spelling a word that is only a keyword where a
+    /// name cannot appear, such as the `in` of a `for` loop
+    fn match_name(&mut self, word: &str) -> bool {
+        if self.lexer.cur_token.token == TokenType::Id && self.lexer.cur_token.value.to_string() == word {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    /// compiles `if a { ... } else if b { ... } else { ... }`, which evaluates to the last value
+    /// of the branch taken, or null if there is none
+    fn conditional(&mut self) {
+        let start = self.expr_start;
+        let line = self.lexer.prev_token.line;
+        if self.lexer.prev_token.value.to_string() != "if" {
+            let diagnostic = Diagnostic::expected(&self.lexer.prev_token, "an expression");
+            self.report(diagnostic);
+            return;
+        }
+        self.expression();
+        self.mark(start);
+        let else_jump = self.vm.emit_jump(OpCode::Branch, 0, line);
+        self.branch();
+        let end_jump = self.vm.emit_jump(OpCode::Jump, 0, line);
+        self.vm.patch_jump(else_jump);
+        // only one of the branches leaves its value
+        self.vm.modify_offset(-1);
+
+        // `else` may start the line after the closing brace
+        if self.lexer.cur_token.token == TokenType::EndL {
+            if let Ok(next) = self.lexer.peek_token() {
+                if next.token == TokenType::Reserved && next.value.to_string() == "else" {
+                    self.advance();
+                }
+            }
+        }
+        if self.match_word("else") {
+            if self.match_word("if") {
+                self.conditional();
+            } else {
+                self.branch();
+            }
+        } else {
+            self.vm.emit_instruction(OpCode::Null, line);
+        }
+        self.vm.patch_jump(end_jump);
+    }
+
+    /// compiles the body of an `if` branch, keeping only the value of its last statement
+    fn branch(&mut self) {
+        let line = self.lexer.cur_token.line;
+        let table = self.vm.offsets.clone();
+        let count = self.body();
+        if count == 0 {
+            self.vm.emit_instruction(OpCode::Null, line);
+        }
+        self.vm.emit_pop(OpCode::Slide, count.saturating_sub(1), line);
+        self.vm.restore_symbols(&table);
+    }
+
+    /// compiles `while condition { ... }`
+    fn while_loop(&mut self) {
+        let start = self.lexer.prev_token.span();
+        let line = self.lexer.prev_token.line;
+        let table = self.vm.offsets.clone();
+        let base = self.vm.stack_height();
+        let begin = self.vm.instructions.len();
+
+        self.expression();
+        self.mark(start);
+        let exit = self.vm.emit_jump(OpCode::Branch, 0, line);
+        self.loops.push(Loop { start: begin, base, outer: base, breaks: Vec::new() });
+        let count = self.body();
+        self.vm.emit_pop(OpCode::Pop, count, line);
+        self.vm.emit_jump(OpCode::Jump, begin, line);
+        self.vm.patch_jump(exit);
+        self.end_loop(&table, line);
+    }
+
+    /// compiles `for x in values { ... }`, binding `x` to each element of a tuple or matrix in turn
+    fn for_loop(&mut self) {
+        let start = self.lexer.prev_token.span();
+        let line = self.lexer.prev_token.line;
+        let table = self.vm.offsets.clone();
+        let outer = self.vm.stack_height();
+        let name = match self.declared_name() {
+            Some(name) => { name }
+            None => { return; }
+        };
+        if !self.match_name("in") {
+            self.error_expected("'in'");
+            return;
+        }
+
+        // the values and the index of the next one stay on the stack while the loop runs
+        self.expression();
+        self.mark(start);
+        self.vm.emit_constant(TokenValue::Number(BigDecimal::from(0)), line);
+        let begin = self.vm.instructions.len();
+        let base = self.vm.stack_height();
+        self.vm.emit_symbol(name, line);
+        let exit = self.vm.emit_iterate(line);
+        self.vm.emit_instruction(OpCode::Assign, line);
+
+        self.loops.push(Loop { start: begin, base, outer, breaks: Vec::new() });
+        let count = self.body();
+        self.vm.emit_pop(OpCode::Pop, count + 1, line);
+        self.vm.emit_jump(OpCode::Jump, begin, line);
+        self.vm.patch_jump(exit);
+        // an exhausted loop leaves the values, the index and the variable's pointer behind
+        self.vm.modify_offset(1);
+        self.vm.emit_pop(OpCode::Pop, 3, line);
+        self.end_loop(&table, line);
+    }
+
+    /// points the `break`s of the innermost loop past its end, where the loop evaluates to null
+    fn end_loop(&mut self, table: &[Offset], line: usize) {
+        if let Some(finished) = self.loops.pop() {
+            for idx in finished.breaks {
+                self.vm.patch_jump(idx);
+            }
+        }
+        self.vm.restore_symbols(table);
+        self.vm.emit_instruction(OpCode::Null, line);
+    }
+
+    /// compiles `break` or `continue`, popping whatever the loop body left on the stack
+    fn jump_out(&mut self, word: &str) {
+        let line = self.lexer.prev_token.line;
+        let height = self.vm.stack_height();
+        let (target, start) = match self.loops.last() {
+            Some(l) if word == "break" => { (l.outer, None) }
+            Some(l) => { (l.base, Some(l.start)) }
+            None => {
+                self.error(format!("'{}' outside of a loop", word));
+                return;
+            }
+        };
+        let count = height.saturating_sub(target);
+        self.vm.emit_pop(OpCode::Pop, count, line);
+        match start {
+            Some(start) => { self.vm.emit_jump(OpCode::Jump, start, line); }
+            None => {
+                let idx = self.vm.emit_jump(OpCode::Jump, 0, line);
+                self.loops.last_mut().unwrap().breaks.push(idx);
+            }
+        }
+        // nothing after the jump runs, but it still counts as a statement leaving a value
+        self.vm.modify_offset(count as isize + 1);
     }
 
     fn string(&mut self) {
+        let start = self.expr_start;
         let mut s = String::new();
-        while self.lexer.cur_token.token != TokenType::DQuote {
+        while self.lexer.cur_token.token != TokenType::DQuote && self.lexer.cur_token.token != TokenType::Eof {
             s.push_str(self.lexer.cur_token.value.to_string().as_str());
-            self.lexer.next_token();
-            if self.lexer.cur_token.token == TokenType::Eof {
-                return;
-            }
+            self.advance();
+        }
+        if !self.consume(TokenType::DQuote, "'\"'") {
+            return;
         }
-        self.lexer.next_token();
+        self.mark(start);
         self.vm.emit_constant(TokenValue::String(s), self.lexer.prev_token.line);
     }
 
     fn grouping(&mut self) {
-        self.compile_precedence(Precedence::None);
+        self.nested(Compiler::expression);
+        self.consume(TokenType::RParen, "')'");
     }
 
     fn unary(&mut self) {
+        let start = self.expr_start;
         let op_tok = self.lexer.prev_token.clone();
-        let rule = self.parse_rules(op_tok.token);
-        if let Some(val) = FromPrimitive::from_usize(rule.prec as usize + 1) {
-            self.compile_precedence(val);
-        }
+        // the operand includes powers, so -2^2 is -(2^2)
+        self.compile_precedence(Precedence::Unary);
+        self.mark(start);
         match op_tok.token {
             TokenType::Plus => { self.vm.emit_instruction(OpCode::UnaryPos, op_tok.line); }
             TokenType::Minus => { self.vm.emit_instruction(OpCode::UnaryNeg, op_tok.line); }
@@ -255,41 +611,99 @@ impl Compiler {
     }
 
     fn matrix(&mut self) {
+        let start = self.expr_start;
         let mut cols = 0;
         let mut rows = 1;
 
         let mut tmp = 0;
         loop {
-            self.compile_precedence(Precedence::Matrix);
-            if self.lexer.cur_token.token == TokenType::Comma {
-                tmp += 1;
-                self.lexer.next_token();
-            } else if self.lexer.cur_token.token == TokenType::Semicolon {
-                if cols == 0 {
-                    cols = tmp + 1;
-                }
-                self.lexer.next_token();
-                rows += 1;
-            } else {
-                if cols == 0 {
-                    cols = tmp + 1;
-                }
+            self.nested(|c| c.compile_precedence(Precedence::Matrix));
+            tmp += 1;
+            let sep = self.lexer.cur_token.token;
+            if sep == TokenType::Comma {
+                self.advance();
+                continue;
+            }
+
+            // every row needs as many columns as the first one
+            if cols == 0 {
+                cols = tmp;
+            } else if tmp != cols {
+                let message = format!("expected {} columns in matrix row, found {}", cols, tmp);
+                self.error(message);
+            }
+            tmp = 0;
+            if sep != TokenType::Semicolon {
                 break;
             }
+            self.advance();
+            rows += 1;
         }
-        self.consume(TokenType::RBracket);
+        if !self.consume(TokenType::RBracket, "',', ';' or ']'") {
+            return;
+        }
+        self.mark(start);
         self.vm.emit_matrix(rows, cols, self.lexer.prev_token.line);
     }
 
+    /// compiles `a[i]`, `a[i, j]` and slices such as `a[1:2, :]`; indexing a variable leaves its
+    /// pointer beneath the indices, so the selection can be assigned to
+    fn index(&mut self) {
+        let start = self.expr_start;
+        let line = self.lexer.prev_token.line;
+        let target = self.ends_with_symbol(true);
+        if target {
+            self.remove_last_load();
+        }
+
+        let (mut count, mut mask) = (0, 0);
+        loop {
+            if self.lexer.cur_token.token == TokenType::Colon {
+                // a lone `:` selects a whole row or column
+                self.advance();
+                mask |= 1 << count;
+            } else {
+                self.nested(|c| c.compile_precedence(Precedence::Ternary));
+            }
+            count += 1;
+            if count == 2 || self.lexer.cur_token.token != TokenType::Comma {
+                break;
+            }
+            self.advance();
+        }
+        if !self.consume(TokenType::RBracket, if count == 2 { "']'" } else { "',' or ']'" }) {
+            return;
+        }
+        self.mark(start);
+        self.vm.emit_index(OpCode::Index, count, mask, line);
+        if target {
+            self.index_target = Some(self.vm.instructions.len());
+        }
+    }
+
+    /// turns the `Index` just emitted into an `IndexAssign` of the value that follows
+    fn index_assign(&mut self, start: Span, line: usize) {
+        let index = self.vm.instructions.len() - OpCode::Index.size();
+        let (count, mask) = (self.vm.operand(index, 0), self.vm.operand(index, 1));
+        self.vm.truncate(index);
+        // the indices are still on the stack until the assignment runs
+        let popped = (0..count).filter(|i| (mask >> i) & 1 == 0).count();
+        self.vm.modify_offset(popped as isize);
+        self.index_target = None;
+
+        self.compile_precedence(Precedence::Assignment);
+        self.mark(start);
+        self.vm.emit_index(OpCode::IndexAssign, count, mask, line);
+    }
+
     fn remove_last_load(&mut self) {
-        let last = self.vm.instructions.last().unwrap().clone();
-        if last == OpCode::Load as usize {
-            self.vm.instructions.pop();
-            self.vm.lines.pop();
+        if self.vm.last_opcode() == Some(OpCode::Load) {
+            self.vm.truncate(self.vm.instructions.len() - 1);
         }
     }
 
     fn tuple(&mut self) {
+        let start = self.expr_start;
         let mut count: usize = 1;
         loop {
             count += 1;
@@ -304,20 +718,17 @@ impl Compiler {
         // if next token is assignment, remove loads
         if self.lexer.peek_assignment() {
             let mut ids = Vec::new();
-            self.lexer.next_token();
-            for i in 0..count {
+            self.advance();
+            for _ in 0..count {
                 self.remove_last_load();
-                let name_ptr = self.vm.instructions.pop().unwrap();
-                if let TokenValue::String(s) = self.vm.consts[name_ptr].clone() {
-                    ids.push(s.clone());
-                    self.vm.instructions.pop();
-                    self.vm.instructions.pop();
-                    self.vm.instructions.pop();
-                    self.vm.lines.pop();
-                    self.vm.lines.pop();
-                    self.vm.lines.pop();
-                    self.vm.remove_offset(s);
+                if !self.ends_with_symbol(false) {
+                    let message = String::from("invalid assignment target, expected a name");
+                    self.error(message);
+                    return;
                 }
+                let s = self.pop_symbol();
+                self.vm.remove_offset(s.clone());
+                ids.push(s);
             }
 
             self.vm.modify_offset(-(count as isize));
@@ -325,32 +736,75 @@ impl Compiler {
             for i in 0..count {
                 self.vm.emit_symbol(ids[count - i - 1].clone(), self.lexer.prev_token.line);
                 self.compile_precedence(FromPrimitive::from_usize(Precedence::Tuple as usize + 1).unwrap());
-                if self.lexer.cur_token.token == TokenType::Comma {
-                    self.lexer.next_token();
+                if i == 0 && self.lexer.cur_token.token != TokenType::Comma {
+                    // a single value, such as `L, U, P = lu(A)`, is a tuple to unpack
+                    ids.reverse();
+                    self.unpack(start, ids);
+                    return;
                 }
-
+                self.mark(start);
                 self.vm.emit_instruction(OpCode::Assign, self.lexer.prev_token.line);
+                if i + 1 < count && !self.consume(TokenType::Comma, "','") {
+                    return;
+                }
             }
         } else {
+            self.mark(start);
             self.vm.emit_tuple(count as usize, self.lexer.prev_token.line);
         }
     }
 
+    /// assigns the elements of the tuple on the stack to `names`: the tuple is assigned to the
+    /// first name, indexed into the others and then replaced by its first element
+    fn unpack(&mut self, start: Span, names: Vec<String>) {
+        let line = self.lexer.prev_token.line;
+        self.mark(start);
+        self.vm.emit_unpack(names.len(), line);
+        self.vm.emit_instruction(OpCode::Assign, line);
+        for k in (1..names.len()).chain(0..1) {
+            self.vm.emit_symbol(names[k].clone(), line);
+            self.vm.emit_symbol(names[0].clone(), line);
+            self.vm.emit_constant(TokenValue::Number(BigDecimal::from(k as u64 + 1)), line);
+            self.vm.emit_index(OpCode::Index, 1, 0, line);
+            self.vm.emit_instruction(OpCode::Assign, line);
+        }
+    }
+
     fn binary(&mut self) {
+        let start = self.expr_start;
         let op_tok = self.lexer.prev_token.clone();
         let rule = self.parse_rules(op_tok.token);
-        if self.lexer.cur_token.token == TokenType::Eof {
-            return;
-        }
-        if let Some(val) = FromPrimitive::from_usize(rule.prec as usize + 1) {
+        if op_tok.token == TokenType::Assign {
+            let len = self.vm.instructions.len();
+            if self.index_target == Some(len) {
+                self.index_assign(start, op_tok.line);
+                return;
+            }
+            if !self.ends_with_symbol(false) {
+                let message = String::from("invalid assignment target, expected a name");
+                self.error(message);
+                return;
+            }
+            // assignment is right associative
+            self.compile_precedence(Precedence::Assignment);
+        } else if op_tok.token == TokenType::Pow || op_tok.token == TokenType::DotPow {
+            // so is exponentiation, and its exponent may be negated: 2^3^2 is 2^9 and 2^-1 is 0.5
+            self.compile_precedence(Precedence::Unary);
+        } else if let Some(val) = FromPrimitive::from_usize(rule.prec as usize + 1) {
             self.compile_precedence(val);
         }
+        self.mark(start);
 
         match op_tok.token {
             TokenType::Plus => { self.vm.emit_instruction(OpCode::Add, op_tok.line); }
             TokenType::Minus => { self.vm.emit_instruction(OpCode::Sub, op_tok.line); }
             TokenType::Mul => { self.vm.emit_instruction(OpCode::Mul, op_tok.line); }
             TokenType::Div => { self.vm.emit_instruction(OpCode::Div, op_tok.line); }
+            TokenType::IntDiv => { self.vm.emit_instruction(OpCode::IntDiv, op_tok.line); }
+            TokenType::Mod => { self.vm.emit_instruction(OpCode::Mod, op_tok.line); }
+            TokenType::DotMul => { self.vm.emit_instruction(OpCode::ElemMul, op_tok.line); }
+            TokenType::DotDiv => { self.vm.emit_instruction(OpCode::ElemDiv, op_tok.line); }
+            TokenType::DotPow => { self.vm.emit_instruction(OpCode::ElemPow, op_tok.line); }
             TokenType::Assign => { self.vm.emit_instruction(OpCode::Assign, op_tok.line); }
             TokenType::Pow => { self.vm.emit_instruction(OpCode::Pow, op_tok.line); }
             TokenType::LArrow => { self.vm.emit_instruction(OpCode::LessThan, op_tok.line); }
@@ -359,12 +813,34 @@ impl Compiler {
             TokenType::MoreEq => { self.vm.emit_instruction(OpCode::MoreEqThan, op_tok.line); }
             TokenType::Eq => { self.vm.emit_instruction(OpCode::Eq, op_tok.line); }
             TokenType::NotEq => { self.vm.emit_instruction(OpCode::NotEq, op_tok.line); }
-            TokenType::AndBool => { self.vm.emit_instruction(OpCode::And, op_tok.line); }
-            TokenType::OrBool => { self.vm.emit_instruction(OpCode::Or, op_tok.line); }
             _ => { return; }
         }
     }
 
+    /// compiles `a && b`, skipping `b` when `a` is false
+    fn and(&mut self) {
+        self.short_circuit(OpCode::JumpIfFalse, OpCode::And, Precedence::And);
+    }
+
+    /// compiles `a || b`, skipping `b` when `a` is true
+    fn or(&mut self) {
+        self.short_circuit(OpCode::JumpIfTrue, OpCode::Or, Precedence::Or);
+    }
+
+    /// leaves `a` as the result if it decides the outcome, otherwise combines it with `b`
+    fn short_circuit(&mut self, jump: OpCode, op: OpCode, prec: Precedence) {
+        let start = self.expr_start;
+        let line = self.lexer.prev_token.line;
+        self.mark(start);
+        let end = self.vm.emit_jump(jump, 0, line);
+        if let Some(val) = FromPrimitive::from_usize(prec as usize + 1) {
+            self.compile_precedence(val);
+        }
+        self.mark(start);
+        self.vm.emit_instruction(op, line);
+        self.vm.patch_jump(end);
+    }
+
     fn identifier(&mut self) {
         let cur = self.lexer.prev_token.clone();
         if let TokenValue::String(id) = cur.value {
@@ -378,27 +854,219 @@ impl Compiler {
     fn number(&mut self) {
         let val = &self.lexer.prev_token;
         self.vm.emit_constant(val.value.clone(), val.line);
+
+        if let Some(unit) = self.unit() {
+            self.mark(self.expr_start);
+            self.vm.emit_unit(OpCode::Unit, unit, self.lexer.prev_token.line);
+        }
     }
 
-    fn compile_precedence(&mut self, prec: Precedence) {
-        self.lexer.next_token();
+    /// compiles a `true` or `false` literal
+    fn boolean(&mut self) {
+        let val = &self.lexer.prev_token;
+        self.vm.emit_constant(TokenValue::Bool(val.value.to_string() == "true"), val.line);
+    }
+
+    /// compiles a single expression
+    fn expression(&mut self) {
+        self.compile_precedence(Precedence::Assignment);
+    }
 
-        let prefix_rule = self.parse_rules(self.lexer.prev_token.token).prefix;
-        match prefix_rule {
-            ParseRuleFn::Func(func) => { func(self); }
-            ParseRuleFn::Null => { //add error
+    /// compiles a declaration, a loop or an expression, returning whether it leaves a value to print
+    fn statement(&mut self) -> bool {
+        if self.lexer.cur_token.token == TokenType::Reserved && !self.check_word("if") || self.declaration() {
+            self.advance();
+            self.reserved();
+            false
+        } else {
+            self.expression();
+            true
+        }
+    }
+
+    /// checks if the statement is a `unit` or `dimension` declaration, which name what they declare
+    /// right away, rather than an expression using a variable called `unit` or `dimension`
+    fn declaration(&mut self) -> bool {
+        let word = self.lexer.cur_token.value.to_string();
+        self.lexer.cur_token.token == TokenType::Id && (word == "unit" || word == "dimension")
+            && matches!(self.lexer.peek_token(), Ok(next) if next.token == TokenType::Id)
+    }
+
+    /// compiles a statement introduced by a reserved word
+    fn reserved(&mut self) {
+        match self.lexer.prev_token.value.to_string().as_str() {
+            "unit" => { self.unit_decl(); }
+            "dimension" => { self.dimension_decl(); }
+            "while" => { self.while_loop(); }
+            "for" => { self.for_loop(); }
+            word @ "break" | word @ "continue" => { self.jump_out(word); }
+            word => {
+                let message = format!("unexpected '{}'", word);
+                self.error(message);
+            }
+        }
+    }
+
+    /// consumes the identifier a declaration introduces
+    fn declared_name(&mut self) -> Option<String> {
+        if let (TokenType::Id, TokenValue::String(name)) = (self.lexer.cur_token.token, &self.lexer.cur_token.value) {
+            let name = name.clone();
+            self.advance();
+            return Some(name);
+        }
+        self.error_expected("a name");
+        None
+    }
+
+    /// declares a new unit in terms of existing ones, e.g. `unit furlong = 201.168 m`
+    fn unit_decl(&mut self) {
+        let name = match self.declared_name() {
+            Some(name) => { name }
+            None => { return; }
+        };
+        let name_token = self.lexer.prev_token.clone();
+        if !self.consume(TokenType::Assign, "'='") {
+            return;
+        }
+
+        // optional scale factor followed by an optional unit expression
+        let mut expr = String::from("1");
+        if self.lexer.cur_token.token == TokenType::Num {
+            self.advance();
+            expr = self.lexer.prev_token.value.to_string();
+        }
+        match self.unit() {
+            Some(unit) => { expr = format!("{}*{}", expr, unit); }
+            None if self.lexer.prev_token.token != TokenType::Num => {
+                self.error_expected("a unit definition");
                 return;
             }
+            None => {}
+        }
+
+        let result = self.vm.units.parse(&expr).and_then(|unit| self.vm.units.define_unit(&name, unit));
+        if let Err(err) = result {
+            self.report(Diagnostic::at(&name_token, err));
         }
-        let x = prec as u32;
-        let y = self.parse_rules(self.lexer.cur_token.token).prec as u32;
+    }
+
+    /// declares a new base dimension measured in a base unit of the same name, e.g. `dimension token`
+    fn dimension_decl(&mut self) {
+        if let Some(name) = self.declared_name() {
+            if let Err(err) = self.vm.units.define_dimension(&name) {
+                self.error(err);
+            }
+        }
+    }
+
+    /// converts the value on the left into the unit expression on the right, e.g. `x -> km/h`
+    fn convert(&mut self) {
+        let start = self.expr_start;
+        let line = self.lexer.prev_token.line;
+        let unit = match self.unit() {
+            Some(unit) => { unit }
+            None if self.lexer.cur_token.token == TokenType::Id => {
+                // unknown unit, reported when the conversion runs
+                self.advance();
+                self.lexer.prev_token.value.to_string()
+            }
+            None => {
+                self.error_expected("a unit");
+                return;
+            }
+        };
+        self.mark(start);
+        self.vm.emit_unit(OpCode::Convert, unit, line);
+    }
+
+    /// checks if a token names a unit in the unit table
+    fn is_unit(&self, token: &Token) -> bool {
+        if let (TokenType::Id, TokenValue::String(name)) = (token.token, &token.value) {
+            return self.vm.units.contains(name);
+        }
+        false
+    }
+
+    /// collects a unit expression written directly after a number, e.g. `9.8 m/s^2`
+    fn unit(&mut self) -> Option<String> {
+        if !self.is_unit(&self.lexer.cur_token) {
+            return None;
+        }
+
+        let mut unit = String::new();
+        loop {
+            self.advance();
+            unit.push_str(self.lexer.prev_token.value.to_string().as_str());
+
+            // integer exponent, e.g. s^2 or s^-1
+            if self.lexer.cur_token.token == TokenType::Pow {
+                let next = self.lexer.peek_token().map(|tok| tok.token);
+                if next == Ok(TokenType::Num) || next == Ok(TokenType::Minus) {
+                    self.advance();
+                    unit.push('^');
+                    if self.lexer.cur_token.token == TokenType::Minus {
+                        self.advance();
+                        unit.push('-');
+                    }
+                    self.advance();
+                    unit.push_str(self.lexer.prev_token.value.to_string().as_str());
+                }
+            }
+
+            // a following `*` or `/` only belongs to the unit if another unit comes after it
+            let op = self.lexer.cur_token.token;
+            if op != TokenType::Mul && op != TokenType::Div {
+                break;
+            }
+            match self.lexer.peek_token() {
+                Ok(tok) if self.is_unit(&tok) => {
+                    self.advance();
+                    unit.push_str(op.value());
+                }
+                _ => { break; }
+            }
+        }
+        Some(unit)
+    }
+
+    fn compile_precedence(&mut self, prec: Precedence) {
+        // rules recurse back into here, so deep nesting would overflow the compiler's own stack
+        if self.depth == MAX_DEPTH {
+            let diagnostic = Diagnostic::at(&self.lexer.cur_token, String::from("expression is nested too deeply"));
+            self.report(diagnostic);
+            return;
+        }
+        // a token that cannot start an expression is left for the caller to recover from
+        let prefix_rule = self.parse_rules(self.lexer.cur_token.token).prefix;
+        let prefix = match prefix_rule {
+            ParseRuleFn::Func(func) => { func }
+            ParseRuleFn::Null => {
+                self.error_expected("an expression");
+                return;
+            }
+        };
+        self.advance();
+        let start = self.lexer.prev_token.span();
+        self.vm.span = start;
+        self.expr_start = start;
+        self.depth += 1;
+        prefix(self);
+        self.depth -= 1;
+
         while prec as u32 <= self.parse_rules(self.lexer.cur_token.token).prec as u32 {
-            self.lexer.next_token();
+            self.advance();
+            self.vm.span = self.lexer.prev_token.span();
+            self.expr_start = start;
             let infix_rule = self.parse_rules(self.lexer.prev_token.token).infix;
             match infix_rule {
-                ParseRuleFn::Func(func) => { func(self); }
+                ParseRuleFn::Func(func) => {
+                    self.depth += 1;
+                    func(self);
+                    self.depth -= 1;
+                }
                 ParseRuleFn::Null => {
-                    // add error
+                    let diagnostic = Diagnostic::expected(&self.lexer.prev_token, "an operator");
+                    self.report(diagnostic);
                     return;
                 }
             }
@@ -406,21 +1074,29 @@ impl Compiler {
     }
 
     pub fn compile(&mut self) {
-        self.lexer.next_token();
-        loop {
+        self.advance();
+        while self.lexer.cur_token.token != TokenType::Eof {
+            if self.lexer.cur_token.token == TokenType::EndL {
+                self.advance();
+                continue;
+            }
             let len = self.vm.instructions.len();
-            self.compile_precedence(Precedence::None);
-            if self.lexer.cur_token.token == TokenType::Eof {
-                break;
+            let start = self.lexer.cur_token.span();
+            let printed = self.statement();
+            if self.lexer.cur_token.token != TokenType::EndL && self.lexer.cur_token.token != TokenType::Eof {
+                self.error_expected("end of line");
             }
-            if len == self.vm.instructions.len() {
+            if self.panic_mode {
+                self.synchronize();
                 continue;
             }
-            self.vm.emit_instruction(OpCode::Print, self.lexer.cur_token.line - 1);
-            self.last_pointer = self.vm.instructions.len();
-            self.lexer.next_token();
+            if printed && len != self.vm.instructions.len() {
+                self.mark(start);
+                self.vm.emit_instruction(OpCode::Print, self.lexer.cur_token.line);
+                self.last_pointer = self.vm.instructions.len();
+            }
         }
-        if self.vm.instructions.last().unwrap().clone() != OpCode::Print as usize {
+        if self.vm.last_opcode() != Some(OpCode::Print) {
             self.vm.emit_instruction(OpCode::Print, self.lexer.prev_token.line);
         }
         self.vm.emit_instruction(OpCode::EndProgram, self.lexer.prev_token.line);
@@ -431,12 +1107,21 @@ impl Compiler {
 mod tests {
     use super::*;
     use bigdecimal::BigDecimal;
-    use crate::vm::{SymbolValue, InterpretResult};
-    use crate::interpreter::Interpreter;
+    use crate::vm::{SymbolValue, InterpretResult, Lcg};
+    use crate::interpreter::{Interpreter, Halt};
+    use crate::natives::Arity;
+    use crate::errors::{RuntimeError, ErrorCode};
 
     fn run(source: &str) -> Vec<(usize, String)> {
+        run_result(source).0
+    }
+
+    fn run_result(source: &str) -> (Vec<(usize, String)>, InterpretResult) {
         let mut c = Compiler::new(source);
         c.compile();
+        if !c.diagnostics.is_empty() {
+            return (Vec::new(), InterpretResult::CompileError(c.diagnostics));
+        }
         let dis = c.vm.disassemble(false);
         for item in dis {
             print!("{}", item);
@@ -453,17 +1138,40 @@ mod tests {
             let line = if el.0 < lines.len() {lines[el.0]} else {""};
             println!("{}: {}\t {}", i + 1, line, interpreter.output[i].1);
         }
-        match result {
+        match &result {
             InterpretResult::Ok => {}
-            InterpretResult::CompileError(err) => {
-                println!("{}", err);
+            InterpretResult::CompileError(diagnostics) => {
+                for diagnostic in diagnostics {
+                    println!("{}", diagnostic);
+                }
             }
             InterpretResult::RuntimeError(err) => {
                 println!("{}", err);
+                for frame in interpreter.trace.iter() {
+                    println!("  {}", frame);
+                }
+            }
+            InterpretResult::ResourceLimitExceeded(limit) => {
+                println!("{}", limit);
+            }
+            InterpretResult::Cancelled => {
+                println!("cancelled");
             }
         }
 
-        return interpreter.output;
+        (interpreter.output, result)
+    }
+
+    fn values(out: &[(usize, String)]) -> Vec<&str> {
+        out.iter().map(|(_, val)| val.as_str()).collect()
+    }
+
+    /// runs each case on its own after `prelude`, checking the last value it prints
+    fn check(prelude: &str, cases: &[(&str, &str)]) {
+        for (source, expected) in cases {
+            let out = run(&format!("{}{}", prelude, source));
+            assert_eq!(out.last().map(|(_, val)| val.as_str()), Some(*expected), "{}", source);
+        }
     }
 
     #[test]
@@ -521,6 +1229,699 @@ mod tests {
         let out = run("f(x, y) = {\n a = x * x + 2 * x + y\ng(z) = z * z\ng}\nx = 3\na = f(x, 5)\na(2)");
     }
 
+    #[test]
+    fn test_units() {
+        let out = run("3 m / 2 s\nx = 9.8 m/s^2\nx * 2 s\n(3 m)^2\n");
+        assert_eq!(values(&out), vec!["1.5 m/s", "9.8 m/s^2", "19.6 m/s", "9 m^2"]);
+        assert_eq!(runtime_error("2 m + 3").code, ErrorCode::Unit);
+
+        let out = run("4 kg*m^2/s^2 / 2 kg\n1 s^-1 * 4 s\n1 m < 2 m");
+        assert_eq!(values(&out), vec!["2 m^2/s^2", "4", "true"]);
+    }
+
+    #[test]
+    fn test_unit_conversion() {
+        let out = run("v = 90 km/h\nv -> m/s\n1 mi -> km\n2 kg * 3 m/s^2\nv -> mph\n1 psi -> kPa");
+        assert_eq!(values(&out), vec!["25 m/s", "25 m/s", "1.609344 km", "6 N", "55.923407301360057265569076592699 mph", "6.8947572931683613367226734453469 kPa"]);
+
+        let err = runtime_error("3 m -> s");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Unit, "could not convert '3 m' to 's': dimensions 'm' and 's' do not match"));
+
+        let err = runtime_error("3 m -> parsec");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Unit, "unknown unit 'parsec'"));
+    }
+
+    #[test]
+    fn test_unit_decl() {
+        let out = run("unit furlong = 201.168 m\nunit fortnight = 14 day\nx = 3 furlong\nx -> m\n1 furlong / 1 fortnight -> m/s\n");
+        assert_eq!(values(&out), vec!["603.504 m", "603.504 m", "0.00016630952380952380952380952380952 m/s"]);
+
+        let out = run("dimension token\nunit kilotoken = 1000 token\nrate = 12 ktoken / 3 s\nrate -> kilotoken/min\nunit dozen = 12\n2 dozen");
+        assert_eq!(values(&out), vec!["4000 token/s", "240 kilotoken/min", "24"]);
+
+        // `unit` and `dimension` only declare something when a name follows them
+        let out = run("unit = 4
+dimension = unit * 2
+unit furlong = 201.168 m
+dimension + unit");
+        assert_eq!(values(&out), vec!["4", "8", "12"]);
+    }
+
+    #[test]
+    fn test_unit_decl_errors() {
+        let (_, result) = run_result("unit m = 3 s");
+        assert_eq!(result, InterpretResult::CompileError(vec![Diagnostic::new(1, 6, String::from("unit 'm' is already defined"))]));
+
+        let (_, result) = run_result("dimension token\ndimension token");
+        assert_eq!(result, InterpretResult::CompileError(vec![Diagnostic::new(2, 11, String::from("unit 'token' is already defined"))]));
+
+        let (_, result) = run_result("unit foo = bar");
+        assert!(matches!(result, InterpretResult::CompileError(_)));
+    }
+
+    #[test]
+    fn test_unit_errors() {
+        let err = runtime_error("3 m + 2 s");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Unit, "could not add '3 m' and '2 s': dimensions 'm' and 's' do not match"));
+
+        assert_eq!(runtime_error("(2 m)^0.5").code, ErrorCode::Unit);
+    }
+
+    #[test]
+    fn test_if() {
+        let out = run("x = 3\nif x > 2 { 10 } else { 20 }\nif x < 2 { 1 } else if x == 3 { 2 } else { 3 }\nif x < 2 { 1 }\nelse { 4 }");
+        assert_eq!(values(&out), vec!["3", "10", "2", "4"]);
+
+        // an `if` is an expression evaluating to the last value of the branch taken
+        let out = run("y = if 1 < 2 {\n  a = 5\n  a * 2\n} else { 0 }\nz = if y == 0 { 1 }\nz");
+        assert_eq!(values(&out), vec!["10", "NULL", "NULL"]);
+
+        let out = run("sign(n) = if n < 0 { -1 } else if n == 0 { 0 } else { 1 }\nsign(-4), sign(0), sign(7)");
+        assert_eq!(values(&out)[1], "(-1, 0, 1)");
+    }
+
+    #[test]
+    fn test_loops() {
+        let out = run("i = 0\ns = 0\nwhile i < 5 {\n  i = i + 1\n  s = s + i\n}\ns");
+        assert_eq!(values(&out), vec!["0", "0", "15"]);
+
+        let out = run("s = 0\nfor y in (1, 2, 3, 4) {\n  if y == 2 { continue }\n  if y == 4 { break }\n  s = s + y\n}\ns\ny");
+        assert_eq!(values(&out), vec!["0", "4", "4"]);
+
+        // matrices are iterated row by row
+        let out = run("p = 1\nfor z in [1, 2; 3, 4] { p = p * z }\np");
+        assert_eq!(values(&out), vec!["1", "24"]);
+
+        // `break` leaves the innermost loop only
+        let out = run("t = 0\nfor i in (1, 2, 3) {\n  for j in (10, 20) {\n    if j == 20 { break }\n    t = t + i * j\n  }\n}\nt");
+        assert_eq!(values(&out), vec!["0", "60"]);
+
+        // loops inside functions work on locals
+        let out = run("fact(n) = {\n  r = 1\n  while n > 1 {\n    r = r * n\n    n = n - 1\n  }\n  r\n}\nfact(5)\ng(n) = {\n  k = 0\n  for v in (1, 2, 3, 4) {\n    if v > n { break }\n    k = k + v\n  }\n  k\n}\ng(3)");
+        assert_eq!(values(&out)[1], "120");
+        assert_eq!(values(&out)[3], "6");
+
+        // `in` is still a name outside of a `for` header
+        let out = run("in = (1, 2, 3)
+sum = 0
+for x in in { sum = sum + x }
+sum");
+        assert_eq!(values(&out), vec!["(1, 2, 3)", "0", "6"]);
+    }
+
+    #[test]
+    fn test_arithmetic_operators() {
+        let out = run("2^10\n2^3^2\n-2^2\n2^-2\n(-3)^3\n1.5^2\n4^0.5\n2^0.5\n10^-1.5");
+        assert_eq!(values(&out), vec![
+            "1024", "512", "-4", "0.25", "-27", "2.25", "2",
+            "1.4142135623730950488016887242096980785696718753769",
+            "0.031622776601683793319988935444327185337195551393252",
+        ]);
+
+        // floored modulo takes the sign of the divisor
+        let out = run("7 % 3\n-7 % 3\n7 % -3\n5.5 % 2\n7 // 2\n-7 // 2\n7.5 // 0.5\n7.5 m % 2 m\n7 m // 2 m");
+        assert_eq!(values(&out), vec!["1", "2", "-2", "1.5", "3", "-4", "15", "1.5 m", "3"]);
+
+        let out = run("!(1 < 2)\n!!(1 < 2)\n!(1 > 2) && 2 > 1");
+        assert_eq!(values(&out), vec!["false", "true", "true"]);
+
+        let out = run("!true\n!false\nx = true\nx && !x\nfalse ? 1 : 2");
+        assert_eq!(values(&out), vec!["false", "true", "true", "false", "2"]);
+
+        assert_eq!(runtime_error("5 % 0").code, ErrorCode::DivideByZero);
+        assert_eq!(runtime_error("5 // 0").code, ErrorCode::DivideByZero);
+        assert_eq!(runtime_error("0^-1").code, ErrorCode::DivideByZero);
+        assert_eq!(runtime_error("(-8)^0.5").message, "could not pow '-8' and '0.5': fractional power of a negative number");
+        assert_eq!(runtime_error("10^(10^20)").code, ErrorCode::Domain);
+        assert_eq!(runtime_error("!3").message, "expected a boolean operand for '!', found '3'");
+        assert_eq!(runtime_error("3 m % 2 s").code, ErrorCode::Unit);
+    }
+
+    #[test]
+    fn test_matrix_arithmetic() {
+        check("A = [1, 2; 3, 4]\nB = [0, 1; 1, 0]\n", &[
+            ("A * B", "[[2, 1], [4, 3]]"),
+            ("B * A", "[[3, 4], [1, 2]]"),
+            ("[1, 2, 3] * [1; 2; 3]", "[[14]]"),
+            ("A + B", "[[1, 3], [4, 4]]"),
+            ("A - B", "[[1, 1], [2, 4]]"),
+            ("A + 1", "[[2, 3], [4, 5]]"),
+            ("1 - A", "[[0, -1], [-2, -3]]"),
+            ("2 * A", "[[2, 4], [6, 8]]"),
+            ("A / 2", "[[0.5, 1], [1.5, 2]]"),
+            ("12 / A", "[[12, 6], [4, 3]]"),
+            ("-A", "[[-1, -2], [-3, -4]]"),
+            ("A^0", "[[1, 0], [0, 1]]"),
+            ("A^3", "[[37, 54], [81, 118]]"),
+            ("A == B", "false"),
+            ("A != B", "true"),
+            ("A == [1, 2; 3, 4]", "true"),
+            ("[1, 2] == [1; 2]", "false"),
+            ("(1, 2) + (3, 4)", "(4, 6)"),
+            ("(1, 2) == (1, 2, 3)", "false"),
+            ("-(1, 2)", "(-1, -2)"),
+            ("[1 km, 2 m] == [1000 m, 2 m]", "true"),
+            ("[1 m, 2 m] * 2", "[[2 m, 4 m]]"),
+        ]);
+
+        let err = runtime_error("[1, 2] * [3, 4]");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "could not multiply matrices of dimensions '(1, 2)' and '(1, 2)'"));
+        assert_eq!(runtime_error("[1, 2] + [1, 2, 3]").message, "could not add matrices of dimensions '(1, 2)' and '(1, 3)'");
+        assert_eq!(runtime_error("[1, 2]^2").message, "could not pow a matrix of dimensions '(1, 2)': it is not square");
+        assert_eq!(runtime_error("[1, 2; 3, 4]^0.5").message, "could not pow a matrix and '0.5': exponent must be a non-negative integer");
+        assert_eq!(runtime_error("[2, 0; 0, 2]^3000000000").message, "could not pow a matrix: its elements grow past 10000 digits");
+        assert_eq!(run("[1, 0; 0, 1]^3000000000"), vec![(0, String::from("[[1, 0], [0, 1]]"))]);
+        assert_eq!(runtime_error("[1, 2] / 0").code, ErrorCode::DivideByZero);
+        assert_eq!(runtime_error("-(1 < 2)").message, "could not negate 'true'");
+        assert_eq!(runtime_error("[1 m] + [1 s]").code, ErrorCode::Unit);
+        assert_eq!(run("1 == \"1\"\n(1, 2) == [1, 2]"), vec![(0, String::from("false")), (1, String::from("false"))]);
+        assert_eq!(runtime_error("[1 m] == [1 s]").code, ErrorCode::Unit);
+        assert_eq!(runtime_error("f(x) = x\nf == f").code, ErrorCode::Type);
+        assert_eq!(runtime_error("sin == 1").code, ErrorCode::Type);
+    }
+
+    #[test]
+    fn test_elementwise() {
+        check("A = [1, 2; 3, 4]\n", &[
+            ("A .* A", "[[1, 4], [9, 16]]"),
+            ("A * A", "[[7, 10], [15, 22]]"),
+            ("A ./ A", "[[1, 1], [1, 1]]"),
+            ("A .^ 2", "[[1, 4], [9, 16]]"),
+            ("A .* 2", "[[2, 4], [6, 8]]"),
+            ("12 ./ A", "[[12, 6], [4, 3]]"),
+            ("2 .^ A", "[[2, 4], [8, 16]]"),
+            ("[2, 4; 5, 8] .^ -1", "[[0.5, 0.25], [0.2, 0.125]]"),
+            ("A ./ [1, 2]", "[[1, 1], [3, 2]]"),
+            ("A .* [10; 100]", "[[10, 20], [300, 400]]"),
+            ("[1, 2, 3] .* [1; 2]", "[[1, 2, 3], [2, 4, 6]]"),
+            ("[1 m, 2 m] ./ [1 s, 4 s]", "[[1 m/s, 0.5 m/s]]"),
+            ("2 .^ 3 .^ 2", "512"),
+            ("2.*3", "6"),
+            ("2./4", "0.5"),
+        ]);
+
+        let err = runtime_error("[1, 2; 3, 4] .* [1, 2, 3]");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "could not broadcast matrices of dimensions '(2, 2)' and '(1, 3)'"));
+        assert_eq!(runtime_error("[1, 2] ./ [1, 0]").code, ErrorCode::DivideByZero);
+        assert_eq!(runtime_error("[-1, 2] .^ 0.5").code, ErrorCode::Domain);
+    }
+
+    #[test]
+    fn test_indexing() {
+        check("A = [1, 2, 3; 4, 5, 6; 7, 8, 9]\n", &[
+            ("A[2, 3]", "6"),
+            ("A[4]", "4"),
+            ("A[1, :]", "[[1, 2, 3]]"),
+            ("A[:, 2]", "[[2], [5], [8]]"),
+            ("A[1:2, 2:3]", "[[2, 3], [5, 6]]"),
+            ("A[[1, 3], 1]", "[[1], [7]]"),
+            ("[4; 5; 6][2:3]", "[[5], [6]]"),
+            ("t = (10, 20, 30)\nt[3]", "30"),
+            ("t = (10, 20, 30)\nt[2:3]", "(20, 30)"),
+            ("i = 2\nA[i + 1, i - 1]", "7"),
+            ("f(x) = x .* 2\nf([1, 2, 3])[3]", "6"),
+        ]);
+
+        let err = runtime_error("A = [1, 2; 3, 4]\nA[3, 1]");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Index, "index 3 is out of bounds for a dimension of size 2"));
+        let err = runtime_error("A = [1, 2; 3, 4]\nA[0]");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Index, "index '0' is not a positive integer"));
+        assert_eq!(runtime_error("[1, 2][1.5]").code, ErrorCode::Index);
+        assert_eq!(runtime_error("x = 1\nx[1]").code, ErrorCode::Type);
+        assert_eq!(runtime_error("(1, 2)[1, 1]").code, ErrorCode::Index);
+        assert_eq!(diagnostics("A = [1]\nA[1, 1, 1]"), vec!["[line 2:7] expected ']', found ','"]);
+    }
+
+    #[test]
+    fn test_index_assignment() {
+        // an indexed assignment evaluates to the value assigned
+        check("A = [1, 2; 3, 4]\n", &[
+            ("A[1, 2] = 7", "7"),
+            ("A[1, 2] = 7\nA", "[[1, 7], [3, 4]]"),
+            ("A[2, :] = [5, 6]\nA", "[[1, 2], [5, 6]]"),
+            ("A[:, 1] = 0", "0"),
+            ("A[:, 1] = 0\nA", "[[0, 2], [0, 4]]"),
+            ("A[4] = 9\nA", "[[1, 2], [3, 9]]"),
+            ("t = (1, 2, 3)\nt[2] = 5\nt", "(1, 5, 3)"),
+        ]);
+        // functions assign into their own copy of an argument
+        check("A = [1, 2; 3, 4]\ng(M) = {\n  M[1, 1] = M[1, 1] + 1\n  M\n}\n", &[
+            ("g(A)", "[[2, 2], [3, 4]]"),
+            ("g(A)\nA", "[[1, 2], [3, 4]]"),
+        ]);
+
+        let err = runtime_error("A = [1, 2; 3, 4]\nA[:, 1] = [1, 2, 3]");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "could not assign a matrix of dimensions '(1, 3)' to a selection of dimensions '(2, 1)'"));
+        assert_eq!(runtime_error("A = [1, 2]\nA[3] = 1").code, ErrorCode::Index);
+    }
+
+    #[test]
+    fn test_ranges() {
+        check("", &[
+            ("1:5", "[[1, 2, 3, 4, 5]]"),
+            ("0:0.25:1", "[[0.00, 0.25, 0.50, 0.75, 1.00]]"),
+            ("5:-2:0", "[[5, 3, 1]]"),
+            ("n = 3\n1:n-1", "[[1, 2]]"),
+            ("1 > 0 ? 1 : 2", "1"),
+            ("1 > 0 ? (1:2) : 3", "[[1, 2]]"),
+            ("1 < 0 ? 1 : 2:3", "[[2, 3]]"),
+        ]);
+        check("A = [1, 2, 3, 4]\n", &[
+            ("A[2:3]", "[[2, 3]]"),
+            ("A[1 + 1:2 * 2]", "[[2, 3, 4]]"),
+            ("A[1 > 0 ? 1 : 2]", "1"),
+            ("1 > 0 ? A[1:2] : 3", "[[1, 2]]"),
+        ]);
+
+        // decimal steps are exact, so the end is always reached
+        let out = run("c = 0\nfor x in 0:0.1:1 { c = c + 1 }\nc\ns = 0\nfor i in 1:100 { s = s + i }\ns\nfor i in 1:0 { s = 0 }\ns");
+        assert_eq!(values(&out), vec!["0", "11", "0", "5050", "5050"]);
+
+        let err = runtime_error("1:0:3");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "the step of a range cannot be zero"));
+        assert_eq!(runtime_error("1:\"a\"").code, ErrorCode::Type);
+    }
+
+    #[test]
+    fn test_linear_algebra() {
+        check("A = [1, 2; 3, 4]\n", &[
+            ("det(A)", "-2"),
+            ("det([2, 0, 1; 1, 3, 2; 1, 1, 2])", "6"),
+            ("det([0.5, 1; 1.5, 2])", "-0.5"),
+            ("det([1, 2; 2, 4])", "0"),
+            ("trace(A)", "5"),
+            ("inv(A)", "[[-2, 1], [1.5, -0.5]]"),
+            ("inv([4, 7; 2, 6])", "[[0.6, -0.7], [-0.2, 0.4]]"),
+            ("A * inv(A)", "[[1.0, 0.0], [0.0, 1.0]]"),
+            ("transpose(A)", "[[1, 3], [2, 4]]"),
+            ("[1, 2, 3]'", "[[1], [2], [3]]"),
+            ("A''", "[[1, 2], [3, 4]]"),
+            ("2 * A'", "[[2, 6], [4, 8]]"),
+            ("rank([1, 2; 2, 4])", "1"),
+            ("rank([1, 2, 3; 4, 5, 6; 7, 8, 10])", "3"),
+            ("rank([1, 2, 3; 4, 5, 6; 7, 8, 9])", "2"),
+            ("rref([1, 2, 3; 4, 5, 6])", "[[1, 0, -1], [0, 1, 2]]"),
+            ("rref([1, 2; 2, 4])", "[[1, 2], [0, 0]]"),
+            ("solve([2, 1; 1, 3], [3; 5])", "[[0.8], [1.4]]"),
+            ("A * solve(A, [1, 0; 0, 1])", "[[1.0, 0.0], [0.0, 1.0]]"),
+            ("f(M) = det(M) * 2\nf(A)", "-4"),
+        ]);
+        // rational results are exact up to the working precision
+        assert_eq!(values(&run("solve([3], [1])[1]"))[0], format!("0.{}", "3".repeat(50)));
+
+        // natives are globals like any other, so scripts may shadow them
+        assert_eq!(values(&run("det\ndet = 5\ndet")), vec!["fn<det> native\targs: 1", "5", "5"]);
+
+        let err = runtime_error("det([1, 2, 3])");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "'det' expects a square matrix, found dimensions '(1, 3)'"));
+        let err = runtime_error("inv([1, 2; 2, 4])");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Singular, "'inv' found a singular matrix"));
+        assert_eq!(runtime_error("solve([1, 2; 3, 4], [1, 2, 3])").code, ErrorCode::Type);
+        assert_eq!(runtime_error("det(\"a\")").code, ErrorCode::Type);
+        let err = runtime_error("det([1], [2])");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Arity, "'det' expects 1 arguments, found 2"));
+    }
+
+    #[test]
+    fn test_decompositions() {
+        check("A = [2, 1; 4, 3]\nL, U, P = lu(A)\n", &[
+            ("L", "[[1, 0], [0.5, 1]]"),
+            ("U", "[[4, 3], [0, -0.5]]"),
+            ("P", "[[0, 1], [1, 0]]"),
+            ("L * U == P * A", "true"),
+        ]);
+        check("", &[
+            ("L, U, P = lu([1, 2; 3, 4])\nL[2, 1]", "0.33333333333333333333333333333333333333333333333333"),
+            ("Q, R = qr([3, 0; 4, 5])\nQ", "[[0.6, -0.8], [0.8, 0.6]]"),
+            ("Q, R = qr([3, 0; 4, 5])\nR", "[[5, 4], [0, 3]]"),
+            ("chol([4, 2; 2, 3])[2, 2]", "1.4142135623730950488016887242096980785696718753769"),
+            ("values, V = eig([2, 1; 1, 2])\nvalues", "[[1], [3]]"),
+            ("values, V = eig([2, 1; 1, 2])\nV[2, 1]", "-0.70710678118654752440084436210484903928483593768847"),
+            ("eig([4, 1, 2; 1, 3, 0; 2, 0, 5])[1][1]", "1.8548973087995775695731899737336880331437404799345"),
+            ("U, S, V = svd([1, 2, 3; 4, 5, 6])\nS[1, 1]", "9.5080320006957241864986347892936539140417560680241"),
+            ("U, S, V = svd([1, 2, 3; 4, 5, 6])\nS[2, 2]", "0.77286963567348429160139775672021236139554689542867"),
+            ("U, S, V = svd([3, 4])\nU", "[[1]]"),
+            ("U, S, V = svd([3, 4])\nS", "[[5]]"),
+            ("U, S, V = svd([3, 4])\nV", "[[0.6], [0.8]]"),
+        ]);
+
+        // the decompositions work to the interpreter's precision
+        let mut c = Compiler::new("chol([2])\nsvd([3, 0; 4, 5])[2][2, 2]");
+        c.compile();
+        let mut interpreter = Interpreter::new();
+        interpreter.precision = 12;
+        assert_eq!(interpreter.interpret(c.vm), InterpretResult::Ok);
+        assert!(interpreter.output[0].1.contains("1.41421356237"));
+        assert_eq!(interpreter.output[1].1, "2.2360679775");
+
+        let err = runtime_error("chol([1, 2; 2, 1])");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'chol' expects a positive definite matrix"));
+        assert_eq!(runtime_error("eig([1, 2; 3, 4])").code, ErrorCode::Domain);
+        assert_eq!(runtime_error("lu([1, 2, 3])").code, ErrorCode::Type);
+        assert_eq!(runtime_error("a, b = (1, 2, 3)").code, ErrorCode::Type);
+        assert_eq!(runtime_error("a, b = 1").code, ErrorCode::Type);
+    }
+
+    #[test]
+    fn test_natives() {
+        check("", &[
+            ("abs(-3)", "3"),
+            ("abs([-1, 2])", "[[1, 2]]"),
+            ("sign(-2.5)", "-1"),
+            ("floor(-2.5)", "-3"),
+            ("ceil(2.1)", "3"),
+            ("round(2.5)", "3"),
+            ("round(-2.5)", "-3"),
+            ("sqrt(16)", "4"),
+            ("sqrt([4, 9])", "[[2, 3]]"),
+            ("exp(0)", "1"),
+            ("ln(1)", "0"),
+            ("max(3, 7, 5)", "7"),
+            ("min([4, 2, 8])", "2"),
+            ("max(2 m, 150 cm)", "2 m"),
+            ("sum([1, 2; 3, 4])", "10"),
+            ("len([1, 2, 3])", "3"),
+            ("size([1, 2; 3, 4])", "(2, 2)"),
+            ("linspace(0, 1, 5)", "[[0, 0.25, 0.5, 0.75, 1]]"),
+            ("len(linspace(1, 2, 1))", "1"),
+            ("round = 2\nround", "2"),
+        ]);
+        assert!(values(&run("sqrt(2)"))[0].starts_with("1.41421356237309504880"));
+
+        let err = runtime_error("sqrt(-1)");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'sqrt' expects a non-negative number, found '-1'"));
+        assert_eq!(runtime_error("ln(0)").code, ErrorCode::Domain);
+        assert_eq!(runtime_error("abs(\"a\")").code, ErrorCode::Type);
+        assert_eq!(runtime_error("max(1 m, 1 s)").code, ErrorCode::Unit);
+        assert_eq!(runtime_error("linspace(0, 1, 0.5)").code, ErrorCode::Domain);
+        let err = runtime_error("max()");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Arity, "'max' expects at least 1 arguments, found 0"));
+
+        check("", &[
+            ("pi", "3.1415926535897932384626433832795028841971693993751"),
+            ("sin(pi / 6)", "0.5"),
+            ("cos(0)", "1"),
+            ("tan(pi / 4)", "1"),
+            ("log10(1000)", "3"),
+            ("atan2(0, -1) == pi", "true"),
+            ("e == exp(1)", "true"),
+            ("e = 3\ne", "3"),
+        ]);
+        // digits sets the precision of later results and evaluates to the previous one
+        check("", &[
+            ("digits(10)", "50"),
+            ("digits(10)\ndigits(20)", "10"),
+            ("digits(20)\nsin(1)", "0.84147098480789650665"),
+            ("digits(20)\natan(1)", "0.78539816339744830962"),
+            // constants follow the precision unless the script assigned them
+            ("pi\ndigits(20)\npi", "3.1415926535897932385"),
+            ("digits(20)\ne\ndigits(10)\ne", "2.718281828"),
+            ("pi = 3\ndigits(20)\npi", "3"),
+        ]);
+        assert_eq!(runtime_error("log10(-1)").code, ErrorCode::Domain);
+        assert_eq!(runtime_error("digits(0)").code, ErrorCode::Domain);
+        assert_eq!(runtime_error("atan2(1, \"a\")").code, ErrorCode::Type);
+
+        // hosts can call their own functions from scripts through a registry of their own
+        fn twice(_: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+            match &args[0] {
+                SymbolValue::Num(n) => { Ok(SymbolValue::Num(n * BigDecimal::from(2))) }
+                _ => { Ok(SymbolValue::Null) }
+            }
+        }
+        let mut registry = Registry::new();
+        registry.register("twice", Arity::Exact(1), twice);
+        let mut c = Compiler::with_registry("twice(21)\nsqrt(4)", registry);
+        c.compile();
+        c.vm.reset();
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(interpreter.interpret(c.vm), InterpretResult::RuntimeError(_)));
+        assert_eq!(interpreter.output[0].1, "42");
+    }
+
+    #[test]
+    fn test_precision() {
+        let mut c = Compiler::new("2^0.5\n3^(1/3)");
+        c.compile();
+        c.vm.reset();
+        let mut interpreter = Interpreter::new();
+        interpreter.precision = 10;
+        assert_eq!(interpreter.interpret(c.vm), InterpretResult::Ok);
+        assert_eq!(values(&interpreter.output), vec!["1.414213562", "1.44224957"]);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        // the right operand is skipped once the left one decides the result
+        let out = run("x = 0\nx != 0 && 1 / x > 2\nx == 0 || 1 / x > 2\n1 < 2 && 2 < 3 && 3 > 4\n1 > 2 || 2 > 3 || 3 < 4");
+        assert_eq!(values(&out), vec!["0", "false", "true", "false", "true"]);
+
+        // guards stop recursion without a conditional
+        let out = run("f(n) = n == 0 || f(n - 1)\nf(50)\nall(n) = n < 1 || n < 100 && all(n - 1)\nall(20)");
+        assert_eq!(values(&out)[1], "true");
+        assert_eq!(values(&out)[3], "true");
+
+        let err = runtime_error("1 && 2 < 3");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "expected a boolean operand for '&&', found '1'"));
+        assert_eq!(runtime_error("1 > 2 || 3").message, "could not or 'false' and '3'");
+    }
+
+    #[test]
+    fn test_control_flow_errors() {
+        assert_eq!(diagnostics("break"), vec!["[line 1:1] 'break' outside of a loop"]);
+        assert_eq!(diagnostics("f(x) = {\n  continue\n}"), vec!["[line 2:3] 'continue' outside of a loop"]);
+        assert_eq!(diagnostics("while 1 < 2 {\n  f(x) = { break }\n}"), vec!["[line 2:12] 'break' outside of a loop"]);
+        assert_eq!(diagnostics("for 1 in (1, 2) { 1 }"), vec!["[line 1:5] expected a name, found '1'"]);
+        assert_eq!(diagnostics("for x (1, 2) { 1 }"), vec!["[line 1:7] expected 'in', found '('"]);
+        assert_eq!(diagnostics("while 1 < 2 3"), vec!["[line 1:13] expected '{', found '3'"]);
+        assert_eq!(diagnostics("else { 1 }"), vec!["[line 1:1] unexpected 'else'"]);
+
+        let err = runtime_error("x = 1\nif x { 2 }");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "expected a boolean condition, found '1'"));
+        assert_eq!((err.span.line, err.span.column), (2, 1));
+        assert_eq!(runtime_error("for x in 5 { x }").message, "cannot iterate over '5'");
+
+        // a loop that never ends runs out of instructions
+        let (_, result) = run_result("while 1 < 2 { }");
+        assert!(matches!(result, InterpretResult::ResourceLimitExceeded(_)));
+    }
+
+    /// runs a program that is expected to fail at runtime
+    fn runtime_error(source: &str) -> RuntimeError {
+        match run_result(source).1 {
+            InterpretResult::RuntimeError(err) => { err }
+            result => { panic!("expected a runtime error, found {:?}", result) }
+        }
+    }
+
+    fn diagnostics(source: &str) -> Vec<String> {
+        let mut c = Compiler::new(source);
+        c.compile();
+        c.diagnostics.iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert_eq!(diagnostics("(1 + 2"), vec!["[line 1:7] expected ')', found end of file"]);
+        assert_eq!(diagnostics("x = 1 +\ny"), vec!["[line 1:8] expected an expression, found end of line"]);
+        assert_eq!(diagnostics("x = 3 4"), vec!["[line 1:7] expected end of line, found '4'"]);
+        assert_eq!(diagnostics("f(1, 2"), vec!["[line 1:7] expected ',' or ')', found end of file"]);
+        assert_eq!(diagnostics("x = [1, 2; 3]"), vec!["[line 1:12] expected 2 columns in matrix row, found 1"]);
+        assert_eq!(diagnostics("1 = 2"), vec!["[line 1:3] invalid assignment target, expected a name"]);
+        assert_eq!(diagnostics("f(x + 1) = 2"), vec!["[line 1:10] invalid function declaration, expected a name and parameter names before '='"]);
+        assert_eq!(diagnostics("f(x) = {\n x + 1\n"), vec!["[line 3:1] expected '}', found end of file"]);
+        assert_eq!(diagnostics("2 * $"), vec!["[line 1:5] unexpected symbol '$'"]);
+        assert_eq!(diagnostics("1 ? 2 3"), vec!["[line 1:7] expected ':', found '3'"]);
+        assert_eq!(diagnostics("x = )"), vec!["[line 1:5] expected an expression, found ')'"]);
+        assert_eq!(diagnostics(&"(".repeat(100000)), vec!["[line 1:257] expression is nested too deeply"]);
+
+        // a program with compile errors is not run
+        let (out, result) = run_result("x = 1\ny = (x");
+        assert!(out.is_empty());
+        assert_eq!(result, InterpretResult::CompileError(vec![Diagnostic::new(2, 7, String::from("expected ')', found end of file"))]));
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let source = "x = (1 +\ny = 2 3\nf(a) = {\n  b = [1, 2; 3]\n  b\n}\nz = 4\nw = $";
+        assert_eq!(diagnostics(source), vec![
+            "[line 1:9] expected an expression, found end of line",
+            "[line 2:7] expected end of line, found '3'",
+            "[line 4:14] expected 2 columns in matrix row, found 1",
+            "[line 8:5] unexpected symbol '$'",
+        ]);
+
+        assert_eq!(diagnostics("}\nx = 1 +"), vec![
+            "[line 1:1] expected an expression, found '}'",
+            "[line 2:8] expected an expression, found end of file",
+        ]);
+
+        // errors in a block do not hide errors after it
+        assert_eq!(diagnostics("f(x) = {\n x + * 2 }\ny = 1 1"), vec![
+            "[line 2:6] expected an expression, found '*'",
+            "[line 3:7] expected end of line, found '1'",
+        ]);
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let source = "x = 2\ny = 1 + (x * 2 m + 3 s) * 2";
+        let span = runtime_error(source).span;
+        assert_eq!(&source[span.start..span.end], "x * 2 m + 3 s");
+        assert_eq!((span.line, span.column), (2, 10));
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let source = "f(n) = {\n    n <= 0 ? 1 m + 1 s : f(n - 1)\n}\ng(x) = f(x)\ny = g(2)";
+        let mut c = Compiler::new(source);
+        c.compile();
+        c.vm.reset();
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.interpret(c.vm);
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+
+        let trace: Vec<(&str, usize, &str)> = interpreter.trace.iter()
+            .map(|frame| (frame.function.as_str(), frame.span.line, &source[frame.span.start..frame.span.end]))
+            .collect();
+        assert_eq!(trace, vec![("f", 2, "f(n - 1)"), ("f", 2, "f(n - 1)"), ("f", 4, "f(x)"), ("g", 5, "g(2)")]);
+        assert_eq!(interpreter.trace[3].to_string(), "in g() called at [line 5:5]");
+
+        // a nested function captures the frame it is declared in, return address included
+        let source = "k(a) = {\n    h(x) = x + a\n    h(1 s)\n}\nk(1 m)";
+        let mut c = Compiler::new(source);
+        c.compile();
+        c.vm.reset();
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.interpret(c.vm);
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+
+        let trace: Vec<(&str, usize, &str)> = interpreter.trace.iter()
+            .map(|frame| (frame.function.as_str(), frame.span.line, &source[frame.span.start..frame.span.end]))
+            .collect();
+        assert_eq!(trace, vec![("h", 3, "h(1 s)"), ("k", 5, "k(1 m)")]);
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let err = runtime_error("x = 1 / 0");
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::DivideByZero, "division by zero"));
+        assert_eq!((err.span.line, err.span.column), (1, 5));
+
+        assert_eq!(runtime_error("3 m / (0 s)").code, ErrorCode::DivideByZero);
+        assert_eq!(runtime_error("(0 m)^-1").code, ErrorCode::DivideByZero);
+        assert_eq!(runtime_error("1 + (1, 2)").code, ErrorCode::Type);
+        assert_eq!(runtime_error("f(a, b) = a\nf(1)").code, ErrorCode::Arity);
+        assert_eq!(runtime_error("f(a, b) = a\nf(1)").message, "'f' expects 2 arguments, found 1");
+    }
+
+    /// generates a random, mostly well-formed expression
+    fn expr(rng: &mut Lcg, depth: usize) -> String {
+        if depth == 0 || rng.next(3) == 0 {
+            return match rng.next(3) {
+                0 => { format!("{} {}", rng.pick(&["0", "1", "2", "0.5"]), rng.pick(&["m", "s", "km", "m/s"])) }
+                1 => { String::from(rng.pick(&["x", "y", "z", "\"a\"", "f", "g"])) }
+                _ => { String::from(rng.pick(&["0", "1", "2", "0.5", "3"])) }
+            };
+        }
+        match rng.next(9) {
+            7 => { format!("[{}, {}; {}, {}]", expr(rng, depth - 1), expr(rng, depth - 1), expr(rng, depth - 1), expr(rng, depth - 1)) }
+            8 => { format!("({}, {})", expr(rng, depth - 1), expr(rng, depth - 1)) }
+            0 => { format!("-{}", expr(rng, depth - 1)) }
+            1 => { format!("({})!", expr(rng, 0)) }
+            2 => { format!("({})", expr(rng, depth - 1)) }
+            3 => { format!("{} ? {} : {}", expr(rng, depth - 1), expr(rng, depth - 1), expr(rng, depth - 1)) }
+            4 => { format!("{} -> {}", expr(rng, depth - 1), rng.pick(&["m", "s", "km", "km/h"])) }
+            5 => { format!("{}({})", rng.pick(&["f", "g", "x"]), expr(rng, depth - 1)) }
+            _ => {
+                let op = rng.pick(&["+", "-", "*", "/", "^", "<", "<=", ">", ">=", "==", "!=", "&&", "||"]);
+                format!("{} {} {}", expr(rng, depth - 1), op, expr(rng, depth - 1))
+            }
+        }
+    }
+
+    /// compiles and runs a program, failing the test with the program if anything panics
+    fn assert_no_panic(source: &str) {
+        let outcome = std::panic::catch_unwind(|| {
+            let mut c = Compiler::new(source);
+            c.compile();
+            if c.diagnostics.is_empty() {
+                c.vm.reset();
+                let mut interpreter = Interpreter::new();
+                // recursive functions are cut short instead of running forever
+                interpreter.limits.instructions = 100_000;
+                interpreter.interpret(c.vm);
+            }
+        });
+        assert!(outcome.is_ok(), "panicked on {:?}", source);
+    }
+
+    #[test]
+    fn test_fuzz_no_panic() {
+        let alphabet = [
+            "x", "y", "f", "g", "(", ")", "[", "]", ",", ";", " = ", "+", "-", "*", "/", "^", "!", "?", ":",
+            "<", "<=", "==", "!=", "&&", "||", " -> ", "{", "}", ".*", "./", ".^", "%", "//", "\n", "0", "1", "2", "0.5", " m", " s", " km",
+            "unit ", "dimension ", "$", "\"a\"", " ", "if ", " else ", "while ", "for ", " in ", "break", "continue", "'", "det", "inv", "solve", "sin", "pi",
+        ];
+        let mut rng = Lcg(0x5eed);
+
+        // token soup, mostly exercising the compiler's error paths
+        for _ in 0..3000 {
+            let len = 1 + rng.next(24);
+            let source: String = (0..len).map(|_| rng.pick(&alphabet)).collect();
+            assert_no_panic(&source);
+        }
+
+        // well-formed programs, exercising the interpreter
+        for _ in 0..1000 {
+            let mut lines = vec![
+                format!("f(a) = {}", expr(&mut rng, 2)),
+                format!("g(a, b) = {{\n  c = {}\n  f(c)\n}}", expr(&mut rng, 2)),
+            ];
+            for _ in 0..1 + rng.next(4) {
+                let target = rng.pick(&["x = ", "y = ", "z = ", ""]);
+                lines.push(format!("{}{}", target, expr(&mut rng, 3)));
+            }
+            lines.push(format!("for i in ({}, {}) {{\n  if {} {{ continue }}\n  x = {}\n}}", expr(&mut rng, 1), expr(&mut rng, 1), expr(&mut rng, 1), expr(&mut rng, 2)));
+            lines.push(format!("while {} {{\n  y = {}\n  if {} {{ break }}\n}}", expr(&mut rng, 2), expr(&mut rng, 2), expr(&mut rng, 1)));
+            assert_no_panic(&lines.join("\n"));
+        }
+    }
+
+    #[test]
+    fn test_instruction_starts() {
+        // the compiler rewrites emitted code in place, so the recorded
+        // instruction starts must still match the decoded stream
+        let sources = [
+            "x = 1\ny = x + 2",
+            "f(a) = a * 2\nf(3)",
+            "g(a, b) = {\n  c = a + b\n  c\n}\ng(1, 2)",
+            "m = [1, 2; 3, 4]\nm[1, 2] = 5\nm[2, 1]",
+            "a, b = (1, 2)\na > b ? a : b",
+            "k = 2\nh(x) = x * k\nh(4)",
+            "for i in (1, 3) {\n  if i == 2 { continue }\n  x = i\n}",
+        ];
+        for source in sources {
+            let mut c = Compiler::new(source);
+            c.compile();
+            assert!(c.diagnostics.is_empty(), "{}", source);
+            let decoded: Vec<usize> = crate::vm::decode(&c.vm.instructions).unwrap().into_iter().map(|(at, _)| at).collect();
+            assert_eq!(decoded, c.vm.starts, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_encoded_size() {
+        // one byte per opcode and four per operand, where the word-sized encoding before took
+        // eight bytes for each
+        let mut c = Compiler::new(&std::fs::read_to_string("ackermann.in").unwrap());
+        c.compile();
+        let decoded = crate::vm::decode(&c.vm.instructions).unwrap();
+        let operands: usize = decoded.iter().map(|(_, op)| op.operands()).sum();
+        assert_eq!(c.vm.instructions.len(), decoded.len() + 4 * operands);
+        assert_eq!((c.vm.instructions.len(), (decoded.len() + operands) * 8), (312, 984));
+    }
+
     #[test]
     fn test_file() {
         use std::fs;
@@ -528,4 +1929,4 @@ mod tests {
         let input = fs::read_to_string("file.in").unwrap();
         run(input.as_str());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,145 @@
+use serde::Serialize;
+use std::fmt;
+use crate::lexer::{Token, Span};
+
+/// Diagnostic struct, a compile error anchored to a line and column of the source
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Diagnostic implementation
+impl Diagnostic {
+    /// creates a diagnostic at the given position
+    pub fn new(line: usize, column: usize, message: String) -> Diagnostic {
+        Diagnostic { line, column, message }
+    }
+
+    /// creates a diagnostic located at a token
+    pub fn at(token: &Token, message: String) -> Diagnostic {
+        Diagnostic::new(token.line, token.column, message)
+    }
+
+    /// creates an expected-vs-found diagnostic located at the token that was found
+    pub fn expected(token: &Token, expected: &str) -> Diagnostic {
+        Diagnostic::at(token, format!("expected {}, found {}", expected, token.describe()))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}:{}] {}", self.line, self.column, self.message)
+    }
+}
+
+/// TraceFrame struct, a function call that was still running when a runtime error occurred
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TraceFrame {
+    pub function: String,
+    pub span: Span,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {}() called at [line {}:{}]", self.function, self.span.line, self.span.column)
+    }
+}
+
+/// ErrorCode enum, the kind of failure behind a runtime error
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum ErrorCode {
+    // an instruction needed more values than the stack holds
+    StackUnderflow,
+    // a stack pointer or return address that doesn't point into the stack
+    BadPointer,
+    // a global slot outside of the global table
+    OutOfBounds,
+    DivideByZero,
+    // an operation applied to values of the wrong type
+    Type,
+    // a unit could not be parsed, converted or combined
+    Unit,
+    // a function called with the wrong number of arguments
+    Arity,
+    // a math operation applied outside of its domain, or with a result too large to compute
+    Domain,
+    // an index that is not a positive integer within the bounds of a matrix or tuple
+    Index,
+    // a matrix without an inverse where one is needed
+    Singular,
+    // an unknown opcode, missing operand or unknown constant
+    InvalidInstruction,
+}
+
+/// RuntimeError struct, a failure raised while executing bytecode
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RuntimeError {
+    pub code: ErrorCode,
+    pub message: String,
+    // span of the failing instruction, filled in by the interpreter
+    pub span: Span,
+}
+
+/// RuntimeError implementation
+impl RuntimeError {
+    /// creates an error that is not yet located in the source
+    pub fn new(code: ErrorCode, message: String) -> RuntimeError {
+        RuntimeError { code, message, span: Span::default() }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}:{}] {}", self.span.line, self.span.column, self.message)
+    }
+}
+
+/// Limit enum, a resource limit that stopped a script along with its configured value
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum Limit {
+    Instructions(usize),
+    CallDepth(usize),
+    StackSize(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Instructions(n) => { write!(f, "resource limit exceeded: executed more than {} instructions", n) }
+            Limit::CallDepth(n) => { write!(f, "resource limit exceeded: calls nested more than {} deep", n) }
+            Limit::StackSize(n) => { write!(f, "resource limit exceeded: stack grew past {} values", n) }
+        }
+    }
+}
+
+/// LoadError enum, why a compiled program could not be loaded
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum LoadError {
+    Io(String),
+    // the file does not start with the magic bytes of a compiled program
+    NotBytecode,
+    UnsupportedVersion(u16),
+    // the file ends in the middle of a section
+    Truncated,
+    Malformed(String),
+    // a native function or constant the program uses is not in the registry
+    UnknownNative(String),
+    // the instructions were rejected by the verifier
+    Unverified(RuntimeError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => { write!(f, "{}", err) }
+            LoadError::NotBytecode => { write!(f, "not a compiled program") }
+            LoadError::UnsupportedVersion(version) => { write!(f, "unsupported bytecode version {}", version) }
+            LoadError::Truncated => { write!(f, "compiled program is truncated") }
+            LoadError::Malformed(message) => { write!(f, "malformed compiled program: {}", message) }
+            LoadError::UnknownNative(name) => { write!(f, "unknown native '{}'", name) }
+            LoadError::Unverified(err) => { write!(f, "compiled program failed verification: {}", err) }
+        }
+    }
+}
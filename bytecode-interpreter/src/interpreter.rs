@@ -1,21 +1,88 @@
 use std::collections::HashMap;
 use crate::vm::{SymbolValue, VirtualMachine, OpCode, InterpretResult, Func};
 use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
-use crate::lexer::{TokenValue, Token};
+use num_traits::{FromPrimitive, ToPrimitive, Zero, Signed};
+use crate::lexer::TokenValue;
 use std::rc::Rc;
 use std::cell::RefCell;
 use bigdecimal::BigDecimal;
 use std::ops::{Mul, MulAssign, SubAssign, Sub};
 use num_bigint::BigInt;
+use crate::units::{Quantity, Unit};
+use crate::numeric::{self, DEFAULT_PRECISION};
+use crate::linalg;
+use crate::bytecode;
+use crate::verifier;
+use crate::natives::{Registry, Constant};
+use std::cmp::Ordering;
+use std::path::Path;
+use crate::errors::{TraceFrame, RuntimeError, ErrorCode, Limit, LoadError};
+use wasm_bindgen::prelude::*;
+
+/// instructions executed between two calls of `Interpreter::interrupt`
+const INTERRUPT_INTERVAL: usize = 4096;
+
+/// Limits struct, the resources a single run of a script may use
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    // instructions executed, long running operations and copies of tuples and
+    // matrices are charged one per step or element
+    pub instructions: usize,
+    pub call_depth: usize,
+    pub stack_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { instructions: 10_000_000, call_depth: 10_000, stack_size: 1_000_000 }
+    }
+}
+
+#[wasm_bindgen]
+impl Limits {
+    /// creates the default limits, for hosts to adjust before running a script
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+}
+
+/// Halt enum, the reasons a run stops before reaching the end of the program
+pub enum Halt {
+    Error(RuntimeError),
+    Limit(Limit),
+    Cancelled,
+}
+
+impl From<RuntimeError> for Halt {
+    fn from(err: RuntimeError) -> Halt {
+        Halt::Error(err)
+    }
+}
 
 pub struct Interpreter {
     global: Vec<SymbolValue>,
     stack: Vec<SymbolValue>,
+    // stack slot of the return address of the innermost running call
+    frame: Option<usize>,
     err: bool,
     err_message: String,
     scope: usize,
+    // start of the instruction being executed
+    instruction: usize,
+    // instructions executed in the current run
+    executed: usize,
+    // asked before the first instruction and then every few thousand whether the host wants
+    // the script stopped, e.g. by checking a flag another thread sets
+    pub interrupt: Option<Box<dyn FnMut() -> bool>>,
+    pub limits: Limits,
+    // significant digits of results that cannot be computed exactly, such as 2^0.5
+    pub precision: u64,
+    // global slots filled with a constant, with the digits and the value they were filled with
+    constants: Vec<(usize, Constant, u64, BigDecimal)>,
     pub output: Vec<(usize, String)>,
+    pub trace: Vec<TraceFrame>,
 }
 
 impl Interpreter {
@@ -23,44 +90,209 @@ impl Interpreter {
         return Interpreter {
             global: Vec::new(),
             stack: Vec::new(),
+            frame: None,
             err: false,
             err_message: String::new(),
             scope: 0,
+            instruction: 0,
+            executed: 0,
+            interrupt: None,
+            limits: Limits::default(),
+            precision: DEFAULT_PRECISION,
+            constants: Vec::new(),
             output: Vec::new(),
+            trace: Vec::new(),
         };
     }
-    fn load(&mut self, ptr: usize, global: bool) {
+
+    /// value of a global slot, if a program has declared it
+    pub fn global(&self, slot: usize) -> Option<&SymbolValue> {
+        self.global.get(slot)
+    }
+
+    /// whether a global slot still holds the constant it was filled with
+    pub fn holds_constant(&self, slot: usize) -> bool {
+        self.constants.iter().any(|(s, _, _, value)| *s == slot && matches!(self.global.get(slot), Some(SymbolValue::Num(n)) if n == value))
+    }
+
+    /// recomputes the constants still in their slots to the current precision, natives that
+    /// change the precision call this so later reads of pi and e get the new digits
+    pub fn refresh_constants(&mut self) {
+        for (slot, constant, precision, value) in self.constants.iter_mut() {
+            if *precision == self.precision || !matches!(&self.global[*slot], SymbolValue::Num(n) if n == value) {
+                continue;
+            }
+            *precision = self.precision;
+            *value = (constant.value)(self.precision);
+            self.global[*slot] = SymbolValue::Num(value.clone());
+        }
+    }
+
+    /// charges instructions against the budget of the current run, natives charge their
+    /// long running operations here
+    pub fn consume(&mut self, instructions: usize) -> Result<(), Halt> {
+        let before = self.executed;
+        self.executed = self.executed.saturating_add(instructions);
+        if self.executed > self.limits.instructions {
+            return Err(Halt::Limit(Limit::Instructions(self.limits.instructions)));
+        }
+        if before == 0 || before / INTERRUPT_INTERVAL != self.executed / INTERRUPT_INTERVAL {
+            if let Some(interrupt) = self.interrupt.as_mut() {
+                if interrupt() {
+                    return Err(Halt::Cancelled);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// number of elements a copy of the value has to clone, plain values are free
+    fn weight(value: &SymbolValue) -> usize {
+        match value {
+            SymbolValue::Tuple(t) => { t.len() + t.iter().map(Interpreter::weight).sum::<usize>() }
+            SymbolValue::Matrix(m) => { m.iter().map(|row| row.len() + row.iter().map(Interpreter::weight).sum::<usize>()).sum() }
+            _ => { 0 }
+        }
+    }
+
+    /// pops the top of the stack
+    fn pop(&mut self) -> Result<SymbolValue, RuntimeError> {
+        self.stack.pop().ok_or_else(|| RuntimeError::new(ErrorCode::StackUnderflow, String::from("stack underflow")))
+    }
+
+    /// pops the operands of a binary operation, left operand first
+    fn pop_pair(&mut self) -> Result<(SymbolValue, SymbolValue), RuntimeError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        Ok((a, b))
+    }
+
+    fn load(&mut self, ptr: usize, global: bool) -> Result<(), RuntimeError> {
         if global {
             if ptr as isize == -1 {
                 self.global.push(SymbolValue::Null);
                 self.stack.push(SymbolValue::Null);
             } else {
-                self.stack.push(self.global[ptr].clone());
+                let value = self.global.get(ptr).cloned().ok_or_else(|| Interpreter::out_of_bounds(ptr))?;
+                self.stack.push(value);
             }
         } else {
             if ptr as isize == -1 {
                 self.stack.push(SymbolValue::Null);
             } else {
-                let index = self.stack.len() - ptr;
-                self.stack.push(self.stack[index].clone());
+                let value = self.stack.len().checked_sub(ptr)
+                    .and_then(|index| self.stack.get(index).cloned())
+                    .ok_or_else(|| Interpreter::bad_pointer(ptr))?;
+                self.stack.push(value);
             }
         }
+        Ok(())
     }
 
-    fn store(&mut self, ptr: usize, global: bool, value: SymbolValue) {
+    fn store(&mut self, ptr: usize, global: bool, value: SymbolValue) -> Result<(), RuntimeError> {
         if global {
             if ptr as isize != -1 {
-                self.global[ptr] = value.clone();
+                let slot = self.global.get_mut(ptr).ok_or_else(|| Interpreter::out_of_bounds(ptr))?;
+                *slot = value.clone();
             } else {
                 self.global.push(value.clone());
             }
             self.stack.push(value);
         } else {
             if ptr as isize != -1 {
-                self.stack[ptr] = value.clone();
+                // locals are addressed from the top of the stack, like in `load`
+                let slot = self.stack.len().checked_sub(ptr)
+                    .and_then(|index| self.stack.get_mut(index))
+                    .ok_or_else(|| Interpreter::bad_pointer(ptr))?;
+                *slot = value.clone();
             }
             self.stack.push(value);
         }
+        Ok(())
+    }
+
+    /// the value a pointer refers to, without pushing it
+    fn read(&self, ptr: usize, global: bool) -> Result<SymbolValue, RuntimeError> {
+        if ptr as isize == -1 {
+            Ok(SymbolValue::Null)
+        } else if global {
+            self.global.get(ptr).cloned().ok_or_else(|| Interpreter::out_of_bounds(ptr))
+        } else {
+            self.stack.len().checked_sub(ptr)
+                .and_then(|index| self.stack.get(index).cloned())
+                .ok_or_else(|| Interpreter::bad_pointer(ptr))
+        }
+    }
+
+    fn out_of_bounds(ptr: usize) -> RuntimeError {
+        RuntimeError::new(ErrorCode::OutOfBounds, format!("global slot {} is out of bounds", ptr))
+    }
+
+    fn bad_pointer(ptr: usize) -> RuntimeError {
+        RuntimeError::new(ErrorCode::BadPointer, format!("stack slot {} is outside of the stack", ptr))
+    }
+
+    fn type_error(message: String) -> RuntimeError {
+        RuntimeError::new(ErrorCode::Type, message)
+    }
+
+    fn unit_error(message: String) -> RuntimeError {
+        RuntimeError::new(ErrorCode::Unit, message)
+    }
+
+    fn divide_by_zero() -> RuntimeError {
+        RuntimeError::new(ErrorCode::DivideByZero, String::from("division by zero"))
+    }
+
+    fn domain_error(message: String) -> RuntimeError {
+        RuntimeError::new(ErrorCode::Domain, message)
+    }
+
+    fn index_error(message: String) -> RuntimeError {
+        RuntimeError::new(ErrorCode::Index, message)
+    }
+
+    /// raises a number to a power
+    fn pow(&self, a: &BigDecimal, b: &BigDecimal) -> Result<BigDecimal, RuntimeError> {
+        if a.is_zero() && b.is_negative() {
+            return Err(Interpreter::divide_by_zero());
+        }
+        if a.is_negative() && !b.is_integer() {
+            return Err(Interpreter::domain_error(format!("could not pow '{}' and '{}': fractional power of a negative number", a, b)));
+        }
+        numeric::pow(a, b, self.precision).map_err(Interpreter::domain_error)
+    }
+
+    /// raises a number or quantity to a power
+    fn scalar_pow(&self, a: &SymbolValue, b: &SymbolValue) -> Result<SymbolValue, RuntimeError> {
+        if let (SymbolValue::Num(x), SymbolValue::Num(y)) = (a, b) {
+            return Ok(SymbolValue::Num(self.pow(x, y)?));
+        }
+        match Interpreter::quantities(a, b) {
+            Some((qa, qb)) if qa.value.is_zero() && qb.value.is_negative() => { Err(Interpreter::divide_by_zero()) }
+            Some((qa, qb)) => { Interpreter::quantity(qa.pow(&qb)) }
+            None => { Err(Interpreter::type_error(format!("could not pow '{}' and '{}'", a, b))) }
+        }
+    }
+
+    /// applies a floored division operator, `%` or `//`, to numbers or quantities
+    fn floor_div(a: &SymbolValue, b: &SymbolValue, op: &str) -> Result<SymbolValue, RuntimeError> {
+        let divisor_zero = match b {
+            SymbolValue::Num(b) => { b.is_zero() }
+            SymbolValue::Quantity(b) => { b.value.is_zero() }
+            _ => { false }
+        };
+        if divisor_zero {
+            return Err(Interpreter::divide_by_zero());
+        }
+        if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (a, b) {
+            let result = if op == "%" { numeric::modulo(a, b) } else { numeric::int_div(a, b) };
+            return result.map(SymbolValue::Num).map_err(Interpreter::domain_error);
+        }
+        match Interpreter::quantities(a, b) {
+            Some((qa, qb)) => { Interpreter::quantity(if op == "%" { qa.modulo(&qb) } else { qa.int_div(&qb) }) }
+            None => { Err(Interpreter::type_error(format!("could not apply '{}' to '{}' and '{}'", op, a, b))) }
+        }
     }
 
     fn dims(&self, vec: &Vec<Vec<SymbolValue>>) -> (usize, usize) {
@@ -73,72 +305,570 @@ impl Interpreter {
         };
     }
 
-    fn add(&self, a: SymbolValue, b: SymbolValue) -> Result<SymbolValue, InterpretResult> {
-        return if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-            Ok(SymbolValue::Num((a + b)))
-        } else if let (SymbolValue::Matrix(a), SymbolValue::Matrix(b)) = (&a, &b) {
-            let dim_a = self.dims(a);
-            let dim_b = self.dims(b);
-            if dim_a == dim_b {
+    /// converts a pair of operands into quantities if at least one of them carries a unit
+    fn quantities(a: &SymbolValue, b: &SymbolValue) -> Option<(Quantity, Quantity)> {
+        match (a, b) {
+            (SymbolValue::Quantity(a), SymbolValue::Quantity(b)) => { Some((a.clone(), b.clone())) }
+            (SymbolValue::Quantity(a), SymbolValue::Num(b)) => { Some((a.clone(), Quantity::dimensionless(b.clone()))) }
+            (SymbolValue::Num(a), SymbolValue::Quantity(b)) => { Some((Quantity::dimensionless(a.clone()), b.clone())) }
+            _ => { None }
+        }
+    }
+
+    /// wraps a quantity result, collapsing dimensionless quantities back into numbers
+    fn quantity(result: Result<Quantity, String>) -> Result<SymbolValue, RuntimeError> {
+        match result {
+            Ok(q) if q.dim.is_dimensionless() => { Ok(SymbolValue::Num(q.value)) }
+            Ok(q) => { Ok(SymbolValue::Quantity(q)) }
+            Err(err) => { Err(Interpreter::unit_error(err)) }
+        }
+    }
+
+    /// compares two numbers or quantities of matching dimensions
+    pub fn compare(a: &SymbolValue, b: &SymbolValue) -> Result<Ordering, RuntimeError> {
+        if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (a, b) {
+            return Ok(a.cmp(b));
+        }
+        match Interpreter::quantities(a, b) {
+            Some((a, b)) => { a.compare(&b).map_err(Interpreter::unit_error) }
+            None => { Err(Interpreter::type_error(format!("could not compare '{}' and '{}'", a, b))) }
+        }
+    }
+
+    /// converts a number, quantity or every element of a matrix or tuple into the named unit
+    fn convert(&self, a: &SymbolValue, name: &str, unit: &Unit) -> Result<SymbolValue, RuntimeError> {
+        let result = match a {
+            SymbolValue::Num(n) => { Quantity::dimensionless(n.clone()).convert(name, unit) }
+            SymbolValue::Quantity(q) => { q.convert(name, unit) }
+            SymbolValue::Matrix(m) => {
                 let mut output = Vec::new();
-                for i in 0..dim_a.0 {
-                    let mut row = Vec::new();
-                    for j in 0..dim_a.1 {
-                        let result = self.add(a[i][j].clone(), b[i][j].clone());
-                        match result {
-                            Ok(val) => {
-                                row.push(val);
-                            }
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        }
-                    }
-                    output.push(row);
+                for row in m.iter() {
+                    output.push(row.iter().map(|el| self.convert(el, name, unit)).collect::<Result<Vec<_>, _>>()?);
+                }
+                return Ok(SymbolValue::Matrix(output));
+            }
+            SymbolValue::Tuple(t) => {
+                return Ok(SymbolValue::Tuple(t.iter().map(|el| self.convert(el, name, unit)).collect::<Result<Vec<_>, _>>()?));
+            }
+            _ => { return Err(Interpreter::type_error(format!("could not convert '{}' to '{}'", a, name))); }
+        };
+        result.map(SymbolValue::Quantity).map_err(Interpreter::unit_error)
+    }
+
+    /// checks if a value is a single number or quantity that can be broadcast over a matrix
+    fn is_scalar(value: &SymbolValue) -> bool {
+        matches!(value, SymbolValue::Num(_) | SymbolValue::Quantity(_))
+    }
+
+    /// names an arithmetic operator in error messages
+    fn verb(op: OpCode) -> &'static str {
+        match op {
+            OpCode::Add => { "add" }
+            OpCode::Sub => { "subtract" }
+            OpCode::Mul => { "multiply" }
+            _ => { "divide" }
+        }
+    }
+
+    /// applies `+ - * /` to two numbers or quantities
+    fn scalar_op(op: OpCode, a: &SymbolValue, b: &SymbolValue) -> Result<SymbolValue, RuntimeError> {
+        if let (SymbolValue::Num(x), SymbolValue::Num(y)) = (a, b) {
+            return match op {
+                OpCode::Add => { Ok(SymbolValue::Num(x + y)) }
+                OpCode::Sub => { Ok(SymbolValue::Num(x - y)) }
+                OpCode::Mul => { Ok(SymbolValue::Num(x * y)) }
+                _ if y.is_zero() => { Err(Interpreter::divide_by_zero()) }
+                _ => { Ok(SymbolValue::Num(x / y)) }
+            };
+        }
+        if let Some((qa, qb)) = Interpreter::quantities(a, b) {
+            return match op {
+                OpCode::Add => { Interpreter::quantity(qa.add(&qb)) }
+                OpCode::Sub => { Interpreter::quantity(qa.sub(&qb)) }
+                OpCode::Mul => { Interpreter::quantity(Ok(qa.mul(&qb))) }
+                _ if qb.value.is_zero() => { Err(Interpreter::divide_by_zero()) }
+                _ => { Interpreter::quantity(Ok(qa.div(&qb))) }
+            };
+        }
+        Err(Interpreter::type_error(format!("could not {} '{}' and '{}'", Interpreter::verb(op), a, b)))
+    }
+
+    /// applies `+ - * /` to two values, element by element for matrices and tuples of the same
+    /// dimensions and broadcasting numbers and quantities over matrices; `*` of two matrices is
+    /// the matrix product
+    fn arith(&mut self, op: OpCode, a: &SymbolValue, b: &SymbolValue) -> Result<SymbolValue, Halt> {
+        match (a, b) {
+            (SymbolValue::Matrix(x), SymbolValue::Matrix(y)) => {
+                if op == OpCode::Mul {
+                    return self.mat_mul(x, y);
+                }
+                let (dim_a, dim_b) = (self.dims(x), self.dims(y));
+                if op == OpCode::Div || dim_a != dim_b {
+                    return Err(Interpreter::type_error(format!("could not {} matrices of dimensions '{:?}' and '{:?}'", Interpreter::verb(op), dim_a, dim_b)).into());
+                }
+                self.consume(dim_a.0 * dim_a.1)?;
+                let mut output = Vec::new();
+                for (row_a, row_b) in x.iter().zip(y.iter()) {
+                    output.push(row_a.iter().zip(row_b.iter()).map(|(el_a, el_b)| self.arith(op, el_a, el_b)).collect::<Result<Vec<_>, _>>()?);
                 }
                 Ok(SymbolValue::Matrix(output))
-            } else {
-                Err(InterpretResult::RuntimeError(String::from(format!("could not add matrices of dimensions '{:?}' and '{:?}'", dim_a, dim_b))))
             }
-        } else if let (SymbolValue::Tuple(a), SymbolValue::Tuple(b)) = (&a, &b) {
-            let dim_a = a.len();
-            let dim_b = b.len();
-            return if dim_a == dim_b {
+            (SymbolValue::Tuple(x), SymbolValue::Tuple(y)) => {
+                if x.len() != y.len() {
+                    return Err(Interpreter::type_error(format!("could not {} tuples of dimensions '{}' and '{}'", Interpreter::verb(op), x.len(), y.len())).into());
+                }
+                self.consume(x.len())?;
+                Ok(SymbolValue::Tuple(x.iter().zip(y.iter()).map(|(el_a, el_b)| self.arith(op, el_a, el_b)).collect::<Result<Vec<_>, _>>()?))
+            }
+            (SymbolValue::Matrix(x), y) if Interpreter::is_scalar(y) => {
                 let mut output = Vec::new();
-                for i in 0..dim_a {
-                    let out = (self.add(a[i].clone(), b[i].clone()));
-                    match out {
-                        Ok(val) => {
-                            output.push(val);
-                        }
-                        Err(err) => {
-                            return Err(err);
-                        }
-                    }
+                for row in x.iter() {
+                    self.consume(row.len())?;
+                    output.push(row.iter().map(|el| self.arith(op, el, y)).collect::<Result<Vec<_>, _>>()?);
                 }
-                Ok(SymbolValue::Tuple(output))
-            } else {
-                Err(InterpretResult::RuntimeError(String::from(format!("could not add tuples of dimensions '{:?}' and '{:?}'", dim_a, dim_b))))
-            };
-        } else {
-            Err(InterpretResult::RuntimeError(String::from(format!("could not add '{}' and '{}'", a, b))))
+                Ok(SymbolValue::Matrix(output))
+            }
+            (x, SymbolValue::Matrix(y)) if Interpreter::is_scalar(x) => {
+                let mut output = Vec::new();
+                for row in y.iter() {
+                    self.consume(row.len())?;
+                    output.push(row.iter().map(|el| self.arith(op, x, el)).collect::<Result<Vec<_>, _>>()?);
+                }
+                Ok(SymbolValue::Matrix(output))
+            }
+            _ => { Ok(Interpreter::scalar_op(op, a, b)?) }
+        }
+    }
+
+    /// applies `.* ./ .^` element by element, broadcasting like NumPy: numbers and quantities
+    /// stretch over matrices, and a row or column of size 1 stretches to the size of the other
+    /// operand's, so a row vector combines with every row of a matrix
+    fn elementwise(&mut self, op: OpCode, a: &SymbolValue, b: &SymbolValue) -> Result<SymbolValue, Halt> {
+        let (x, y) = match (a, b) {
+            (SymbolValue::Matrix(x), SymbolValue::Matrix(y)) => { (x, y) }
+            (SymbolValue::Matrix(x), s) if Interpreter::is_scalar(s) => {
+                let mut output = Vec::new();
+                for row in x.iter() {
+                    self.consume(row.len())?;
+                    output.push(row.iter().map(|el| self.elementwise(op, el, s)).collect::<Result<Vec<_>, _>>()?);
+                }
+                return Ok(SymbolValue::Matrix(output));
+            }
+            (s, SymbolValue::Matrix(y)) if Interpreter::is_scalar(s) => {
+                let mut output = Vec::new();
+                for row in y.iter() {
+                    self.consume(row.len())?;
+                    output.push(row.iter().map(|el| self.elementwise(op, s, el)).collect::<Result<Vec<_>, _>>()?);
+                }
+                return Ok(SymbolValue::Matrix(output));
+            }
+            _ if op == OpCode::ElemPow => { return Ok(self.scalar_pow(a, b)?); }
+            _ if op == OpCode::ElemMul => { return self.arith(OpCode::Mul, a, b); }
+            _ => { return self.arith(OpCode::Div, a, b); }
         };
+
+        let (dim_a, dim_b) = (self.dims(x), self.dims(y));
+        let stretch = |n: usize, m: usize| if n == m || m == 1 { Some(n) } else if n == 1 { Some(m) } else { None };
+        let (rows, cols) = match (stretch(dim_a.0, dim_b.0), stretch(dim_a.1, dim_b.1)) {
+            (Some(rows), Some(cols)) => { (rows, cols) }
+            _ => {
+                return Err(Interpreter::type_error(format!("could not broadcast matrices of dimensions '{:?}' and '{:?}'", dim_a, dim_b)).into());
+            }
+        };
+        // a row or column of size 1 is read again for every index of the stretched dimension
+        let index = |i: usize, n: usize| if n == 1 { 0 } else { i };
+        let mut output = Vec::new();
+        for i in 0..rows {
+            self.consume(cols)?;
+            let row = (0..cols).map(|j| {
+                self.elementwise(op, &x[index(i, dim_a.0)][index(j, dim_a.1)], &y[index(i, dim_b.0)][index(j, dim_b.1)])
+            }).collect::<Result<Vec<_>, _>>()?;
+            output.push(row);
+        }
+        Ok(SymbolValue::Matrix(output))
+    }
+
+    /// pops the indices of an `Index` or `IndexAssign`, with `None` in place of each `:`
+    fn pop_indices(&mut self, count: usize, mask: usize) -> Result<Vec<Option<SymbolValue>>, RuntimeError> {
+        if count == 0 || count > 2 {
+            return Err(RuntimeError::new(ErrorCode::InvalidInstruction, format!("expected 1 or 2 indices, found {}", count)));
+        }
+        let mut indices = Vec::new();
+        for i in (0..count).rev() {
+            indices.push(if (mask >> i) & 1 == 1 { None } else { Some(self.pop()?) });
+        }
+        indices.reverse();
+        Ok(indices)
+    }
+
+    /// the zero based positions selected by a one based index into a dimension of size `len`, a
+    /// number selects a single position and a vector or tuple of numbers selects several, `None`
+    /// selects all of them
+    fn positions(index: Option<&SymbolValue>, len: usize) -> Result<(Vec<usize>, bool), RuntimeError> {
+        let position = |value: &SymbolValue| match value {
+            SymbolValue::Num(n) if n.is_integer() && n.is_positive() => {
+                let i = n.to_usize().unwrap_or(usize::MAX);
+                if i > len {
+                    Err(Interpreter::index_error(format!("index {} is out of bounds for a dimension of size {}", n, len)))
+                } else {
+                    Ok(i - 1)
+                }
+            }
+            SymbolValue::Num(n) => { Err(Interpreter::index_error(format!("index '{}' is not a positive integer", n))) }
+            value => { Err(Interpreter::type_error(format!("could not index with '{}'", value))) }
+        };
+        match index {
+            None => { Ok(((0..len).collect(), false)) }
+            Some(SymbolValue::Matrix(m)) if m.len() <= 1 || m.iter().all(|row| row.len() == 1) => {
+                Ok((m.iter().flatten().map(position).collect::<Result<_, _>>()?, false))
+            }
+            Some(SymbolValue::Tuple(t)) => { Ok((t.iter().map(position).collect::<Result<_, _>>()?, false)) }
+            Some(value) => { Ok((vec![position(value)?], true)) }
+        }
+    }
+
+    /// selects `base[i]` or `base[i, j]`: a single index reads matrices row by row and keeps the
+    /// shape of vectors, two indices give an element if both are numbers and a sub-matrix otherwise
+    fn index_value(&mut self, base: &SymbolValue, indices: &[Option<SymbolValue>]) -> Result<SymbolValue, Halt> {
+        match (base, indices) {
+            (SymbolValue::Tuple(t), [index]) => {
+                let (positions, single) = Interpreter::positions(index.as_ref(), t.len())?;
+                self.consume(positions.len())?;
+                let mut selected: Vec<SymbolValue> = positions.iter().map(|&i| t[i].clone()).collect();
+                Ok(if single { selected.remove(0) } else { SymbolValue::Tuple(selected) })
+            }
+            (SymbolValue::Matrix(m), [index]) => {
+                let (rows, cols) = self.dims(m);
+                let (positions, single) = Interpreter::positions(index.as_ref(), rows * cols)?;
+                self.consume(positions.len())?;
+                let mut selected: Vec<SymbolValue> = positions.iter().map(|&i| m[i / cols][i % cols].clone()).collect();
+                Ok(if single {
+                    selected.remove(0)
+                } else if cols == 1 && rows > 1 {
+                    SymbolValue::Matrix(selected.into_iter().map(|el| vec![el]).collect())
+                } else {
+                    SymbolValue::Matrix(vec![selected])
+                })
+            }
+            (SymbolValue::Matrix(m), [row, col]) => {
+                let (rows, cols) = self.dims(m);
+                let (row_positions, single_row) = Interpreter::positions(row.as_ref(), rows)?;
+                let (col_positions, single_col) = Interpreter::positions(col.as_ref(), cols)?;
+                if single_row && single_col {
+                    return Ok(m[row_positions[0]][col_positions[0]].clone());
+                }
+                let mut output = Vec::new();
+                for &i in row_positions.iter() {
+                    self.consume(col_positions.len())?;
+                    output.push(col_positions.iter().map(|&j| m[i][j].clone()).collect());
+                }
+                Ok(SymbolValue::Matrix(output))
+            }
+            (SymbolValue::Tuple(_), _) => {
+                Err(Interpreter::index_error(format!("could not index a tuple with {} indices", indices.len())).into())
+            }
+            _ => { Err(Interpreter::type_error(format!("could not index '{}'", base)).into()) }
+        }
+    }
+
+    /// assigns `value` to the selection `base[i]` or `base[i, j]`, a matrix or tuple with as many
+    /// elements as the selection fills it in order and any other value fills every element
+    fn assign_index(&mut self, base: SymbolValue, indices: &[Option<SymbolValue>], value: &SymbolValue) -> Result<SymbolValue, Halt> {
+        // the element positions selected, as (row, column) pairs for matrices and (0, i) for tuples
+        let tuple = matches!(base, SymbolValue::Tuple(_));
+        let (selection, shape, mut base) = match (base, indices) {
+            (SymbolValue::Tuple(t), [index]) => {
+                let (positions, _) = Interpreter::positions(index.as_ref(), t.len())?;
+                let shape = (1, positions.len());
+                (positions.into_iter().map(|i| (0, i)).collect::<Vec<_>>(), shape, vec![t])
+            }
+            (SymbolValue::Matrix(m), [index]) => {
+                let (rows, cols) = self.dims(&m);
+                let (positions, _) = Interpreter::positions(index.as_ref(), rows * cols)?;
+                let shape = if cols == 1 { (positions.len(), 1) } else { (1, positions.len()) };
+                (positions.into_iter().map(|i| (i / cols, i % cols)).collect(), shape, m)
+            }
+            (SymbolValue::Matrix(m), [row, col]) => {
+                let (rows, cols) = self.dims(&m);
+                let (row_positions, _) = Interpreter::positions(row.as_ref(), rows)?;
+                let (col_positions, _) = Interpreter::positions(col.as_ref(), cols)?;
+                let shape = (row_positions.len(), col_positions.len());
+                let selection = row_positions.iter().flat_map(|&i| col_positions.iter().map(move |&j| (i, j))).collect();
+                (selection, shape, m)
+            }
+            (SymbolValue::Tuple(_), _) => {
+                return Err(Interpreter::index_error(format!("could not index a tuple with {} indices", indices.len())).into());
+            }
+            (base, _) => { return Err(Interpreter::type_error(format!("could not index '{}'", base)).into()); }
+        };
+
+        let values: Vec<SymbolValue> = match value {
+            SymbolValue::Matrix(v) => {
+                let dims = self.dims(v);
+                let vector = dims.0 == 1 || dims.1 == 1;
+                if dims != shape && !(vector && (shape.0 == 1 || shape.1 == 1) && dims.0 * dims.1 == selection.len()) {
+                    return Err(Interpreter::type_error(format!("could not assign a matrix of dimensions '{:?}' to a selection of dimensions '{:?}'", dims, shape)).into());
+                }
+                v.iter().flatten().cloned().collect()
+            }
+            SymbolValue::Tuple(t) if t.len() == selection.len() && selection.len() > 1 => { t.clone() }
+            value => { vec![value.clone(); selection.len()] }
+        };
+        self.consume(selection.len() + values.iter().map(Interpreter::weight).sum::<usize>())?;
+        for ((i, j), value) in selection.into_iter().zip(values) {
+            base[i][j] = value;
+        }
+        Ok(if tuple { SymbolValue::Tuple(base.remove(0)) } else { SymbolValue::Matrix(base) })
+    }
+
+    /// the row vector `start, start + step, ...` of every value up to and including `end`
+    fn range(&mut self, start: &SymbolValue, step: &SymbolValue, end: &SymbolValue) -> Result<SymbolValue, Halt> {
+        let (start, step, end) = match (start, step, end) {
+            (SymbolValue::Num(start), SymbolValue::Num(step), SymbolValue::Num(end)) => { (start, step, end) }
+            _ => { return Err(Interpreter::type_error(format!("could not make a range of '{}', '{}' and '{}'", start, step, end)).into()); }
+        };
+        if step.is_zero() {
+            return Err(Interpreter::domain_error(String::from("the step of a range cannot be zero")).into());
+        }
+        let count = numeric::int_div(&(end - start), step).map_err(Interpreter::domain_error)?;
+        let count = if count.is_negative() { 0 } else { count.to_usize().unwrap_or(usize::MAX).saturating_add(1) };
+        self.consume(count)?;
+        let row = (0..count).map(|i| SymbolValue::Num(start + step * BigDecimal::from(i as u64))).collect();
+        Ok(SymbolValue::Matrix(vec![row]))
+    }
+
+    /// multiplies an `n x m` matrix by an `m x p` matrix
+    fn mat_mul(&mut self, x: &Vec<Vec<SymbolValue>>, y: &Vec<Vec<SymbolValue>>) -> Result<SymbolValue, Halt> {
+        let (dim_a, dim_b) = (self.dims(x), self.dims(y));
+        if dim_a.1 != dim_b.0 {
+            return Err(Interpreter::type_error(format!("could not multiply matrices of dimensions '{:?}' and '{:?}'", dim_a, dim_b)).into());
+        }
+        self.consume(dim_a.0.saturating_mul(dim_b.1).saturating_mul(dim_a.1))?;
+        let mut output = Vec::new();
+        for row in x.iter() {
+            let out_row = (0..dim_b.1).map(|col| {
+                let mut sum = SymbolValue::Num(BigDecimal::zero());
+                for (k, el) in row.iter().enumerate() {
+                    let product = self.arith(OpCode::Mul, el, &y[k][col])?;
+                    sum = if k == 0 { product } else { self.arith(OpCode::Add, &sum, &product)? };
+                }
+                Ok(sum)
+            }).collect::<Result<Vec<_>, Halt>>()?;
+            output.push(out_row);
+        }
+        Ok(SymbolValue::Matrix(output))
+    }
+
+    /// raises a square matrix to a non-negative integer power by repeated squaring
+    fn mat_pow(&mut self, m: &Vec<Vec<SymbolValue>>, n: &BigDecimal) -> Result<SymbolValue, Halt> {
+        let (rows, cols) = self.dims(m);
+        if rows != cols {
+            return Err(Interpreter::type_error(format!("could not pow a matrix of dimensions '{:?}': it is not square", (rows, cols))).into());
+        }
+        let exponent = if n.is_integer() { n.to_u64() } else { None };
+        let mut n = match exponent {
+            Some(n) => { n }
+            None => { return Err(Interpreter::type_error(format!("could not pow a matrix and '{}': exponent must be a non-negative integer", n)).into()); }
+        };
+        let mut result = SymbolValue::Matrix((0..rows).map(|i| (0..rows).map(|j| SymbolValue::Num(BigDecimal::from((i == j) as u8))).collect()).collect());
+        let mut square = SymbolValue::Matrix(m.clone());
+        while n > 0 {
+            if n & 1 == 1 {
+                result = self.arith(OpCode::Mul, &result, &square)?;
+                Interpreter::check_power(&result)?;
+            }
+            n >>= 1;
+            if n > 0 {
+                square = self.arith(OpCode::Mul, &square, &square)?;
+                Interpreter::check_power(&square)?;
+            }
+        }
+        Ok(result)
+    }
+
+    /// fails once an element of a matrix power needs more digits than an exact power may have,
+    /// since every further squaring doubles them
+    fn check_power(power: &SymbolValue) -> Result<(), RuntimeError> {
+        if let SymbolValue::Matrix(m) = power {
+            for value in m.iter().flatten() {
+                let digits = match value {
+                    SymbolValue::Num(n) => { n.digits() }
+                    SymbolValue::Quantity(q) => { q.value.digits() }
+                    _ => { 0 }
+                };
+                if digits > numeric::MAX_EXACT_DIGITS {
+                    return Err(Interpreter::domain_error(format!("could not pow a matrix: its elements grow past {} digits", numeric::MAX_EXACT_DIGITS)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// negates a number, quantity or every element of a matrix or tuple
+    fn negate(a: &SymbolValue) -> Result<SymbolValue, RuntimeError> {
+        match a {
+            SymbolValue::Num(n) => { Ok(SymbolValue::Num(-n)) }
+            SymbolValue::Quantity(q) => { Ok(SymbolValue::Quantity(q.neg())) }
+            SymbolValue::Matrix(m) => {
+                let mut output = Vec::new();
+                for row in m.iter() {
+                    output.push(row.iter().map(Interpreter::negate).collect::<Result<Vec<_>, _>>()?);
+                }
+                Ok(SymbolValue::Matrix(output))
+            }
+            SymbolValue::Tuple(t) => { Ok(SymbolValue::Tuple(t.iter().map(Interpreter::negate).collect::<Result<Vec<_>, _>>()?)) }
+            _ => { Err(Interpreter::type_error(format!("could not negate '{}'", a))) }
+        }
+    }
+
+    /// checks if two values are equal, matrices and tuples are equal when they have the same
+    /// dimensions and equal elements, and numbers, strings and booleans of different types are
+    /// never equal; comparing functions or natives is a type error and comparing quantities of
+    /// different dimensions a unit error
+    fn equal(a: &SymbolValue, b: &SymbolValue) -> Result<bool, RuntimeError> {
+        match (a, b) {
+            (SymbolValue::Num(x), SymbolValue::Num(y)) => { Ok(x == y) }
+            (SymbolValue::Bool(x), SymbolValue::Bool(y)) => { Ok(x == y) }
+            (SymbolValue::Str(x), SymbolValue::Str(y)) => { Ok(x == y) }
+            (SymbolValue::Null, SymbolValue::Null) => { Ok(true) }
+            (SymbolValue::Matrix(x), SymbolValue::Matrix(y)) => {
+                if x.len() != y.len() {
+                    return Ok(false);
+                }
+                for (row_a, row_b) in x.iter().zip(y.iter()) {
+                    if !Interpreter::all_equal(row_a, row_b)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            (SymbolValue::Tuple(x), SymbolValue::Tuple(y)) => { Interpreter::all_equal(x, y) }
+            (SymbolValue::Func(_), _) | (_, SymbolValue::Func(_)) | (SymbolValue::Native(_), _) | (_, SymbolValue::Native(_))
+            | (SymbolValue::Pointer { .. }, _) | (_, SymbolValue::Pointer { .. })
+            | (SymbolValue::Return { .. }, _) | (_, SymbolValue::Return { .. }) => {
+                Err(Interpreter::type_error(format!("could not compare '{}' and '{}'", a, b)))
+            }
+            _ => {
+                match Interpreter::quantities(a, b) {
+                    Some(_) => { Ok(Interpreter::compare(a, b)? == Ordering::Equal) }
+                    None => { Ok(false) }
+                }
+            }
+        }
+    }
+
+    /// checks if two rows of values have the same length and pairwise equal elements
+    fn all_equal(x: &[SymbolValue], y: &[SymbolValue]) -> Result<bool, RuntimeError> {
+        if x.len() != y.len() {
+            return Ok(false);
+        }
+        for (el_a, el_b) in x.iter().zip(y.iter()) {
+            if !Interpreter::equal(el_a, el_b)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
     /// begins interpreting the instruction set
     pub fn interpret(&mut self, mut vm: VirtualMachine) -> InterpretResult {
+        self.execute(&mut vm)
+    }
+
+    /// loads a compiled program written by `bytecode::save` and runs it with the standard natives
+    pub fn interpret_file(&mut self, path: impl AsRef<Path>) -> Result<InterpretResult, LoadError> {
+        let vm = bytecode::load(path, &Registry::standard())?;
+        Ok(self.interpret(vm))
+    }
+
+    /// runs the instructions of `vm` from its instruction pointer, with the globals left by the
+    /// programs this interpreter ran before
+    pub fn execute(&mut self, vm: &mut VirtualMachine) -> InterpretResult {
+        self.executed = 0;
+        // a program that failed may have left values and calls behind
+        self.stack.clear();
+        self.frame = None;
+        self.scope = 0;
+        self.trace.clear();
+        if let Err(err) = verifier::verify(vm) {
+            return InterpretResult::RuntimeError(err);
+        }
+        let declared = self.global.len();
+        if declared < vm.globals {
+            self.global.resize(vm.globals, SymbolValue::Null);
+        }
+        // natives and constants fill the slots this program declared for them, earlier programs may have reassigned older ones
+        for (slot, native) in vm.natives.iter() {
+            if *slot >= declared {
+                self.global[*slot] = SymbolValue::Native(*native);
+            }
+        }
+        for (slot, constant) in vm.constants.iter() {
+            if *slot >= declared {
+                let value = (constant.value)(self.precision);
+                self.global[*slot] = SymbolValue::Num(value.clone());
+                self.constants.push((*slot, *constant, self.precision, value));
+            }
+        }
+        // the host may have changed the precision since the constants of earlier programs were computed
+        self.refresh_constants();
+        match self.run(vm) {
+            Ok(()) => { InterpretResult::Ok }
+            Err(Halt::Error(mut err)) => {
+                err.span = vm.span(self.instruction).unwrap_or_default();
+                self.trace(vm);
+                InterpretResult::RuntimeError(err)
+            }
+            Err(Halt::Limit(limit)) => {
+                self.trace(vm);
+                InterpretResult::ResourceLimitExceeded(limit)
+            }
+            Err(Halt::Cancelled) => { InterpretResult::Cancelled }
+        }
+    }
+
+    /// number of calls currently running
+    fn depth(&self) -> usize {
+        match self.frame.and_then(|at| self.stack.get(at)) {
+            Some(SymbolValue::Return { depth, .. }) => { *depth }
+            _ => { 0 }
+        }
+    }
+
+    /// records the calls that were still running when execution stopped
+    fn trace(&mut self, vm: &VirtualMachine) {
+        // innermost call first, following the return addresses on the stack, each located at its `FnCall` instruction
+        let mut trace = Vec::new();
+        let mut frame = self.frame;
+        while let Some(SymbolValue::Return { ptr, func, caller, .. }) = frame.and_then(|at| self.stack.get(at)) {
+            trace.push(TraceFrame {
+                function: func.borrow().name.clone(),
+                span: ptr.checked_sub(OpCode::FnCall.size()).and_then(|ip| vm.span(ip)).unwrap_or_default(),
+            });
+            frame = *caller;
+        }
+        self.trace = trace;
+    }
+
+    /// executes instructions until the program halts or fails
+    fn run(&mut self, vm: &mut VirtualMachine) -> Result<(), Halt> {
         loop {
+            self.consume(1)?;
+            if self.stack.len() > self.limits.stack_size {
+                return Err(Halt::Limit(Limit::StackSize(self.limits.stack_size)));
+            }
             if vm._ip >= vm.instructions.len() {
-                return InterpretResult::RuntimeError(String::from("expected a return"));
+                return Err(RuntimeError::new(ErrorCode::InvalidInstruction, String::from("expected a return")).into());
             }
 
+            self.instruction = vm._ip;
             let instruction = vm.next_instruction();
             // check for argument errors
 
-            match FromPrimitive::from_usize(instruction) {
+            match FromPrimitive::from_u8(instruction) {
                 Some(OpCode::Constant) => {
-                    let const_idx = vm.next_instruction();
-                    let value = &vm.consts[const_idx];
+                    let const_idx = vm.next_operand()?;
+                    let value = vm.constant(const_idx)?;
 
                     match value {
                         TokenValue::Number(n) => {
@@ -147,215 +877,216 @@ impl Interpreter {
                         TokenValue::String(s) => {
                             self.stack.push(SymbolValue::Str(s.clone()))
                         }
+                        TokenValue::Bool(b) => {
+                            self.stack.push(SymbolValue::Bool(*b))
+                        }
                     }
                 }
                 Some(OpCode::Symbol) => {
-                    let offset = vm.next_instruction();
-                    let global = vm.next_instruction() == 1;
-                    let name_ptr = vm.next_instruction();
-                    let name = vm.consts[name_ptr].clone();
-                    if let TokenValue::String(str) = name {
-                        print!("");
-                    }
+                    let offset = vm.next_operand()?;
+                    let global = vm.next_operand()? == 1;
+                    vm.next_operand()?;
                     self.stack.push(SymbolValue::Pointer { global, ptr: offset });
                 }
                 Some(OpCode::And) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
+                    let (a, b) = self.pop_pair()?;
 
                     if let (SymbolValue::Bool(a), SymbolValue::Bool(b)) = (&a, &b) {
                         self.stack.push(SymbolValue::Bool(*a && *b));
                     } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not subtract '{}' and '{}'", a, b)));
+                        return Err(Interpreter::type_error(format!("could not and '{}' and '{}'", a, b)).into());
                     }
                 }
                 Some(OpCode::Load) => {
-                    let offset = self.stack.pop().unwrap();
+                    let offset = self.pop()?;
                     if let SymbolValue::Pointer { ptr, global } = offset {
-                        self.load(ptr, global);
+                        self.load(ptr, global)?;
+                        self.consume(self.stack.last().map_or(0, Interpreter::weight))?;
                     } else {
-                        return InterpretResult::RuntimeError(String::from("expected a memory address to load"));
+                        return Err(RuntimeError::new(ErrorCode::BadPointer, String::from("expected a memory address to load")).into());
                     }
                 }
                 Some(OpCode::Assign) => {
-                    let value = self.stack.pop().unwrap();
-                    let offset = self.stack.pop().unwrap();
+                    let (offset, value) = self.pop_pair()?;
                     if let SymbolValue::Pointer { ptr, global } = offset {
-                        self.store(ptr, global, value);
+                        self.consume(Interpreter::weight(&value))?;
+                        self.store(ptr, global, value)?;
                     } else {
-                        return InterpretResult::RuntimeError(String::from("expected a memory address to assign"));
+                        return Err(RuntimeError::new(ErrorCode::BadPointer, String::from("expected a memory address to assign")).into());
                     }
                 }
                 Some(OpCode::UnaryPos) => {}
                 Some(OpCode::UnaryNeg) => {
-                    let a = self.stack.pop().unwrap();
-
-                    if let SymbolValue::Num(a) = a {
-                        self.stack.push(SymbolValue::Num(-a));
-                    }
-                }
-                Some(OpCode::UnaryNot) => {}
-                Some(OpCode::Add) => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-
-                    let out = self.add(a, b);
-                    match out {
-                        Ok(val) => {
-                            self.stack.push(val);
-                        }
-                        Err(err) => {
-                            return err;
-                        }
-                    }
-                }
-                Some(OpCode::Sub) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Num(a - b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not subtract '{}' and '{}'", a, b)));
-                    }
+                    let a = self.pop()?;
+                    self.consume(Interpreter::weight(&a))?;
+                    self.stack.push(Interpreter::negate(&a)?);
                 }
-                Some(OpCode::Mul) => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
+                Some(OpCode::UnaryNot) => {
+                    let a = self.pop()?;
 
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Num(a * b));
+                    if let SymbolValue::Bool(a) = a {
+                        self.stack.push(SymbolValue::Bool(!a));
                     } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not multiply '{}' and '{}'", a, b)));
+                        return Err(Interpreter::type_error(format!("expected a boolean operand for '!', found '{}'", a)).into());
                     }
                 }
-                Some(OpCode::Div) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Num(a / b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not divide '{}' and '{}'", a, b)));
-                    }
+                Some(op @ (OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div)) => {
+                    let (a, b) = self.pop_pair()?;
+                    let val = self.arith(op, &a, &b)?;
+                    self.stack.push(val);
                 }
                 Some(OpCode::Pow) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
+                    let (a, b) = self.pop_pair()?;
 
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Num(a * b));
+                    if let (SymbolValue::Matrix(m), SymbolValue::Num(n)) = (&a, &b) {
+                        let val = self.mat_pow(m, n)?;
+                        self.stack.push(val);
                     } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not pow '{}' and '{}'", a, b)));
+                        let val = self.scalar_pow(&a, &b)?;
+                        self.stack.push(val);
                     }
                 }
+                Some(op @ (OpCode::ElemMul | OpCode::ElemDiv | OpCode::ElemPow)) => {
+                    let (a, b) = self.pop_pair()?;
+                    let val = self.elementwise(op, &a, &b)?;
+                    self.stack.push(val);
+                }
+                Some(OpCode::Mod) => {
+                    let (a, b) = self.pop_pair()?;
+                    self.stack.push(Interpreter::floor_div(&a, &b, "%")?);
+                }
+                Some(OpCode::IntDiv) => {
+                    let (a, b) = self.pop_pair()?;
+                    self.stack.push(Interpreter::floor_div(&a, &b, "//")?);
+                }
                 Some(OpCode::Or) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
+                    let (a, b) = self.pop_pair()?;
 
                     if let (SymbolValue::Bool(a), SymbolValue::Bool(b)) = (&a, &b) {
                         self.stack.push(SymbolValue::Bool(*a || *b));
                     } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not or '{}' and '{}'", a, b)));
+                        return Err(Interpreter::type_error(format!("could not or '{}' and '{}'", a, b)).into());
                     }
                 }
                 Some(OpCode::Print) => {
-                    let line = vm.lines[vm._ip - 1] - 1;
-                    if self.stack.len() >= 1 {
-                        self.output.push((line, self.stack.last().unwrap().clone().to_string()));
+                    let line = vm.line(self.instruction).map_or(0, |line| line.saturating_sub(1));
+                    if let Some(top) = self.stack.last() {
+                        self.output.push((line, top.to_string()));
                     } else {
                         self.output.push((line, String::from("null")));
                     }
                 }
                 Some(OpCode::Branch) => {
-                    let b = self.stack.pop().unwrap();
-                    let ptr = vm.next_instruction();
+                    let b = self.pop()?;
+                    let ptr = vm.next_operand()?;
 
                     if let SymbolValue::Bool(b) = &b {
                         if !*b {
                             vm._ip = ptr;
                         }
                     } else {
-                        return InterpretResult::RuntimeError(String::from(format!("cannot perform ternary operation on non-boolean expression: '{}'", b)));
+                        return Err(Interpreter::type_error(format!("expected a boolean condition, found '{}'", b)).into());
                     }
                 }
                 Some(OpCode::Tuple) => {
                     let mut tuple: Vec<SymbolValue> = Vec::new();
-                    let size = vm.next_instruction();
+                    let size = vm.next_operand()?;
                     for _ in 0..size {
-                        tuple.push(self.stack.pop().unwrap());
+                        tuple.push(self.pop()?);
                     }
                     tuple.reverse();
-                    self.stack.push(SymbolValue::Tuple(tuple));
+                    let tuple = SymbolValue::Tuple(tuple);
+                    self.consume(Interpreter::weight(&tuple))?;
+                    self.stack.push(tuple);
                 }
                 Some(OpCode::Matrix) => {
                     let mut matrix = Vec::new();
-                    let rows = vm.next_instruction();
-                    let cols = vm.next_instruction();
+                    let rows = vm.next_operand()?;
+                    let cols = vm.next_operand()?;
+                    // rows are allocated up front, even when they hold no elements
+                    self.consume(rows)?;
 
                     for _ in 0..rows {
                         let mut row = Vec::new();
                         for _ in 0..cols {
-                            row.push(self.stack.pop().unwrap());
+                            row.push(self.pop()?);
                         }
                         row.reverse();
                         matrix.push(row);
                     }
                     matrix.reverse();
-                    self.stack.push(SymbolValue::Matrix(matrix));
+                    let matrix = SymbolValue::Matrix(matrix);
+                    self.consume(Interpreter::weight(&matrix))?;
+                    self.stack.push(matrix);
                 }
                 Some(OpCode::FnDecl) => {
-                    let args = vm.next_instruction();
-                    let offset = self.stack.pop().unwrap();
-                    let start = self.stack.len() - vm.next_instruction();
+                    let args = vm.next_operand()?;
+                    let offset = self.pop()?;
+                    let captured = vm.next_operand()?;
+                    let start = self.stack.len().checked_sub(captured).ok_or_else(|| Interpreter::bad_pointer(captured))?;
+                    let name_ptr = vm.next_operand()?;
+                    let name = vm.constant(name_ptr)?.to_string();
 
                     if let (SymbolValue::Pointer { ptr, global }) = offset {
                         let mut closure = Func {
+                            name,
                             args,
-                            ptr: vm._ip + 2,
+                            // the body starts after the jump that skips it
+                            ptr: vm._ip + OpCode::Jump.size(),
                             closure_stack: Vec::from(&self.stack[start..]),
                         };
                         let closure_pointer = Rc::new(RefCell::new(closure));
                         if !global {
                             {
                                 let mut closure_ref = closure_pointer.borrow_mut();
-                                (*closure_ref).closure_stack.remove(0);
+                                if !closure_ref.closure_stack.is_empty() {
+                                    closure_ref.closure_stack.remove(0);
+                                }
                                 (*closure_ref).closure_stack.push(SymbolValue::Func(closure_pointer.clone()));
                             }
                         }
-                        self.store(ptr, global, SymbolValue::Func(closure_pointer.clone()));
+                        self.store(ptr, global, SymbolValue::Func(closure_pointer.clone()))?;
+                    } else {
+                        return Err(RuntimeError::new(ErrorCode::BadPointer, String::from("expected a memory address to declare a function")).into());
                     }
                 }
                 Some(OpCode::Return) => {
-                    let offset = vm.next_instruction();
-                    let last = self.stack.pop().unwrap();
+                    let offset = vm.next_operand()?;
+                    let last = self.pop()?;
                     for _ in 0..(offset) {
-                        self.stack.pop().unwrap();
+                        self.pop()?;
                     }
 
-                    let return_addr = self.stack.pop().unwrap();
+                    let return_addr = self.pop()?;
                     self.stack.push(last);
-                    if let SymbolValue::Pointer { ptr, global } = return_addr {
+                    if let SymbolValue::Return { ptr, caller, .. } = return_addr {
                         vm._ip = ptr;
+                        self.frame = caller;
                     } else {
-                        return InterpretResult::RuntimeError(String::from("expected pointer to return address"));
+                        return Err(RuntimeError::new(ErrorCode::BadPointer, String::from("expected pointer to return address")).into());
                     }
                 }
                 Some(OpCode::FnCall) => {
-                    let num_args = vm.next_instruction();
+                    let num_args = vm.next_operand()?;
                     let mut arg_values = Vec::new();
                     for i in 0..num_args {
-                        arg_values.push(self.stack.pop().unwrap());
+                        arg_values.push(self.pop()?);
                     }
                     arg_values.reverse();
-                    let func_ptr = self.stack.pop().unwrap();
+                    let func_ptr = self.pop()?;
 
                     if let SymbolValue::Func(ptr) = func_ptr {
                         let func = ptr.borrow();
+                        let depth = self.depth() + 1;
+                        if depth > self.limits.call_depth {
+                            return Err(Halt::Limit(Limit::CallDepth(self.limits.call_depth)));
+                        }
                         if func.args != num_args {
-                            return InterpretResult::RuntimeError(String::from("incorrect number of arguments"));
+                            return Err(RuntimeError::new(ErrorCode::Arity, format!("'{}' expects {} arguments, found {}", func.name, func.args, num_args)).into());
                         }
-                        self.stack.push(SymbolValue::Pointer { ptr: vm._ip, global: false });
+                        self.stack.push(SymbolValue::Return { ptr: vm._ip, func: ptr.clone(), caller: self.frame, depth });
+                        self.frame = Some(self.stack.len() - 1);
+                        self.consume(func.closure_stack.iter().map(Interpreter::weight).sum())?;
                         self.stack.extend(func.closure_stack.clone());
 
                         vm._ip = func.ptr;
@@ -363,103 +1094,382 @@ impl Interpreter {
                         for val in arg_values {
                             self.stack.push(val);
                         }
+                    } else if let SymbolValue::Native(native) = func_ptr {
+                        if !native.arity.accepts(num_args) {
+                            return Err(RuntimeError::new(ErrorCode::Arity, format!("'{}' expects {} arguments, found {}", native.name, native.arity, num_args)).into());
+                        }
+                        let value = (native.func)(self, &arg_values)?;
+                        self.stack.push(value);
                     } else {
-                        return InterpretResult::RuntimeError(String::from(format!("couldn't call '{}'", func_ptr)));
+                        return Err(Interpreter::type_error(format!("couldn't call '{}'", func_ptr)).into());
                     }
                 }
                 Some(OpCode::BeginScope) => {
                     self.scope += 1;
                 }
                 Some(OpCode::EndScope) => {
-                    self.scope -= 1;
+                    self.scope = self.scope.saturating_sub(1);
                 }
                 Some(OpCode::Jump) => {
-                    let loc = vm.next_instruction();
+                    let loc = vm.next_operand()?;
                     vm._ip = loc;
                 }
+                Some(OpCode::JumpIfFalse) | Some(OpCode::JumpIfTrue) => {
+                    // the left operand of `&&` or `||` stays on the stack, either as the result or
+                    // to be combined with the right operand
+                    let loc = vm.next_operand()?;
+                    let short = instruction == OpCode::JumpIfTrue as u8;
+                    match self.stack.last() {
+                        Some(SymbolValue::Bool(b)) => {
+                            if *b == short {
+                                vm._ip = loc;
+                            }
+                        }
+                        Some(val) => {
+                            let op = if short { "||" } else { "&&" };
+                            return Err(Interpreter::type_error(format!("expected a boolean operand for '{}', found '{}'", op, val)).into());
+                        }
+                        None => { return Err(RuntimeError::new(ErrorCode::StackUnderflow, String::from("stack underflow")).into()); }
+                    }
+                }
                 Some(OpCode::EndProgram) => {
-                    return InterpretResult::Ok;
+                    return Ok(());
                 }
                 Some(OpCode::LessThan) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Bool(a < b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not compare '{}' and '{}'", a, b)));
-                    }
+                    let (a, b) = self.pop_pair()?;
+                    let ord = Interpreter::compare(&a, &b)?;
+                    self.stack.push(SymbolValue::Bool(ord == Ordering::Less));
                 }
                 Some(OpCode::MoreThan) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Bool(a > b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not compare '{}' and '{}'", a, b)));
-                    }
+                    let (a, b) = self.pop_pair()?;
+                    let ord = Interpreter::compare(&a, &b)?;
+                    self.stack.push(SymbolValue::Bool(ord == Ordering::Greater));
                 }
                 Some(OpCode::LessEqThan) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Bool(a <= b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not compare '{}' and '{}'", a, b)));
-                    }
+                    let (a, b) = self.pop_pair()?;
+                    let ord = Interpreter::compare(&a, &b)?;
+                    self.stack.push(SymbolValue::Bool(ord != Ordering::Greater));
                 }
                 Some(OpCode::MoreEqThan) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Bool(a >= b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not compare '{}' and '{}'", a, b)));
-                    }
+                    let (a, b) = self.pop_pair()?;
+                    let ord = Interpreter::compare(&a, &b)?;
+                    self.stack.push(SymbolValue::Bool(ord != Ordering::Less));
                 }
                 Some(OpCode::Eq) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Bool(a == b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not compare '{}' and '{}'", a, b)));
-                    }
+                    let (a, b) = self.pop_pair()?;
+                    self.consume(Interpreter::weight(&a))?;
+                    self.stack.push(SymbolValue::Bool(Interpreter::equal(&a, &b)?));
                 }
                 Some(OpCode::NotEq) => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
+                    let (a, b) = self.pop_pair()?;
+                    self.consume(Interpreter::weight(&a))?;
+                    self.stack.push(SymbolValue::Bool(!Interpreter::equal(&a, &b)?));
+                }
+                Some(OpCode::Unit) => {
+                    let unit_idx = vm.next_operand()?;
+                    let a = self.pop()?;
+                    let unit = match vm.constant(unit_idx)? {
+                        TokenValue::String(expr) => { vm.units.parse(expr) }
+                        value => { Err(format!("expected a unit, found '{}'", value)) }
+                    };
 
-                    if let (SymbolValue::Num(a), SymbolValue::Num(b)) = (&a, &b) {
-                        self.stack.push(SymbolValue::Bool(a != b));
-                    } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not compare '{}' and '{}'", a, b)));
+                    match (&a, unit) {
+                        (SymbolValue::Num(n), Ok(unit)) => {
+                            self.stack.push(Interpreter::quantity(Ok(Quantity::new(n, &unit)))?);
+                        }
+                        (_, Err(err)) => {
+                            return Err(Interpreter::unit_error(err).into());
+                        }
+                        _ => {
+                            return Err(Interpreter::type_error(format!("could not attach a unit to '{}'", a)).into());
+                        }
                     }
                 }
+                Some(OpCode::Convert) => {
+                    let unit_idx = vm.next_operand()?;
+                    let a = self.pop()?;
+                    let name = vm.constant(unit_idx)?.to_string();
+
+                    let unit = vm.units.parse(&name).map_err(Interpreter::unit_error)?;
+                    let val = self.convert(&a, &name, &unit)?;
+                    self.stack.push(val);
+                }
                 Some(OpCode::Factorial) => {
-                    let a = self.stack.pop().unwrap();
+                    let a = self.pop()?;
 
                     if let (SymbolValue::Num(a)) = &a {
                         if !a.is_integer() {
-                            return InterpretResult::RuntimeError(String::from("cannot take factorial of decimal"));
+                            return Err(Interpreter::type_error(String::from("cannot take factorial of decimal")).into());
                         }
                         let mut value = a.clone();
                         let mut out = BigDecimal::from(1);
                         while value >= BigDecimal::from(1) {
+                            self.consume(1)?;
                             out.mul_assign(value.clone());
                             value = value.sub(BigDecimal::from(1));
                         }
                         self.stack.push(SymbolValue::Num(out));
                     } else {
-                        return InterpretResult::RuntimeError(String::from(format!("could not fact '{}' and", a)));
+                        return Err(Interpreter::type_error(format!("could not fact '{}' and", a)).into());
                     }
                 }
-                _ => {}
+                Some(OpCode::Index) => {
+                    let count = vm.next_operand()?;
+                    let mask = vm.next_operand()?;
+                    let indices = self.pop_indices(count, mask)?;
+                    let base = match self.pop()? {
+                        SymbolValue::Pointer { ptr, global } => {
+                            // indexing a variable reads it in place rather than loading a copy
+                            let value = self.read(ptr, global)?;
+                            self.consume(Interpreter::weight(&value))?;
+                            value
+                        }
+                        value => { value }
+                    };
+                    let value = self.index_value(&base, &indices)?;
+                    self.stack.push(value);
+                }
+                Some(OpCode::IndexAssign) => {
+                    let count = vm.next_operand()?;
+                    let mask = vm.next_operand()?;
+                    let value = self.pop()?;
+                    let indices = self.pop_indices(count, mask)?;
+                    if let SymbolValue::Pointer { ptr, global } = self.pop()? {
+                        let base = self.read(ptr, global)?;
+                        let updated = self.assign_index(base, &indices, &value)?;
+                        self.store(ptr, global, updated)?;
+                        // `store` leaves the whole matrix, an assignment evaluates to the value assigned
+                        self.pop()?;
+                        self.stack.push(value);
+                    } else {
+                        return Err(RuntimeError::new(ErrorCode::BadPointer, String::from("expected a memory address to assign")).into());
+                    }
+                }
+                Some(OpCode::Range) => {
+                    let parts = vm.next_operand()?;
+                    let end = self.pop()?;
+                    let (start, step) = match parts {
+                        2 => { (self.pop()?, SymbolValue::Num(BigDecimal::from(1))) }
+                        3 => { self.pop_pair()? }
+                        _ => { return Err(RuntimeError::new(ErrorCode::InvalidInstruction, format!("expected a range of 2 or 3 parts, found {}", parts)).into()); }
+                    };
+                    let range = self.range(&start, &step, &end)?;
+                    self.stack.push(range);
+                }
+                Some(OpCode::Unpack) => {
+                    let count = vm.next_operand()?;
+                    match self.stack.last() {
+                        Some(SymbolValue::Tuple(t)) if t.len() == count => {}
+                        Some(value) => {
+                            return Err(Interpreter::type_error(format!("could not unpack '{}' into {} names", value, count)).into());
+                        }
+                        None => { return Err(RuntimeError::new(ErrorCode::StackUnderflow, String::from("stack underflow")).into()); }
+                    }
+                }
+                Some(OpCode::Transpose) => {
+                    let a = self.pop()?;
+                    let value = linalg::transpose(self, &[a])?;
+                    self.stack.push(value);
+                }
+                Some(OpCode::Pop) => {
+                    let count = vm.next_operand()?;
+                    for _ in 0..count {
+                        self.pop()?;
+                    }
+                }
+                Some(OpCode::Slide) => {
+                    let count = vm.next_operand()?;
+                    let top = self.pop()?;
+                    for _ in 0..count {
+                        self.pop()?;
+                    }
+                    self.stack.push(top);
+                }
+                Some(OpCode::Null) => {
+                    self.stack.push(SymbolValue::Null);
+                }
+                Some(OpCode::Iterate) => {
+                    // the loop's iterable and index sit beneath the loop variable's pointer
+                    let exit = vm.next_operand()?;
+                    let len = self.stack.len();
+                    if len < 3 {
+                        return Err(RuntimeError::new(ErrorCode::StackUnderflow, String::from("stack underflow")).into());
+                    }
+                    let index = match &self.stack[len - 2] {
+                        SymbolValue::Num(n) => { n.to_usize() }
+                        _ => { None }
+                    }.ok_or_else(|| RuntimeError::new(ErrorCode::BadPointer, String::from("expected a loop index")))?;
+                    let element = match &self.stack[len - 3] {
+                        SymbolValue::Tuple(t) => { t.get(index).cloned() }
+                        SymbolValue::Matrix(m) => {
+                            // matrices are iterated element by element, row by row
+                            let cols = m.first().map_or(0, |row| row.len());
+                            index.checked_div(cols).and_then(|row| m.get(row)).and_then(|row| row.get(index % cols)).cloned()
+                        }
+                        iterable => { return Err(Interpreter::type_error(format!("cannot iterate over '{}'", iterable)).into()); }
+                    };
+                    match element {
+                        Some(element) => {
+                            self.consume(Interpreter::weight(&element))?;
+                            self.stack[len - 2] = SymbolValue::Num(BigDecimal::from(index as u64 + 1));
+                            self.stack.push(element);
+                        }
+                        None => { vm._ip = exit; }
+                    }
+                }
+                _ => {
+                    return Err(RuntimeError::new(ErrorCode::InvalidInstruction, format!("unknown instruction {}", instruction)).into());
+                }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Span;
+    use crate::compiler::Compiler;
+    use crate::vm::{self, Lcg};
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering as MemoryOrdering};
+
+    /// runs hand-written bytecode, failing the test unless it ends in a runtime error
+    fn run_bytecode(code: Vec<u8>, consts: Vec<TokenValue>) -> RuntimeError {
+        let mut vm = VirtualMachine::new();
+        // each instruction that decodes spans the column of its first byte
+        vm.starts = vm::decode(&code).map_or(Vec::new(), |ops| ops.iter().map(|(start, _)| *start).collect());
+        vm.lines = vec![1; vm.starts.len()];
+        vm.spans = vm.starts.iter().map(|&i| Span { start: i, end: i + 1, line: 1, column: i + 1 }).collect();
+        vm.instructions = code;
+        vm.consts = consts;
+        match Interpreter::new().interpret(vm) {
+            InterpretResult::RuntimeError(err) => { err }
+            result => { panic!("expected a runtime error, found {:?}", result) }
+        }
+    }
+
+    #[test]
+    fn test_malformed_bytecode() {
+        let name = || vec![TokenValue::String(String::from("x"))];
+        let end = (OpCode::EndProgram, &[][..]);
+
+        let err = run_bytecode(vm::assemble(&[(OpCode::Print, &[]), (OpCode::Add, &[]), end]), Vec::new());
+        assert_eq!((err.code, err.span.column), (ErrorCode::StackUnderflow, 2));
+
+        let err = run_bytecode(vm::assemble(&[(OpCode::Symbol, &[5, 0, 0]), (OpCode::Load, &[]), end]), name());
+        assert_eq!((err.code, err.span.column), (ErrorCode::BadPointer, OpCode::Symbol.size() + 1));
+
+        let err = run_bytecode(vm::assemble(&[(OpCode::Symbol, &[7, 1, 0]), (OpCode::Load, &[]), end]), name());
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::OutOfBounds, "global slot 7 is out of bounds"));
+
+        let err = run_bytecode(vm::assemble(&[(OpCode::Constant, &[0]), (OpCode::Constant, &[0]), (OpCode::Assign, &[]), end]), name());
+        assert_eq!(err.code, ErrorCode::BadPointer);
+
+        let err = run_bytecode(vm::assemble(&[(OpCode::Constant, &[3]), end]), name());
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::InvalidInstruction, "unknown constant 3"));
+
+        assert_eq!(run_bytecode(vec![OpCode::Constant as u8, 0], name()).code, ErrorCode::InvalidInstruction);
+        assert_eq!(run_bytecode(vec![200, OpCode::EndProgram as u8], Vec::new()).code, ErrorCode::InvalidInstruction);
+        assert_eq!(run_bytecode(vec![OpCode::Print as u8], Vec::new()).message, "expected a return");
+    }
+
+    /// compiles and runs a program under the given limits
+    fn run_limited(source: &str, limits: Limits) -> (Interpreter, InterpretResult) {
+        let mut c = Compiler::new(source);
+        c.compile();
+        assert!(c.diagnostics.is_empty());
+        c.vm.reset();
+        let mut interpreter = Interpreter::new();
+        interpreter.limits = limits;
+        let result = interpreter.interpret(c.vm);
+        (interpreter, result)
+    }
+
+    #[test]
+    fn test_limits() {
+        let (interpreter, result) = run_limited("f(n) = f(n + 1)\nf(0)", Limits::default());
+        assert_eq!(result, InterpretResult::ResourceLimitExceeded(Limit::CallDepth(10_000)));
+        assert_eq!(interpreter.trace.len(), 10_000);
+
+        let limits = Limits { instructions: 50, ..Limits::default() };
+        let (interpreter, result) = run_limited("x = 1\nx + 1\nf(n) = f(n)\nf(x)", limits);
+        assert_eq!(result, InterpretResult::ResourceLimitExceeded(Limit::Instructions(50)));
+        assert_eq!(interpreter.output.len(), 3);
+
+        // a single long running instruction still uses up the budget
+        let limits = Limits { instructions: 1000, ..Limits::default() };
+        assert_eq!(run_limited("100000!", limits).1, InterpretResult::ResourceLimitExceeded(Limit::Instructions(1000)));
+
+        let limits = Limits { stack_size: 100, ..Limits::default() };
+        assert_eq!(run_limited("f(n) = f(n + 1)\nf(0)", limits).1, InterpretResult::ResourceLimitExceeded(Limit::StackSize(100)));
+
+        assert_eq!(Limit::CallDepth(3).to_string(), "resource limit exceeded: calls nested more than 3 deep");
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut c = Compiler::new("x = 1\nf(n) = f(n)\nf(x)");
+        c.compile();
+        c.vm.reset();
+        let mut interpreter = Interpreter::new();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = cancelled.clone();
+        interpreter.interrupt = Some(Box::new(move || flag.load(MemoryOrdering::Relaxed)));
+        cancelled.store(true, MemoryOrdering::Relaxed);
+        assert_eq!(interpreter.interpret(c.vm), InterpretResult::Cancelled);
+        assert!(interpreter.output.is_empty());
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut c = Compiler::new("x = 0\nwhile 1 < 2 {\n  x = x + 1\n}");
+        c.compile();
+        c.vm.reset();
+        let mut interpreter = Interpreter::new();
+        let polls = Rc::new(Cell::new(0));
+        let counter = polls.clone();
+        interpreter.interrupt = Some(Box::new(move || {
+            counter.set(counter.get() + 1);
+            counter.get() == 3
+        }));
+        assert_eq!(interpreter.interpret(c.vm), InterpretResult::Cancelled);
+        assert_eq!(polls.get(), 3);
+    }
+
+    #[test]
+    fn test_fuzz_bytecode() {
+        // random opcodes, each followed by small operands or by stray bytes, with a few constants
+        // to point at
+        let mut rng = Lcg(0xb17e);
+        let opcodes = OpCode::Print as usize..OpCode::None as usize + 1;
+        for _ in 0..5000 {
+            let len = 1 + rng.next(32);
+            let mut code = Vec::new();
+            while code.len() < len * 2 {
+                let op: OpCode = FromPrimitive::from_usize(opcodes.start + rng.next(opcodes.len())).unwrap();
+                code.push(op as u8);
+                for _ in 0..op.operands() {
+                    let operand = rng.next(code.len() + 8);
+                    vm::encode_operand(&mut code, operand);
+                }
+                if rng.next(4) == 0 {
+                    code.push(rng.next(256) as u8);
+                }
+            }
+            code.truncate(len * 2 - rng.next(2));
+            let consts = vec![TokenValue::Number(BigDecimal::from(2)), TokenValue::String(String::from("m")), TokenValue::String(String::from("x"))];
+
+            let mut vm = VirtualMachine::new();
+            vm.instructions = code.clone();
+            vm.consts = consts;
+            let outcome = std::panic::catch_unwind(|| {
+                let mut interpreter = Interpreter::new();
+                interpreter.limits.instructions = 10_000;
+                interpreter.interpret(vm)
+            });
+            assert!(outcome.is_ok(), "panicked on {:?}", code);
+        }
+    }
+}
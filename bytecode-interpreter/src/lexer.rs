@@ -2,8 +2,12 @@ use bigdecimal::BigDecimal;
 use std::str::FromStr;
 use std::ptr::replace;
 use std::mem::swap;
+use serde::Serialize;
 
-pub const RESERVED_WORDS: [&str; 2] = ["true", "false"];
+// `in`, `unit` and `dimension` are not reserved, they only act as keywords after the variable of a
+// `for` loop and before the name a declaration introduces
+pub const RESERVED_WORDS: [&str; 6] = ["if", "else", "while", "for", "break", "continue"];
+pub const RESERVED_VALUES: [&str; 2] = ["true", "false"];
 
 /// Lexer struct
 pub struct Lexer {
@@ -12,6 +16,8 @@ pub struct Lexer {
     pub position: usize,
     pub prev_token: Token,
     pub cur_token: Token,
+    pub line: usize,
+    line_start: usize,
     pub err: bool,
     pub err_message: String,
 }
@@ -35,6 +41,11 @@ pub enum TokenType {
     SubEq,
     MulEq,
     DivEq,
+    IntDiv,
+    DotMul,
+    DotDiv,
+    DotPow,
+    Convert,
     NotUnary,
     Plus,
     Minus,
@@ -81,6 +92,11 @@ impl TokenType {
             "-=" => { Some(TokenType::SubEq) }
             "*=" => { Some(TokenType::MulEq) }
             "/=" => { Some(TokenType::DivEq) }
+            "//" => { Some(TokenType::IntDiv) }
+            ".*" => { Some(TokenType::DotMul) }
+            "./" => { Some(TokenType::DotDiv) }
+            ".^" => { Some(TokenType::DotPow) }
+            "->" => { Some(TokenType::Convert) }
             _ => { None }
         };
     }
@@ -136,6 +152,11 @@ impl TokenType {
             TokenType::SubEq => { "-=" }
             TokenType::MulEq => { "*=" }
             TokenType::DivEq => { "/=" }
+            TokenType::IntDiv => { "//" }
+            TokenType::DotMul => { ".*" }
+            TokenType::DotDiv => { "./" }
+            TokenType::DotPow => { ".^" }
+            TokenType::Convert => { "->" }
             TokenType::NotUnary => { "!" }
             TokenType::Plus => { "+" }
             TokenType::Minus => { "-" }
@@ -174,8 +195,25 @@ impl TokenType {
 pub enum TokenValue {
     Number(BigDecimal),
     String(String),
+    Bool(bool),
+}
+
+
+/// Span struct, a range of source bytes along with the line and column it starts at
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
 }
 
+impl Span {
+    /// extends the span to cover everything up to the end of another span
+    pub fn to(self, other: Span) -> Span {
+        Span { end: self.end.max(other.end), ..self }
+    }
+}
 
 /// Token structure
 #[derive(Clone, Debug)]
@@ -183,12 +221,29 @@ pub struct Token {
     pub token: TokenType,
     pub value: TokenValue,
     pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+    pub length: usize,
 }
 
 impl Token {
     /// creates empty token
     pub fn eof() -> Token {
-        Token { token: TokenType::Eof, value: TokenValue::String(TokenType::Eof.value().to_string()), line: 0 }
+        Token { token: TokenType::Eof, value: TokenValue::String(TokenType::Eof.value().to_string()), line: 0, column: 0, offset: 0, length: 0 }
+    }
+
+    /// source span covered by the token
+    pub fn span(&self) -> Span {
+        Span { start: self.offset, end: self.offset + self.length, line: self.line, column: self.column }
+    }
+
+    /// describes the token for error messages
+    pub fn describe(&self) -> String {
+        match self.token {
+            TokenType::EndL => { String::from("end of line") }
+            TokenType::Eof => { String::from("end of file") }
+            _ => { format!("'{}'", self.value) }
+        }
     }
 }
 
@@ -213,6 +268,11 @@ impl Lexer {
         }
     }
 
+    /// column of the current character, starting at 1
+    pub fn column(&self) -> usize {
+        self.position - self.line_start + 1
+    }
+
     /// advances current character sequence position until character is not a space
     fn ignore_whitespace(&mut self) {
         while self.current.is_whitespace() && self.current != '\n' {
@@ -225,6 +285,7 @@ impl Lexer {
 impl Lexer {
     /// tokenizes next number
     fn tokenize_number(&mut self) -> Token {
+        let (offset, column) = (self.position, self.column());
         let mut num = String::new();
 
         while self.current.is_ascii_digit() {
@@ -232,8 +293,9 @@ impl Lexer {
             self.advance();
         }
 
-        // check for a decimal point, floating point num
-        if self.current == '.' {
+        // check for a decimal point, floating point num, unless it starts an element-wise operator
+        let next = self.text.get(self.position + 1).copied();
+        if self.current == '.' && !matches!(next, Some(b'*') | Some(b'/') | Some(b'^')) {
             num.push(self.current);
             self.advance();
 
@@ -245,11 +307,12 @@ impl Lexer {
         }
 
         // construct a new token object with specified number as value
-        Token { token: TokenType::Num, value: TokenValue::Number(BigDecimal::from_str(num.as_str()).unwrap()), line: self.cur_token.line }
+        Token { token: TokenType::Num, value: TokenValue::Number(BigDecimal::from_str(num.as_str()).unwrap()), line: self.line, column, offset, length: self.position - offset }
     }
 
     /// converts a string of characters into an identifier
     fn tokenize_identifier(&mut self) -> Token {
+        let (offset, column) = (self.position, self.column());
         let mut str = String::new();
 
         while self.current.is_alphanumeric() || self.current == '_' {
@@ -257,11 +320,14 @@ impl Lexer {
             self.advance();
         }
 
-        // check if identifier is a reserved word
+        // check if identifier is a reserved word or value
+        let length = self.position - offset;
         if RESERVED_WORDS.contains(&str.as_str()) {
-            Token { token: TokenType::ReservedValue, value: TokenValue::String(str), line: self.cur_token.line }
+            Token { token: TokenType::Reserved, value: TokenValue::String(str), line: self.line, column, offset, length }
+        } else if RESERVED_VALUES.contains(&str.as_str()) {
+            Token { token: TokenType::ReservedValue, value: TokenValue::String(str), line: self.line, column, offset, length }
         } else {
-            Token { token: TokenType::Id, value: TokenValue::String(str), line: self.cur_token.line }
+            Token { token: TokenType::Id, value: TokenValue::String(str), line: self.line, column, offset, length }
         }
     }
 
@@ -272,7 +338,7 @@ impl Lexer {
 
         return if !self.has_next() {
             //  reached end of file
-            Ok(Token { token: TokenType::Eof, value: TokenValue::String(TokenType::Eof.value().to_string()), line: self.cur_token.line + 1 })
+            Ok(Token { token: TokenType::Eof, value: TokenValue::String(TokenType::Eof.value().to_string()), line: self.line, column: self.column(), offset: self.position, length: 0 })
         } else if self.current.is_ascii_digit() {
             // tokenize a number
             Ok(self.tokenize_number())
@@ -284,20 +350,21 @@ impl Lexer {
             // search single
             let mut tok_str = String::from(self.current);
             let mut result = TokenType::match_single_symbol(tok_str.as_str());
-            let mut line = self.cur_token.line;
+            let (line, offset, column) = (self.line, self.position, self.column());
             if result.is_none() {
-                return Err(format!("unexpected symbol {}", tok_str));
+                return Err(format!("unexpected symbol '{}'", tok_str));
             }
 
             let mut tok = result.unwrap();
 
+            self.advance();
+
             // when new line is encountered, increment line number
             if tok == TokenType::EndL {
-                line += 1;
+                self.line += 1;
+                self.line_start = self.position;
             }
 
-            self.advance();
-
             // check next token for symbol with 2 characters
             if self.has_next() {
                 tok_str.push(self.current);
@@ -309,7 +376,7 @@ impl Lexer {
                     tok_str.pop();
                 }
             }
-            Ok(Token { token: tok, value: TokenValue::String(tok_str), line })
+            Ok(Token { token: tok, value: TokenValue::String(tok_str), line, column, offset, length: self.position - offset })
         };
     }
 
@@ -335,12 +402,17 @@ impl Lexer {
             text: Vec::from(bytes),
             position: 0,
             current: cur,
-            cur_token: Token { token: TokenType::Dot, value: TokenValue::String(String::from("eof")), line: 1 },
+            cur_token: Token { token: TokenType::Dot, value: TokenValue::String(String::from("eof")), line: 1, column: 1, offset: 0, length: 0 },
             prev_token: Token {
                 token: TokenType::Dot,
                 value: TokenValue::String(String::from("eof")),
                 line: 1,
+                column: 1,
+                offset: 0,
+                length: 0,
             },
+            line: 1,
+            line_start: 0,
             err: false,
             err_message: String::new(),
         }
@@ -372,13 +444,20 @@ impl Lexer {
         return Ok(self.cur_token.clone());
     }
 
+    /// skips the current character, used to recover from an unexpected symbol
+    pub fn skip(&mut self) {
+        self.advance();
+    }
+
     /// retrieves next token without advancing the current position
     pub fn peek_token(&mut self) -> Result<Token, String> {
         let tmp_pos = self.position;
         let tmp_char = self.current;
+        let tmp_line = (self.line, self.line_start);
         let tok = self.tokenize();
         self.position = tmp_pos;
         self.current = tmp_char;
+        (self.line, self.line_start) = tmp_line;
         return tok;
     }
 
@@ -388,15 +467,18 @@ impl Lexer {
         let tmp_char = self.current;
         let tmp_prev = self.prev_token.clone();
         let tmp_cur = self.cur_token.clone();
-
+        let tmp_line = (self.line, self.line_start);
 
         let mut tok = self.cur_token.clone();
         while tok.token == TokenType::RParen {
-            self.next_token();
+            if self.next_token().is_err() {
+                break;
+            }
             tok = self.cur_token.clone();
         }
         self.position = tmp_pos;
         self.current = tmp_char;
+        (self.line, self.line_start) = tmp_line;
         self.prev_token = tmp_prev;
         self.cur_token = tmp_cur;
         return tok.token == TokenType::Assign;
@@ -442,4 +524,20 @@ mod tests {
         assert_eq!(tok.token, TokenType::Num);
         println!("{:?}", tok.value);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_spans() {
+        let text = "x = 12\n  foo -> km";
+        let mut lexer = Lexer::new(text);
+
+        let mut spans = Vec::new();
+        loop {
+            let tok = lexer.next_token().unwrap();
+            if tok.token == TokenType::Eof {
+                break;
+            }
+            spans.push((tok.line, tok.column, &text[tok.offset..tok.offset + tok.length]));
+        }
+        assert_eq!(spans, vec![(1, 1, "x"), (1, 3, "="), (1, 5, "12"), (1, 7, "\n"), (2, 3, "foo"), (2, 7, "->"), (2, 10, "km")]);
+    }
+}
@@ -2,11 +2,22 @@ mod compiler;
 mod interpreter;
 mod lexer;
 mod vm;
+mod units;
+mod errors;
+mod numeric;
+mod natives;
+mod linalg;
+mod stdlib;
+mod session;
+mod bytecode;
+mod verifier;
 
 use compiler::Compiler;
-use interpreter::Interpreter;
+use interpreter::{Interpreter, Limits};
 use vm::SymbolValue;
 use vm::InterpretResult;
+use lexer::Span;
+use errors::TraceFrame;
 use wasm_bindgen::JsValue;
 use wasm_bindgen::closure;
 use serde::Serialize;
@@ -17,24 +28,57 @@ use wasm_bindgen::prelude::*;
 #[derive(Serialize)]
 struct InterpretOutput {
     disassembly: Vec<String>,
-    asm: Vec<usize>,
+    asm: Vec<u8>,
+    spans: Vec<Span>,
     output: Vec<(usize, String)>,
     result: InterpretResult,
+    trace: Vec<TraceFrame>,
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// host object asked every few thousand instructions whether the running script should stop,
+    /// e.g. `{ cancelled: () => performance.now() > deadline }`
+    pub type Cancellation;
+
+    #[wasm_bindgen(method)]
+    fn cancelled(this: &Cancellation) -> bool;
 }
 
 #[wasm_bindgen]
 pub fn lib_interpret(text: &str) -> JsValue {
+    interpret(text, Limits::default(), None)
+}
+
+/// runs a script within `limits`, stopping early once `cancellation` asks to
+#[wasm_bindgen]
+pub fn lib_interpret_with(text: &str, limits: &Limits, cancellation: Option<Cancellation>) -> JsValue {
+    interpret(text, *limits, cancellation)
+}
+
+fn interpret(text: &str, limits: Limits, cancellation: Option<Cancellation>) -> JsValue {
     let mut c = Compiler::new(text);
     c.compile();
+    if !c.diagnostics.is_empty() {
+        let result = InterpretResult::CompileError(c.diagnostics);
+        let iout = InterpretOutput { output: Vec::new(), disassembly: Vec::new(), result, asm: Vec::new(), spans: Vec::new(), trace: Vec::new() };
+        return JsValue::from_serde(&iout).unwrap();
+    }
     let disassembly = c.vm.disassemble(false);
     let asm = c.vm.instructions.clone();
+    let spans = c.vm.spans.clone();
     c.vm.reset();
 
     let mut interpreter = Interpreter::new();
+    interpreter.limits = limits;
+    if let Some(cancellation) = cancellation {
+        interpreter.interrupt = Some(Box::new(move || cancellation.cancelled()));
+    }
 
     let result = interpreter.interpret(c.vm);
     let mut output = interpreter.output;
+    let trace = interpreter.trace;
 
-    let iout = InterpretOutput { output, disassembly, result, asm };
+    let iout = InterpretOutput { output, disassembly, result, asm, spans, trace };
     JsValue::from_serde(&iout).unwrap()
 }
\ No newline at end of file
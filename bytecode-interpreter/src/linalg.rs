@@ -0,0 +1,577 @@
+use bigdecimal::{BigDecimal, One, Zero, Signed};
+use bigdecimal::num_bigint::BigInt;
+use crate::vm::SymbolValue;
+use crate::interpreter::{Interpreter, Halt};
+use crate::errors::{RuntimeError, ErrorCode};
+use crate::numeric;
+use crate::natives::{Arity, NativeFn};
+
+/// matrices whose entries would need more digits than this to be made integers are rejected
+const MAX_DIGITS: u64 = 1_000_000;
+
+/// extra digits carried through decompositions that cannot be computed exactly
+const GUARD_DIGITS: u64 = 10;
+
+/// sweeps of Jacobi rotations after which `eig` and `svd` give up
+const MAX_SWEEPS: usize = 100;
+
+/// the linear algebra natives, with the number of arguments each expects
+pub const FUNCTIONS: &[(&str, Arity, NativeFn)] = &[
+    ("det", Arity::Exact(1), det),
+    ("inv", Arity::Exact(1), inv),
+    ("transpose", Arity::Exact(1), transpose),
+    ("trace", Arity::Exact(1), trace),
+    ("rank", Arity::Exact(1), rank),
+    ("rref", Arity::Exact(1), rref),
+    ("solve", Arity::Exact(2), solve),
+    ("lu", Arity::Exact(1), lu),
+    ("qr", Arity::Exact(1), qr),
+    ("chol", Arity::Exact(1), chol),
+    ("eig", Arity::Exact(1), eig),
+    ("svd", Arity::Exact(1), svd),
+];
+
+/// Reduced struct, a matrix after fraction-free Gauss-Jordan elimination
+struct Reduced {
+    // the rows, whose entries divided by `divisor` are the reduced row echelon form
+    rows: Vec<Vec<BigInt>>,
+    // the column of the pivot of each nonzero row
+    pivots: Vec<usize>,
+    // the last pivot, which every pivot equals once elimination is done
+    divisor: BigInt,
+    // whether rows were swapped an odd number of times
+    odd: bool,
+}
+
+fn type_error(message: String) -> RuntimeError {
+    RuntimeError::new(ErrorCode::Type, message)
+}
+
+fn dims<T>(m: &[Vec<T>]) -> (usize, usize) {
+    (m.len(), m.first().map_or(0, |row| row.len()))
+}
+
+/// the elements of a matrix of numbers, a number is a 1 x 1 matrix
+fn numbers(name: &str, value: &SymbolValue) -> Result<Vec<Vec<BigDecimal>>, RuntimeError> {
+    let error = || type_error(format!("'{}' expects a matrix of numbers, found '{}'", name, value));
+    match value {
+        SymbolValue::Num(n) => { Ok(vec![vec![n.clone()]]) }
+        SymbolValue::Matrix(m) => {
+            m.iter().map(|row| row.iter().map(|el| match el {
+                SymbolValue::Num(n) => { Ok(n.clone()) }
+                _ => { Err(error()) }
+            }).collect()).collect()
+        }
+        _ => { Err(error()) }
+    }
+}
+
+/// the size of a square matrix
+fn square(name: &str, m: &[Vec<BigDecimal>]) -> Result<usize, RuntimeError> {
+    let (rows, cols) = dims(m);
+    if rows != cols {
+        return Err(type_error(format!("'{}' expects a square matrix, found dimensions '{:?}'", name, (rows, cols))));
+    }
+    Ok(rows)
+}
+
+/// the matrix scaled by the power of ten that makes every entry an integer, and that power
+fn integers(m: &[Vec<BigDecimal>]) -> Result<(Vec<Vec<BigInt>>, i64), RuntimeError> {
+    let scale = m.iter().flatten().map(|el| el.as_bigint_and_exponent().1).max().unwrap_or(0).max(0);
+    m.iter().map(|row| row.iter().map(|el| {
+        let (int, exponent) = el.as_bigint_and_exponent();
+        let shift = (scale - exponent) as u64;
+        if el.abs().digits().saturating_add(shift) > MAX_DIGITS {
+            return Err(RuntimeError::new(ErrorCode::Domain, String::from("the entries of the matrix differ too much in size")));
+        }
+        Ok(int * BigInt::from(10).pow(shift as u32))
+    }).collect()).collect::<Result<_, _>>().map(|ints| (ints, scale))
+}
+
+/// brings the first `cols` columns of an integer matrix to reduced row echelon form without
+/// fractions: each step scales the rows by the new pivot and divides exactly by the previous one
+fn reduce(interpreter: &mut Interpreter, mut rows: Vec<Vec<BigInt>>, cols: usize) -> Result<Reduced, Halt> {
+    let mut divisor = BigInt::one();
+    let mut pivots = Vec::new();
+    let mut odd = false;
+    for c in 0..cols {
+        let r = pivots.len();
+        let pivot = match (r..rows.len()).find(|&i| !rows[i][c].is_zero()) {
+            Some(pivot) => { pivot }
+            None => { continue; }
+        };
+        if pivot != r {
+            rows.swap(pivot, r);
+            odd = !odd;
+        }
+        interpreter.consume(rows.len().saturating_mul(rows[r].len()))?;
+        let pivot_row = rows[r].clone();
+        for (k, row) in rows.iter_mut().enumerate() {
+            if k == r {
+                continue;
+            }
+            let factor = row[c].clone();
+            for (el, p) in row.iter_mut().zip(pivot_row.iter()) {
+                *el = (&pivot_row[c] * &*el - &factor * p) / &divisor;
+            }
+        }
+        divisor = pivot_row[c].clone();
+        pivots.push(c);
+    }
+    Ok(Reduced { rows, pivots, divisor, odd })
+}
+
+/// the fraction `num / den` as a number, exact when it has at most `prec` significant digits
+fn fraction(num: &BigInt, den: &BigInt, prec: u64) -> SymbolValue {
+    if num.is_zero() {
+        return SymbolValue::Num(BigDecimal::zero());
+    }
+    SymbolValue::Num(numeric::div(&BigDecimal::new(num.clone(), 0), &BigDecimal::new(den.clone(), 0), prec))
+}
+
+/// solves `a x = b` for a square `a` by reducing `[a | b]`
+fn solve_system(interpreter: &mut Interpreter, name: &str, a: &[Vec<BigDecimal>], b: &[Vec<BigDecimal>]) -> Result<SymbolValue, Halt> {
+    let n = square(name, a)?;
+    if b.len() != n {
+        return Err(type_error(format!("'{}' expects a right-hand side with {} rows, found dimensions '{:?}'", name, n, dims(b))).into());
+    }
+    let augmented: Vec<Vec<BigDecimal>> = a.iter().zip(b.iter()).map(|(x, y)| x.iter().chain(y.iter()).cloned().collect()).collect();
+    let (ints, _) = integers(&augmented)?;
+    let reduced = reduce(interpreter, ints, n)?;
+    if reduced.pivots.len() < n {
+        return Err(RuntimeError::new(ErrorCode::Singular, format!("'{}' found a singular matrix", name)).into());
+    }
+    let prec = interpreter.precision;
+    let divisor = &reduced.divisor;
+    Ok(SymbolValue::Matrix(reduced.rows.iter().map(|row| row[n..].iter().map(|el| fraction(el, divisor, prec)).collect()).collect()))
+}
+
+/// `det(A)`, the determinant of a square matrix, always exact
+pub fn det(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let m = numbers("det", &args[0])?;
+    let n = square("det", &m)?;
+    let (ints, scale) = integers(&m)?;
+    let reduced = reduce(interpreter, ints, n)?;
+    if reduced.pivots.len() < n {
+        return Ok(SymbolValue::Num(BigDecimal::zero()));
+    }
+    let det = if reduced.odd { -reduced.divisor } else { reduced.divisor };
+    Ok(SymbolValue::Num(BigDecimal::new(det, scale * n as i64).normalized()))
+}
+
+/// `inv(A)`, the inverse of a nonsingular square matrix
+pub fn inv(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let m = numbers("inv", &args[0])?;
+    let n = square("inv", &m)?;
+    let identity: Vec<Vec<BigDecimal>> = (0..n).map(|i| (0..n).map(|j| if i == j { BigDecimal::one() } else { BigDecimal::zero() }).collect()).collect();
+    solve_system(interpreter, "inv", &m, &identity)
+}
+
+/// `solve(A, b)`, the solution `x` of `A x = b` for a nonsingular `A` and one or more columns `b`
+pub fn solve(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let a = numbers("solve", &args[0])?;
+    let b = numbers("solve", &args[1])?;
+    solve_system(interpreter, "solve", &a, &b)
+}
+
+/// `rank(A)`, the number of linearly independent rows
+pub fn rank(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let m = numbers("rank", &args[0])?;
+    let (ints, _) = integers(&m)?;
+    let reduced = reduce(interpreter, ints, dims(&m).1)?;
+    Ok(SymbolValue::Num(BigDecimal::from(reduced.pivots.len() as u64)))
+}
+
+/// `rref(A)`, the reduced row echelon form
+pub fn rref(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let m = numbers("rref", &args[0])?;
+    let (ints, _) = integers(&m)?;
+    let reduced = reduce(interpreter, ints, dims(&m).1)?;
+    let prec = interpreter.precision;
+    let divisor = &reduced.divisor;
+    Ok(SymbolValue::Matrix(reduced.rows.iter().map(|row| row.iter().map(|el| fraction(el, divisor, prec)).collect()).collect()))
+}
+
+/// `trace(A)`, the sum of the diagonal of a square matrix
+pub fn trace(_: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let m = numbers("trace", &args[0])?;
+    let n = square("trace", &m)?;
+    Ok(SymbolValue::Num((0..n).map(|i| &m[i][i]).sum()))
+}
+
+/// `transpose(A)` or `A'`, which swaps the rows and columns of a matrix of any elements
+pub fn transpose(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    match &args[0] {
+        SymbolValue::Matrix(m) => {
+            let (rows, cols) = dims(m);
+            interpreter.consume(rows.saturating_mul(cols))?;
+            Ok(SymbolValue::Matrix((0..cols).map(|j| m.iter().map(|row| row[j].clone()).collect()).collect()))
+        }
+        SymbolValue::Num(_) | SymbolValue::Quantity(_) => { Ok(args[0].clone()) }
+        value => { Err(type_error(format!("'transpose' expects a matrix, found '{}'", value)).into()) }
+    }
+}
+
+fn identity(n: usize) -> Vec<Vec<BigDecimal>> {
+    (0..n).map(|i| (0..n).map(|j| if i == j { BigDecimal::one() } else { BigDecimal::zero() }).collect()).collect()
+}
+
+fn swap_axes(m: &[Vec<BigDecimal>]) -> Vec<Vec<BigDecimal>> {
+    (0..dims(m).1).map(|j| m.iter().map(|row| row[j].clone()).collect()).collect()
+}
+
+fn domain_error(message: String) -> RuntimeError {
+    RuntimeError::new(ErrorCode::Domain, message)
+}
+
+/// `sign(x) / (|x| + sqrt(x^2 + 1))`, the tangent of the smaller angle of a Jacobi rotation
+fn rotation(x: &BigDecimal, wp: u64) -> (BigDecimal, BigDecimal) {
+    let root = numeric::sqrt(&numeric::round(&(x * x + BigDecimal::one()), wp), wp);
+    let t = numeric::div(&BigDecimal::one(), &numeric::round(&(x.abs() + root), wp), wp);
+    let t = if x.is_negative() { -t } else { t };
+    let c = numeric::div(&BigDecimal::one(), &numeric::sqrt(&numeric::round(&(&t * &t + BigDecimal::one()), wp), wp), wp);
+    let s = numeric::round(&(&t * &c), wp);
+    (c, s)
+}
+
+/// replaces columns `p` and `q` of a matrix by `c p - s q` and `s p + c q`
+fn rotate_columns(m: &mut [Vec<BigDecimal>], p: usize, q: usize, c: &BigDecimal, s: &BigDecimal, wp: u64) {
+    for row in m.iter_mut() {
+        let (x, y) = (row[p].clone(), row[q].clone());
+        row[p] = numeric::round(&(c * &x - s * &y), wp);
+        row[q] = numeric::round(&(s * &x + c * &y), wp);
+    }
+}
+
+/// replaces rows `p` and `q` of a matrix by `c p - s q` and `s p + c q`
+fn rotate_rows(m: &mut [Vec<BigDecimal>], p: usize, q: usize, c: &BigDecimal, s: &BigDecimal, wp: u64) {
+    let (x, y) = (m[p].clone(), m[q].clone());
+    m[p] = x.iter().zip(y.iter()).map(|(x, y)| numeric::round(&(c * x - s * y), wp)).collect();
+    m[q] = x.iter().zip(y.iter()).map(|(x, y)| numeric::round(&(s * x + c * y), wp)).collect();
+}
+
+fn norm_squared<'a>(values: impl Iterator<Item=&'a BigDecimal>, wp: u64) -> BigDecimal {
+    numeric::round(&values.map(|x| x * x).sum::<BigDecimal>(), wp)
+}
+
+/// a matrix rounded to `prec` digits, with entries too small to matter next to the largest one
+/// set to zero, which hides the rounding left over from iterations
+fn rounded(m: &[Vec<BigDecimal>], prec: u64) -> SymbolValue {
+    let largest = m.iter().flatten().map(|x| x.abs()).max().unwrap_or_else(BigDecimal::zero);
+    let threshold = largest * BigDecimal::new(BigInt::one(), prec as i64);
+    SymbolValue::Matrix(m.iter().map(|row| row.iter().map(|x| {
+        SymbolValue::Num(if x.abs() < threshold { BigDecimal::zero() } else { numeric::round(x, prec).normalized() })
+    }).collect()).collect())
+}
+
+/// `lu(A)`, the tuple `(L, U, P)` of a square matrix with `P A = L U`, where `L` is unit lower
+/// triangular, `U` upper triangular and `P` a permutation that puts the largest pivots first
+pub fn lu(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let mut u = numbers("lu", &args[0])?;
+    let n = square("lu", &u)?;
+    let (prec, wp) = (interpreter.precision, interpreter.precision + GUARD_DIGITS);
+    let mut l = vec![vec![BigDecimal::zero(); n]; n];
+    let mut order: Vec<usize> = (0..n).collect();
+    for k in 0..n {
+        interpreter.consume(n.saturating_mul(n))?;
+        let mut pivot = k;
+        for i in k + 1..n {
+            if u[i][k].abs() > u[pivot][k].abs() {
+                pivot = i;
+            }
+        }
+        if u[pivot][k].is_zero() {
+            // the column is already eliminated
+            continue;
+        }
+        u.swap(k, pivot);
+        l.swap(k, pivot);
+        order.swap(k, pivot);
+        let pivot_row = u[k].clone();
+        for i in k + 1..n {
+            let factor = numeric::div(&u[i][k], &pivot_row[k], wp);
+            for (x, p) in u[i].iter_mut().zip(pivot_row.iter()).skip(k) {
+                *x = numeric::round(&(&*x - &factor * p), wp);
+            }
+            u[i][k] = BigDecimal::zero();
+            l[i][k] = factor;
+        }
+    }
+    let mut p = vec![vec![BigDecimal::zero(); n]; n];
+    for (i, &row) in order.iter().enumerate() {
+        l[i][i] = BigDecimal::one();
+        p[i][row] = BigDecimal::one();
+    }
+    Ok(SymbolValue::Tuple(vec![rounded(&l, prec), rounded(&u, prec), rounded(&p, prec)]))
+}
+
+/// `qr(A)`, the tuple `(Q, R)` with `A = Q R`, where `Q` is orthogonal and `R` upper triangular
+/// with a non-negative diagonal, found by Householder reflections
+pub fn qr(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let mut r = numbers("qr", &args[0])?;
+    let (rows, cols) = dims(&r);
+    let (prec, wp) = (interpreter.precision, interpreter.precision + GUARD_DIGITS);
+    let mut q = identity(rows);
+    let two = BigDecimal::from(2);
+    for k in 0..cols.min(rows.saturating_sub(1)) {
+        interpreter.consume(rows.saturating_mul(rows + cols))?;
+        // reflect the column below the diagonal onto a multiple of the first axis
+        let norm = numeric::sqrt(&norm_squared(r[k..].iter().map(|row| &row[k]), wp), wp);
+        if norm.is_zero() {
+            continue;
+        }
+        let alpha = if r[k][k].is_negative() { norm } else { -norm };
+        let mut v: Vec<BigDecimal> = r[k..].iter().map(|row| row[k].clone()).collect();
+        v[0] = numeric::round(&(&v[0] - &alpha), wp);
+        let vv = norm_squared(v.iter(), wp);
+        for j in 0..cols {
+            let dot = numeric::round(&r[k..].iter().zip(v.iter()).map(|(row, x)| &row[j] * x).sum::<BigDecimal>(), wp);
+            let factor = numeric::div(&(&dot * &two), &vv, wp);
+            for (row, x) in r[k..].iter_mut().zip(v.iter()) {
+                row[j] = numeric::round(&(&row[j] - &factor * x), wp);
+            }
+        }
+        for row in q.iter_mut() {
+            let dot = numeric::round(&row[k..].iter().zip(v.iter()).map(|(y, x)| y * x).sum::<BigDecimal>(), wp);
+            let factor = numeric::div(&(&dot * &two), &vv, wp);
+            for (y, x) in row[k..].iter_mut().zip(v.iter()) {
+                *y = numeric::round(&(&*y - &factor * x), wp);
+            }
+        }
+    }
+    for k in 0..cols.min(rows) {
+        if r[k][k].is_negative() {
+            r[k].iter_mut().for_each(|x| *x = -x.clone());
+            q.iter_mut().for_each(|row| row[k] = -row[k].clone());
+        }
+    }
+    for (i, row) in r.iter_mut().enumerate() {
+        row.iter_mut().take(i).for_each(|x| *x = BigDecimal::zero());
+    }
+    Ok(SymbolValue::Tuple(vec![rounded(&q, prec), rounded(&r, prec)]))
+}
+
+/// `chol(A)`, the lower triangular `L` with `A = L L'` of a symmetric positive definite matrix
+pub fn chol(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let a = numbers("chol", &args[0])?;
+    let n = square("chol", &a)?;
+    if a != swap_axes(&a) {
+        return Err(domain_error(String::from("'chol' expects a symmetric matrix")).into());
+    }
+    let (prec, wp) = (interpreter.precision, interpreter.precision + GUARD_DIGITS);
+    let mut l = vec![vec![BigDecimal::zero(); n]; n];
+    for j in 0..n {
+        interpreter.consume(n.saturating_mul(j + 1))?;
+        let diagonal = numeric::round(&(&a[j][j] - norm_squared(l[j][..j].iter(), wp)), wp);
+        if !diagonal.is_positive() {
+            return Err(domain_error(String::from("'chol' expects a positive definite matrix")).into());
+        }
+        l[j][j] = numeric::sqrt(&diagonal, wp);
+        for i in j + 1..n {
+            let dot: BigDecimal = l[i][..j].iter().zip(l[j][..j].iter()).map(|(x, y)| x * y).sum();
+            l[i][j] = numeric::div(&(&a[i][j] - dot), &l[j][j], wp);
+        }
+    }
+    Ok(rounded(&l, prec))
+}
+
+/// `eig(A)`, the tuple `(values, V)` of a symmetric matrix with `A V = V diag(values)`: the
+/// eigenvalues in ascending order as a column and their unit eigenvectors as the columns of `V`,
+/// found by cyclic Jacobi rotations
+pub fn eig(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let a = numbers("eig", &args[0])?;
+    jacobi_eig(interpreter, a, MAX_SWEEPS)
+}
+
+/// the decomposition of `eig`, giving up after `max_sweeps` sweeps
+fn jacobi_eig(interpreter: &mut Interpreter, mut a: Vec<Vec<BigDecimal>>, max_sweeps: usize) -> Result<SymbolValue, Halt> {
+    let n = square("eig", &a)?;
+    if a != swap_axes(&a) {
+        return Err(domain_error(String::from("'eig' expects a symmetric matrix")).into());
+    }
+    let (prec, wp) = (interpreter.precision, interpreter.precision + GUARD_DIGITS);
+    let mut v = identity(n);
+    // converged once the off-diagonal entries are negligible next to the whole matrix
+    let tolerance = norm_squared(a.iter().flatten(), wp) * BigDecimal::new(BigInt::one(), 2 * wp as i64);
+    let mut sweeps = 0;
+    loop {
+        let off = norm_squared((0..n).flat_map(|i| a[i][i + 1..].iter()), wp);
+        if off <= tolerance {
+            break;
+        }
+        if sweeps == max_sweeps {
+            return Err(domain_error(format!("'eig' did not converge after {} sweeps", max_sweeps)).into());
+        }
+        sweeps += 1;
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q].is_zero() {
+                    continue;
+                }
+                interpreter.consume(3 * n)?;
+                let theta = numeric::div(&(&a[q][q] - &a[p][p]), &(&a[p][q] * BigDecimal::from(2)), wp);
+                let (c, s) = rotation(&theta, wp);
+                rotate_columns(&mut a, p, q, &c, &s, wp);
+                rotate_rows(&mut a, p, q, &c, &s, wp);
+                rotate_columns(&mut v, p, q, &c, &s, wp);
+                a[p][q] = BigDecimal::zero();
+                a[q][p] = BigDecimal::zero();
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| a[i][i].cmp(&a[j][j]));
+    let values: Vec<Vec<BigDecimal>> = order.iter().map(|&i| vec![a[i][i].clone()]).collect();
+    let vectors = swap_axes(&order.iter().map(|&i| unit(v.iter().map(|row| row[i].clone()).collect(), wp)).collect::<Vec<_>>());
+    Ok(SymbolValue::Tuple(vec![rounded(&values, prec), rounded(&vectors, prec)]))
+}
+
+/// a vector of length one, signed so that its largest entry is positive
+fn unit(vector: Vec<BigDecimal>, wp: u64) -> Vec<BigDecimal> {
+    let norm = numeric::sqrt(&norm_squared(vector.iter(), wp), wp);
+    let largest = vector.iter().fold(BigDecimal::zero(), |max, x| if x.abs() > max.abs() { x.clone() } else { max });
+    if norm.is_zero() {
+        return vector;
+    }
+    let norm = if largest.is_negative() { -norm } else { norm };
+    vector.iter().map(|x| numeric::div(x, &norm, wp)).collect()
+}
+
+/// `svd(A)`, the tuple `(U, S, V)` with `A = U S V'`: `S` is the square diagonal matrix of the
+/// singular values in descending order and the columns of `U` and `V` are orthonormal, found
+/// by one-sided Jacobi rotations
+pub fn svd(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let a = numbers("svd", &args[0])?;
+    jacobi_svd(interpreter, a, MAX_SWEEPS)
+}
+
+/// the decomposition of `svd`, giving up after `max_sweeps` sweeps
+fn jacobi_svd(interpreter: &mut Interpreter, a: Vec<Vec<BigDecimal>>, max_sweeps: usize) -> Result<SymbolValue, Halt> {
+    let (rows, cols) = dims(&a);
+    // a wide matrix is decomposed through its transpose
+    let wide = rows < cols;
+    let mut u = if wide { swap_axes(&a) } else { a };
+    let n = rows.min(cols);
+    let (prec, wp) = (interpreter.precision, interpreter.precision + GUARD_DIGITS);
+    let mut v = identity(n);
+    let epsilon = BigDecimal::new(BigInt::one(), 2 * wp as i64);
+    let mut sweeps = 0;
+    loop {
+        let mut rotated = false;
+        for p in 0..n {
+            for q in p + 1..n {
+                interpreter.consume(3 * rows.max(cols))?;
+                let alpha = norm_squared(u.iter().map(|row| &row[p]), wp);
+                let beta = norm_squared(u.iter().map(|row| &row[q]), wp);
+                let gamma = numeric::round(&u.iter().map(|row| &row[p] * &row[q]).sum::<BigDecimal>(), wp);
+                // columns that are already orthogonal to the working precision are left alone
+                if gamma.is_zero() || &gamma * &gamma <= &alpha * &beta * &epsilon {
+                    continue;
+                }
+                rotated = true;
+                let zeta = numeric::div(&(beta - alpha), &(&gamma * BigDecimal::from(2)), wp);
+                let (c, s) = rotation(&zeta, wp);
+                rotate_columns(&mut u, p, q, &c, &s, wp);
+                rotate_columns(&mut v, p, q, &c, &s, wp);
+            }
+        }
+        if !rotated {
+            break;
+        }
+        sweeps += 1;
+        if sweeps == max_sweeps {
+            return Err(domain_error(format!("'svd' did not converge after {} sweeps", max_sweeps)).into());
+        }
+    }
+
+    // the lengths of the orthogonal columns are the singular values
+    let columns = swap_axes(&u);
+    let sigma: Vec<BigDecimal> = columns.iter().map(|col| numeric::sqrt(&norm_squared(col.iter(), wp), wp)).collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| sigma[j].cmp(&sigma[i]));
+    let left = swap_axes(&order.iter().map(|&j| {
+        columns[j].iter().map(|x| if sigma[j].is_zero() { BigDecimal::zero() } else { numeric::div(x, &sigma[j], wp) }).collect()
+    }).collect::<Vec<_>>());
+    let right = swap_axes(&order.iter().map(|&j| v.iter().map(|row| row[j].clone()).collect()).collect::<Vec<_>>());
+    let mut s = vec![vec![BigDecimal::zero(); n]; n];
+    for (k, &j) in order.iter().enumerate() {
+        s[k][k] = sigma[j].clone();
+    }
+    let (left, right) = if wide { (right, left) } else { (left, right) };
+    Ok(SymbolValue::Tuple(vec![rounded(&left, prec), rounded(&s, prec), rounded(&right, prec)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(rows: &[&[i64]]) -> SymbolValue {
+        SymbolValue::Matrix(rows.iter().map(|row| row.iter().map(|&x| SymbolValue::Num(BigDecimal::from(x))).collect()).collect())
+    }
+
+    fn error(result: Result<SymbolValue, Halt>) -> RuntimeError {
+        match result {
+            Err(Halt::Error(err)) => { err }
+            Ok(value) => { panic!("expected a runtime error, found '{}'", value) }
+            Err(_) => { panic!("expected a runtime error, found a halt") }
+        }
+    }
+
+    fn value(result: Result<SymbolValue, Halt>) -> String {
+        match result {
+            Ok(value) => { value.to_string() }
+            Err(_) => { panic!("expected a value") }
+        }
+    }
+
+    #[test]
+    fn test_singular() {
+        let mut interpreter = Interpreter::new();
+        let singular = matrix(&[&[1, 2], &[2, 4]]);
+        let err = error(inv(&mut interpreter, std::slice::from_ref(&singular)));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Singular, "'inv' found a singular matrix"));
+        let err = error(solve(&mut interpreter, &[singular.clone(), matrix(&[&[1], &[2]])]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Singular, "'solve' found a singular matrix"));
+        assert_eq!(value(det(&mut interpreter, &[singular])), "0");
+    }
+
+    #[test]
+    fn test_non_square() {
+        let mut interpreter = Interpreter::new();
+        let wide = matrix(&[&[1, 2, 3], &[4, 5, 6]]);
+        for (name, func) in [("inv", inv as NativeFn), ("det", det), ("trace", trace), ("lu", lu), ("chol", chol), ("eig", eig)] {
+            let err = error(func(&mut interpreter, std::slice::from_ref(&wide)));
+            assert_eq!((err.code, err.message), (ErrorCode::Type, format!("'{}' expects a square matrix, found dimensions '(2, 3)'", name)));
+        }
+        let err = error(solve(&mut interpreter, &[wide, matrix(&[&[1], &[2]])]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "'solve' expects a square matrix, found dimensions '(2, 3)'"));
+        let err = error(solve(&mut interpreter, &[matrix(&[&[1, 0], &[0, 1]]), matrix(&[&[1], &[2], &[3]])]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "'solve' expects a right-hand side with 2 rows, found dimensions '(3, 1)'"));
+        assert_eq!(value(solve(&mut interpreter, &[matrix(&[&[2, 0], &[0, 4]]), matrix(&[&[1], &[2]])])), "[[0.5], [0.5]]");
+    }
+
+    #[test]
+    fn test_chol() {
+        let mut interpreter = Interpreter::new();
+        let err = error(chol(&mut interpreter, &[matrix(&[&[1, 2], &[2, 4]])]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'chol' expects a positive definite matrix"));
+        let err = error(chol(&mut interpreter, &[matrix(&[&[1, 2], &[3, 4]])]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'chol' expects a symmetric matrix"));
+        assert_eq!(value(chol(&mut interpreter, &[matrix(&[&[4, 2], &[2, 2]])])), "[[2, 0], [1, 1]]");
+    }
+
+    #[test]
+    fn test_sweeps() {
+        let mut interpreter = Interpreter::new();
+        interpreter.precision = 5;
+        let a = numbers("eig", &matrix(&[&[2, 1], &[1, 2]])).unwrap();
+        let err = error(jacobi_eig(&mut interpreter, a.clone(), 0));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'eig' did not converge after 0 sweeps"));
+        assert_eq!(value(jacobi_eig(&mut interpreter, a.clone(), MAX_SWEEPS)), "([[1], [3]], [[0.70711, 0.70711], [-0.70711, 0.70711]])");
+        let err = error(jacobi_svd(&mut interpreter, a, 1));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'svd' did not converge after 1 sweeps"));
+        // a diagonal matrix needs no rotations at all
+        let diagonal = numbers("svd", &matrix(&[&[3, 0], &[0, 2]])).unwrap();
+        assert_eq!(value(jacobi_svd(&mut interpreter, diagonal, 1)), "([[1, 0], [0, 1]], [[3, 0], [0, 2]], [[1, 0], [0, 1]])");
+    }
+}
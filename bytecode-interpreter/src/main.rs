@@ -1,25 +1,236 @@
 mod vm;
+mod units;
 mod lexer;
 mod compiler;
 mod interpreter;
+mod errors;
+mod numeric;
+mod natives;
+mod linalg;
+mod stdlib;
+mod session;
+mod bytecode;
+mod verifier;
+#[cfg(not(target_arch = "wasm32"))]
+mod repl;
 
-use std::io::{self, Read};
+use std::env;
+use std::fs;
+use std::process;
+use std::path::Path;
+use compiler::Compiler;
+use errors::LoadError;
+use interpreter::Interpreter;
+use natives::Registry;
+use vm::{VM, InterpretResult};
 
+// exit codes of the command line runner
+const EXIT_COMPILE_ERROR: i32 = 1;
+const EXIT_RUNTIME_ERROR: i32 = 2;
+const EXIT_LIMIT: i32 = 3;
+const EXIT_USAGE: i32 = 64;
+const EXIT_BAD_BYTECODE: i32 = 65;
+const EXIT_NO_INPUT: i32 = 66;
+
+const USAGE: &str = "\
+usage: mathscript [command] [file]
+
+commands:
+  run <file>              compile and run a script, printing each value next to its source line
+  dis <file>              compile a script and print its bytecode
+  check <file>            compile a script and report its errors
+  compile <file> [out]    compile a script into a .msc file, next to the script by default
+  repl                    start the interactive REPL, the default without a command
+
+run, dis and check also take compiled .msc files. These keep line and column numbers but no
+source text, so run lists their values by line number and dis shows line:column only.
+
+exit codes: 0 success, 1 compile error, 2 runtime error, 3 resource limit exceeded,
+64 bad usage, 65 invalid compiled file, 66 unreadable file";
 
 fn main() {
-    let mode = 1;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        #[cfg(not(target_arch = "wasm32"))]
+        [] | ["repl"] => { repl::repl(); }
+        ["help"] | ["--help"] | ["-h"] => { println!("{}", USAGE); }
+        [command @ ("run" | "dis" | "check"), path] => { process::exit(run_file(command, path)); }
+        ["compile", path] => { process::exit(compile_file(path, &Path::new(path).with_extension("msc"))); }
+        ["compile", path, out] => { process::exit(compile_file(path, Path::new(out))); }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    }
+}
 
-    if mode == 1 {
-        repl();
+/// compiles a script file, or loads a compiled one, returning its program and source or the
+/// exit code to fail with
+fn program(path: &str) -> Result<(VM, String), i32> {
+    let bytes = fs::read(path).map_err(|err| load_failed(path, LoadError::Io(err.to_string())))?;
+    if bytecode::is_bytecode(&bytes) {
+        let vm = bytecode::deserialize(&bytes, &Registry::standard()).map_err(|err| load_failed(path, err))?;
+        return Ok((vm, String::new()));
     }
+    let source = String::from_utf8(bytes).map_err(|_| {
+        eprintln!("{}: error: neither a compiled program nor a UTF-8 script", path);
+        EXIT_NO_INPUT
+    })?;
+    let mut c = Compiler::new(&source);
+    c.compile();
+    if !c.diagnostics.is_empty() {
+        for diagnostic in c.diagnostics.iter() {
+            eprintln!("{}: error: {}", path, diagnostic);
+        }
+        return Err(EXIT_COMPILE_ERROR);
+    }
+    Ok((c.vm, source))
 }
 
-fn repl() {
-    let mut stdin = io::stdin();
-    loop {
-        print!(">\t");
-        let mut buffer = String::new();
-        stdin.read_line(&mut buffer);
+/// reports why a compiled file could not be loaded, returning the exit code to fail with
+fn load_failed(path: &str, err: LoadError) -> i32 {
+    match err {
+        LoadError::Io(err) => {
+            eprintln!("could not read '{}': {}", path, err);
+            EXIT_NO_INPUT
+        }
+        err => {
+            eprintln!("{}: error: {}", path, err);
+            EXIT_BAD_BYTECODE
+        }
     }
+}
+
+/// compiles a script file into a compiled file, returning the exit code
+fn compile_file(path: &str, out: &Path) -> i32 {
+    let vm = match program(path) {
+        Ok((vm, _)) => { vm }
+        Err(code) => { return code; }
+    };
+    if let Err(err) = bytecode::save(&vm, out) {
+        eprintln!("could not write '{}': {}", out.display(), err);
+        return EXIT_NO_INPUT;
+    }
+    0
+}
+
+/// runs a command of the runner on a script or compiled file, returning the exit code
+fn run_file(command: &str, path: &str) -> i32 {
+    if command == "run" && Path::new(path).extension().is_some_and(|ext| ext == "msc") {
+        let mut interpreter = Interpreter::new();
+        return match interpreter.interpret_file(path) {
+            Ok(result) => { finish(path, "", &interpreter, result) }
+            Err(err) => { load_failed(path, err) }
+        };
+    }
+    let (mut vm, source) = match program(path) {
+        Ok(program) => { program }
+        Err(code) => { return code; }
+    };
+    match command {
+        "check" => { 0 }
+        "dis" => {
+            vm.disassemble(true);
+            0
+        }
+        _ => {
+            vm.reset();
+            let mut interpreter = Interpreter::new();
+            let result = interpreter.interpret(vm);
+            finish(path, &source, &interpreter, result)
+        }
+    }
+}
 
+/// prints what a run printed and how it ended, returning the exit code
+fn finish(path: &str, source: &str, interpreter: &Interpreter, result: InterpretResult) -> i32 {
+    for line in listing(source, &interpreter.output) {
+        println!("{}", line);
+    }
+    match result {
+        InterpretResult::Ok => { 0 }
+        InterpretResult::CompileError(_) => { EXIT_COMPILE_ERROR }
+        InterpretResult::RuntimeError(err) => {
+            eprintln!("{}: error: {}", path, err);
+            for frame in interpreter.trace.iter() {
+                eprintln!("  {}", frame);
+            }
+            EXIT_RUNTIME_ERROR
+        }
+        InterpretResult::ResourceLimitExceeded(limit) => {
+            eprintln!("{}: error: {}", path, limit);
+            EXIT_LIMIT
+        }
+        InterpretResult::Cancelled => {
+            eprintln!("{}: error: cancelled", path);
+            EXIT_LIMIT
+        }
+    }
+}
+
+/// each printed value after the number and text of the source line that printed it, or after the
+/// number alone for compiled files, which keep no source text
+fn listing(source: &str, output: &[(usize, String)]) -> Vec<String> {
+    let lines: Vec<&str> = source.lines().collect();
+    output.iter().map(|(line, value)| {
+        match lines.get(*line) {
+            Some(text) => { format!("{}: {}\t {}", line + 1, text.trim(), value) }
+            None => { format!("{}:\t {}", line + 1, value) }
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// writes a script to a temporary file and runs a command of the runner on it
+    fn run_script(command: &str, name: &str, source: &str) -> i32 {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, source).unwrap();
+        let code = run_file(command, path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        code
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(run_script("run", "mathscript_ok.ms", "x = 2\nx * 3"), 0);
+        assert_eq!(run_script("check", "mathscript_check.ms", "x = 1 / 0"), 0);
+        assert_eq!(run_script("dis", "mathscript_dis.ms", "x = 2"), 0);
+        assert_eq!(run_script("check", "mathscript_compile.ms", "x = "), EXIT_COMPILE_ERROR);
+        assert_eq!(run_script("run", "mathscript_runtime.ms", "x = 1 / 0"), EXIT_RUNTIME_ERROR);
+        assert_eq!(run_script("run", "mathscript_limit.ms", "f(n) = f(n + 1)\nf(1)"), EXIT_LIMIT);
+        assert_eq!(run_file("run", "/nonexistent/script.ms"), EXIT_NO_INPUT);
+        let path = env::temp_dir().join("mathscript_binary.ms");
+        fs::write(&path, [0xff, 0xfe, 0x00]).unwrap();
+        assert_eq!(run_file("run", path.to_str().unwrap()), EXIT_NO_INPUT);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(finish("cancelled.ms", "", &Interpreter::new(), InterpretResult::Cancelled), EXIT_LIMIT);
+    }
+
+    #[test]
+    fn test_compiled_files() {
+        let (source, compiled) = (env::temp_dir().join("mathscript_lib.ms"), env::temp_dir().join("mathscript_lib.msc"));
+        fs::write(&source, "f(n) = n * 2\nf(21)").unwrap();
+        assert_eq!(compile_file(source.to_str().unwrap(), &compiled), 0);
+        assert_eq!(run_file("run", compiled.to_str().unwrap()), 0);
+        assert_eq!(run_file("check", compiled.to_str().unwrap()), 0);
+        assert_eq!(run_file("dis", compiled.to_str().unwrap()), 0);
+        let mut bytes = fs::read(&compiled).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&compiled, bytes).unwrap();
+        assert_eq!(run_file("run", compiled.to_str().unwrap()), EXIT_BAD_BYTECODE);
+        fs::remove_file(&source).unwrap();
+        fs::remove_file(&compiled).unwrap();
+        assert_eq!(compile_file("/nonexistent/script.ms", &compiled), EXIT_NO_INPUT);
+    }
+
+    #[test]
+    fn test_listing() {
+        let output = vec![(0, String::from("2")), (2, String::from("6"))];
+        assert_eq!(listing("x = 2\n\n  x * 3\n", &output), ["1: x = 2\t 2", "3: x * 3\t 6"]);
+        assert_eq!(listing("", &output), ["1:\t 2", "3:\t 6"]);
+    }
 }
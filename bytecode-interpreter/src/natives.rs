@@ -0,0 +1,119 @@
+use std::fmt;
+use std::collections::HashMap;
+use bigdecimal::BigDecimal;
+use crate::vm::SymbolValue;
+use crate::interpreter::{Interpreter, Halt};
+use crate::{linalg, stdlib};
+
+/// signature of a native function, called with a number of arguments its arity accepts
+pub type NativeFn = fn(&mut Interpreter, &[SymbolValue]) -> Result<SymbolValue, Halt>;
+
+/// computes a constant to a number of significant digits
+pub type ConstantFn = fn(u64) -> BigDecimal;
+
+/// Arity enum, the number of arguments a native function accepts
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, args: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => { args == n }
+            Arity::AtLeast(n) => { args >= n }
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => { write!(f, "{}", n) }
+            Arity::AtLeast(n) => { write!(f, "at least {}", n) }
+        }
+    }
+}
+
+/// Native struct, a function implemented in Rust that scripts call like their own functions
+#[derive(Clone, Copy)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub func: NativeFn,
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Native) -> bool {
+        self.name == other.name && self.arity == other.arity
+    }
+}
+
+impl Eq for Native {}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+/// Constant struct, a number implemented in Rust that scripts read like their own globals,
+/// computed to the precision of the run
+#[derive(Clone, Copy)]
+pub struct Constant {
+    pub name: &'static str,
+    pub value: ConstantFn,
+}
+
+impl fmt::Debug for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Constant({})", self.name)
+    }
+}
+
+/// Registry struct, the native functions and constants a program can refer to by name; a name is
+/// declared as a global holding its native or constant wherever the program first uses it, until
+/// the program assigns it
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    natives: HashMap<&'static str, Native>,
+    constants: HashMap<&'static str, Constant>,
+}
+
+impl Registry {
+    /// a registry without any natives
+    pub fn new() -> Registry {
+        Registry { natives: HashMap::new(), constants: HashMap::new() }
+    }
+
+    /// the math and linear algebra functions every program can use
+    pub fn standard() -> Registry {
+        let mut registry = Registry::new();
+        for &(name, arity, func) in stdlib::FUNCTIONS.iter().chain(linalg::FUNCTIONS.iter()) {
+            registry.register(name, arity, func);
+        }
+        for &(name, value) in stdlib::CONSTANTS.iter() {
+            registry.register_constant(name, value);
+        }
+        registry
+    }
+
+    /// adds a native function, replacing any other of the same name
+    pub fn register(&mut self, name: &'static str, arity: Arity, func: NativeFn) {
+        self.natives.insert(name, Native { name, arity, func });
+    }
+
+    /// adds a constant, replacing any other of the same name
+    pub fn register_constant(&mut self, name: &'static str, value: ConstantFn) {
+        self.constants.insert(name, Constant { name, value });
+    }
+
+    pub fn get(&self, name: &str) -> Option<Native> {
+        self.natives.get(name).copied()
+    }
+
+    pub fn constant(&self, name: &str) -> Option<Constant> {
+        self.constants.get(name).copied()
+    }
+}
@@ -0,0 +1,432 @@
+use bigdecimal::{BigDecimal, One, Zero, Signed, ToPrimitive, FromPrimitive};
+use bigdecimal::num_bigint::BigInt;
+use crate::units::powi;
+
+/// significant digits kept by operations that cannot be computed exactly
+pub const DEFAULT_PRECISION: u64 = 50;
+
+/// extra digits carried through intermediate steps so the rounded result is correct
+const GUARD_DIGITS: u64 = 10;
+
+/// integer powers whose exact result would need more digits than this are approximated instead
+pub const MAX_EXACT_DIGITS: u64 = 10_000;
+
+/// exact operations whose operands would need more digits than this once aligned are rejected
+const MAX_ALIGNED_DIGITS: u64 = 1_000_000;
+
+/// exponents of e beyond this are rejected, e^MAX_EXPONENT already has over 400000 digits before
+/// its point and larger results take too long to compute, compare or print
+const MAX_EXPONENT: i64 = 1_000_000;
+
+/// power of ten of the leading digit, e.g. 2 for 123.4 and -2 for 0.01
+fn magnitude(x: &BigDecimal) -> i64 {
+    let (_, scale) = x.as_bigint_and_exponent();
+    x.abs().digits() as i64 - 1 - scale
+}
+
+/// rounds to `prec` significant digits, `with_prec` miscounts the digits of negative numbers
+pub fn round(x: &BigDecimal, prec: u64) -> BigDecimal {
+    if x.is_negative() {
+        -x.abs().with_prec(prec)
+    } else {
+        x.with_prec(prec)
+    }
+}
+
+/// the integer parts of two numbers brought to a common scale
+fn aligned(a: &BigDecimal, b: &BigDecimal) -> Result<(BigInt, BigInt), String> {
+    let (sa, sb) = (a.as_bigint_and_exponent().1, b.as_bigint_and_exponent().1);
+    let digits = (sa as i128 - sb as i128).unsigned_abs() + a.abs().digits().max(b.abs().digits()) as u128;
+    if digits > MAX_ALIGNED_DIGITS as u128 {
+        return Err(format!("'{}' and '{}' differ too much in size to divide exactly", a, b));
+    }
+    let scale = sa.max(sb);
+    Ok((a.with_scale(scale).as_bigint_and_exponent().0, b.with_scale(scale).as_bigint_and_exponent().0))
+}
+
+/// divides `a` by a nonzero `b`, rounding the quotient to `prec` significant digits and dropping
+/// trailing zeros
+pub fn div(a: &BigDecimal, b: &BigDecimal, prec: u64) -> BigDecimal {
+    let (ia, sa) = a.as_bigint_and_exponent();
+    let (ib, sb) = b.as_bigint_and_exponent();
+    let shift = (prec + GUARD_DIGITS + b.abs().digits()).saturating_sub(a.abs().digits());
+    let quotient = ia * BigInt::from(10).pow(shift as u32) / ib;
+    round(&BigDecimal::new(quotient, sa - sb + shift as i64), prec).normalized()
+}
+
+/// rounds towards negative infinity
+pub fn floor(x: &BigDecimal) -> BigDecimal {
+    let truncated = x.with_scale(0);
+    if x.is_negative() && &truncated != x {
+        truncated - BigDecimal::one()
+    } else {
+        truncated
+    }
+}
+
+/// floored division `floor(a / b)` of a nonzero `b`, exact for any size of operands
+pub fn int_div(a: &BigDecimal, b: &BigDecimal) -> Result<BigDecimal, String> {
+    let (ia, ib) = aligned(a, b)?;
+    let mut quotient = &ia / &ib;
+    if !(&ia % &ib).is_zero() && (ia.is_negative() != ib.is_negative()) {
+        quotient -= 1;
+    }
+    Ok(BigDecimal::new(quotient, 0))
+}
+
+/// remainder of floored division of a nonzero `b`, which takes the sign of `b`
+pub fn modulo(a: &BigDecimal, b: &BigDecimal) -> Result<BigDecimal, String> {
+    Ok(a - b * int_div(a, b)?)
+}
+
+/// sums a series term by term until the terms no longer change the first `prec` digits
+fn series(first: BigDecimal, prec: u64, mut next: impl FnMut(&BigDecimal, u64) -> BigDecimal) -> BigDecimal {
+    let mut sum = first.clone();
+    let mut term = first;
+    let mut k = 1;
+    loop {
+        term = round(&next(&term, k), prec);
+        if term.is_zero() || magnitude(&term) < magnitude(&sum) - prec as i64 {
+            return sum;
+        }
+        sum += &term;
+        k += 1;
+    }
+}
+
+/// atanh(z) for |z| < 1, used to build logarithms
+fn atanh(z: &BigDecimal, prec: u64) -> BigDecimal {
+    let square = round(&(z * z), prec);
+    // each term is z^(2k+1) / (2k+1), with the odd powers of z carried along in `power`
+    let mut power = z.clone();
+    series(z.clone(), prec, |_, k| {
+        power = round(&(&power * &square), prec);
+        div(&power, &BigDecimal::from(2 * k + 1), prec)
+    })
+}
+
+/// natural logarithm of 2
+fn ln2(prec: u64) -> BigDecimal {
+    atanh(&div(&BigDecimal::one(), &BigDecimal::from(3), prec), prec) * BigDecimal::from(2)
+}
+
+/// natural logarithm of 10, which is 3 ln 2 + ln 1.25
+fn ln10(prec: u64) -> BigDecimal {
+    ln2(prec) * BigDecimal::from(3) + atanh(&div(&BigDecimal::one(), &BigDecimal::from(9), prec), prec) * BigDecimal::from(2)
+}
+
+/// atan(z) for |z| < 1 from its alternating series, best for small z
+fn atan_series(z: &BigDecimal, prec: u64) -> BigDecimal {
+    let square = round(&(z * z), prec);
+    let mut power = z.clone();
+    series(z.clone(), prec, |_, k| {
+        power = round(&(-&power * &square), prec);
+        div(&power, &BigDecimal::from(2 * k + 1), prec)
+    })
+}
+
+/// pi to `prec` significant digits, from Machin's formula pi = 16 atan(1/5) - 4 atan(1/239)
+pub fn pi(prec: u64) -> BigDecimal {
+    let wp = prec + GUARD_DIGITS;
+    let atan = |n: u32| atan_series(&div(&BigDecimal::one(), &BigDecimal::from(n), wp), wp);
+    round(&(atan(5) * BigDecimal::from(16) - atan(239) * BigDecimal::from(4)), prec).normalized()
+}
+
+/// e to `prec` significant digits, the sum of 1/k!
+pub fn e(prec: u64) -> BigDecimal {
+    let wp = prec + GUARD_DIGITS;
+    let sum = series(BigDecimal::one(), wp, |term, k| div(term, &BigDecimal::from(k), wp));
+    round(&sum, prec).normalized()
+}
+
+/// square root of a non-negative number to `prec` significant digits
+pub fn sqrt(x: &BigDecimal, prec: u64) -> BigDecimal {
+    if x.is_zero() {
+        return BigDecimal::zero();
+    }
+    let wp = prec + GUARD_DIGITS;
+    // x = m * 10^(2k) with m in [1, 100), whose root in floating point starts the iteration
+    let k = magnitude(x).div_euclid(2);
+    let (int, scale) = x.as_bigint_and_exponent();
+    let m = BigDecimal::new(int, scale + 2 * k);
+    let guess = m.to_f64().map_or(1.0, f64::sqrt);
+    let mut y = BigDecimal::new(BigInt::one(), -k) * BigDecimal::from_f64(guess).unwrap_or_else(BigDecimal::one);
+    // Newton's method doubles the correct digits of the 15 or so that floating point gives
+    let mut digits = 15;
+    let half = BigDecimal::new(BigInt::from(5), 1);
+    loop {
+        y = round(&((&y + div(x, &y, wp)) * &half), wp);
+        if digits > wp {
+            return round(&y, prec).normalized();
+        }
+        digits *= 2;
+    }
+}
+
+/// natural logarithm of a positive number to `prec` significant digits
+pub fn ln(x: &BigDecimal, prec: u64) -> BigDecimal {
+    let wp = prec + GUARD_DIGITS;
+    // x = m * 10^e with m in [1, 10), then m = r * 2^j with r in [1, 2)
+    let (int, _) = x.as_bigint_and_exponent();
+    let e = magnitude(x);
+    let mut r = BigDecimal::new(int, x.digits() as i64 - 1);
+    let mut j = 0;
+    let two = BigDecimal::from(2);
+    let half = BigDecimal::new(BigInt::from(5), 1);
+    while r >= two {
+        r = &r * &half;
+        j += 1;
+    }
+
+    let z = div(&(&r - BigDecimal::one()), &(&r + BigDecimal::one()), wp);
+    let result = ln10(wp) * BigDecimal::from(e) + ln2(wp) * BigDecimal::from(j) + atanh(&z, wp) * two;
+    round(&result, prec).normalized()
+}
+
+/// base 10 logarithm of a positive number to `prec` significant digits, exact for powers of ten
+pub fn log10(x: &BigDecimal, prec: u64) -> BigDecimal {
+    let normalized = x.normalized();
+    if normalized.digits() == 1 && normalized.as_bigint_and_exponent().0.is_one() {
+        return BigDecimal::from(magnitude(x));
+    }
+    let wp = prec + GUARD_DIGITS;
+    div(&ln(x, wp), &ln10(wp), prec)
+}
+
+/// e raised to a number, to `prec` significant digits
+pub fn exp(x: &BigDecimal, prec: u64) -> Result<BigDecimal, String> {
+    if x.is_zero() {
+        return Ok(BigDecimal::one());
+    }
+    let whole = floor(x);
+    let n = whole.to_i64().filter(|n| n.abs() <= MAX_EXPONENT).ok_or_else(|| format!("exponent '{}' is too large", x))?;
+    if n < 0 {
+        return Ok(div(&BigDecimal::one(), &exp(&-x, prec + 2)?, prec));
+    }
+    // precision lost while squaring grows with the number of digits in n
+    let wp = prec + GUARD_DIGITS + n.to_string().len() as u64;
+    let taylor = |f: &BigDecimal| series(BigDecimal::one(), wp, |term, k| div(&(term * f), &BigDecimal::from(k), wp));
+
+    // e^x = e^n * e^f with f in [0, 1)
+    let mut result = taylor(&(x - &whole));
+    let mut square = taylor(&BigDecimal::one());
+    let mut n = n;
+    while n > 0 {
+        if n & 1 == 1 {
+            result = round(&(&result * &square), wp);
+        }
+        n >>= 1;
+        if n > 0 {
+            square = round(&(&square * &square), wp);
+        }
+    }
+    Ok(round(&result, prec).normalized())
+}
+
+/// sums a series for a sine or cosine of `x` to `prec` significant digits, repeating the reduction
+/// of `x` into [-pi, pi] with more digits while the result is small enough to have lost some
+fn periodic(x: &BigDecimal, prec: u64, taylor: impl Fn(&BigDecimal, u64) -> BigDecimal) -> Result<BigDecimal, String> {
+    let size = magnitude(x).max(0) as u64;
+    if size > MAX_EXACT_DIGITS {
+        return Err(format!("'{}' is too large to reduce by multiples of pi", x));
+    }
+    let wp = prec + GUARD_DIGITS;
+    let mut digits = wp;
+    loop {
+        let tau = pi(digits + size) * BigDecimal::from(2);
+        let half = BigDecimal::new(BigInt::from(5), 1);
+        let turns = floor(&(div(x, &tau, digits + size) + half));
+        let r = round(&(x - tau * turns), digits);
+        let result = round(&taylor(&r, digits), digits);
+        // digits lost to cancellation, the result is correct to about `digits` places after the point
+        let needed = wp + if result.is_zero() { digits } else { (-magnitude(&result)).max(0) as u64 };
+        if needed <= digits || needed > MAX_EXACT_DIGITS {
+            return Ok(round(&result, prec).normalized());
+        }
+        digits = needed;
+    }
+}
+
+/// sine of a number in radians to `prec` significant digits
+pub fn sin(x: &BigDecimal, prec: u64) -> Result<BigDecimal, String> {
+    if x.is_zero() {
+        return Ok(BigDecimal::zero());
+    }
+    periodic(x, prec, |r, wp| {
+        let square = round(&(r * r), wp);
+        series(r.clone(), wp, |term, k| div(&-(term * &square), &BigDecimal::from(2 * k * (2 * k + 1)), wp))
+    })
+}
+
+/// cosine of a number in radians to `prec` significant digits
+pub fn cos(x: &BigDecimal, prec: u64) -> Result<BigDecimal, String> {
+    periodic(x, prec, |r, wp| {
+        let square = round(&(r * r), wp);
+        series(BigDecimal::one(), wp, |term, k| div(&-(term * &square), &BigDecimal::from((2 * k - 1) * 2 * k), wp))
+    })
+}
+
+/// tangent of a number in radians to `prec` significant digits
+pub fn tan(x: &BigDecimal, prec: u64) -> Result<BigDecimal, String> {
+    let wp = prec + GUARD_DIGITS;
+    let cos = cos(x, wp)?;
+    if cos.is_zero() {
+        return Err(format!("the tangent of '{}' is undefined", x));
+    }
+    Ok(div(&sin(x, wp)?, &cos, prec))
+}
+
+/// arctangent in radians to `prec` significant digits
+pub fn atan(x: &BigDecimal, prec: u64) -> BigDecimal {
+    if x.is_zero() {
+        return BigDecimal::zero();
+    }
+    let wp = prec + GUARD_DIGITS;
+    // atan(x) = +-pi/2 - atan(1/x) brings x into [-1, 1]
+    if x.abs() > BigDecimal::one() {
+        let quarter = round(&(pi(wp) * BigDecimal::new(BigInt::from(5), 1)), wp);
+        let quarter = if x.is_negative() { -quarter } else { quarter };
+        return round(&(quarter - atan(&div(&BigDecimal::one(), x, wp), wp)), prec).normalized();
+    }
+    // atan(x) = 2 atan(x / (1 + sqrt(1 + x^2))) halves the argument until the series is quick
+    let mut z = x.clone();
+    let mut doublings = 0;
+    let limit = BigDecimal::new(BigInt::one(), 1);
+    while z.abs() > limit {
+        let root = sqrt(&round(&(BigDecimal::one() + &z * &z), wp), wp);
+        z = div(&z, &(BigDecimal::one() + root), wp);
+        doublings += 1;
+    }
+    round(&(atan_series(&z, wp) * BigDecimal::from(1u64 << doublings)), prec).normalized()
+}
+
+/// angle in radians of the point (x, y) from the positive x axis, in (-pi, pi], to `prec`
+/// significant digits; zero at the origin
+pub fn atan2(y: &BigDecimal, x: &BigDecimal, prec: u64) -> BigDecimal {
+    let wp = prec + GUARD_DIGITS;
+    if x.is_zero() {
+        if y.is_zero() {
+            return BigDecimal::zero();
+        }
+        let quarter = round(&(pi(prec) * BigDecimal::new(BigInt::from(5), 1)), prec).normalized();
+        return if y.is_negative() { -quarter } else { quarter };
+    }
+    let angle = atan(&div(y, x, wp), wp);
+    if x.is_positive() {
+        return round(&angle, prec).normalized();
+    }
+    let pi = pi(wp);
+    let result = if y.is_negative() { angle - pi } else { angle + pi };
+    round(&result, prec).normalized()
+}
+
+/// raises a number to a power, exactly for integer exponents with results of a reasonable size
+/// and to `prec` significant digits otherwise; zero to a negative power and negative numbers to
+/// fractional powers are left to the caller
+pub fn pow(x: &BigDecimal, y: &BigDecimal, prec: u64) -> Result<BigDecimal, String> {
+    if x.is_zero() {
+        return Ok(if y.is_zero() { BigDecimal::one() } else { BigDecimal::zero() });
+    }
+    if y.is_integer() {
+        if let Some(n) = y.to_i64() {
+            if x.abs().digits().saturating_mul(n.unsigned_abs()) <= MAX_EXACT_DIGITS {
+                let exact = powi(x, n.abs())?;
+                return Ok(if n < 0 { div(&BigDecimal::one(), &exact, prec) } else { exact });
+            }
+        }
+    }
+    // x^y = e^(y ln |x|), negative for a negative base with an odd exponent
+    let wp = prec + GUARD_DIGITS;
+    let result = exp(&round(&(y * ln(&x.abs(), wp)), wp), prec).map_err(|_| format!("'{}^{}' is out of range", x, y))?;
+    let odd = y.is_integer() && !modulo(y, &BigDecimal::from(2))?.is_zero();
+    Ok(if x.is_negative() && odd { -result } else { result })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn num(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_floor_division() {
+        assert_eq!(int_div(&num("7"), &num("2")).unwrap(), num("3"));
+        assert_eq!(int_div(&num("-7"), &num("2")).unwrap(), num("-4"));
+        assert_eq!(int_div(&num("7"), &num("-2")).unwrap(), num("-4"));
+        assert_eq!(int_div(&num("7.5"), &num("0.5")).unwrap(), num("15"));
+        assert_eq!(modulo(&num("7"), &num("3")).unwrap(), num("1"));
+        assert_eq!(modulo(&num("-7"), &num("3")).unwrap(), num("2"));
+        assert_eq!(modulo(&num("7"), &num("-3")).unwrap(), num("-2"));
+        assert_eq!(modulo(&num("5.5"), &num("2")).unwrap(), num("1.5"));
+        assert_eq!(floor(&num("-0.5")), num("-1"));
+        assert_eq!(floor(&num("2.99")), num("2"));
+        assert!(modulo(&num("1e-2000000"), &num("3")).is_err());
+    }
+
+    #[test]
+    fn test_transcendental() {
+        assert_eq!(ln(&num("2"), 30), num("0.693147180559945309417232121458"));
+        assert_eq!(ln(&num("1000"), 30), num("6.90775527898213705205397436405"));
+        assert_eq!(ln(&num("0.001"), 30), num("-6.90775527898213705205397436405"));
+        assert_eq!(exp(&num("1"), 30).unwrap(), num("2.71828182845904523536028747135"));
+        assert_eq!(exp(&num("-2.5"), 30).unwrap(), num("0.0820849986238987951695286744672"));
+        assert_eq!(exp(&num("100"), 20).unwrap(), num("2.6881171418161354484e43"));
+    }
+
+    #[test]
+    fn test_trigonometric() {
+        assert_eq!(pi(30), num("3.14159265358979323846264338328"));
+        assert_eq!(e(30), num("2.71828182845904523536028747135"));
+        for prec in 1..=120 {
+            assert_eq!(e(prec), exp(&BigDecimal::one(), prec).unwrap(), "{} digits", prec);
+        }
+        assert_eq!(log10(&num("1000"), 30), num("3"));
+        assert_eq!(log10(&num("0.01"), 30), num("-2"));
+        assert_eq!(log10(&num("2"), 30), num("0.301029995663981195213738894724"));
+        assert_eq!(sin(&num("1"), 30).unwrap(), num("0.841470984807896506652502321630"));
+        assert_eq!(cos(&num("1"), 30).unwrap(), num("0.540302305868139717400936607443"));
+        assert_eq!(tan(&num("1"), 30).unwrap(), num("1.55740772465490223050697480746"));
+        assert_eq!(sin(&num("-100"), 30).unwrap(), num("0.50636564110975879365655761046"));
+        // close to a multiple of pi the reduction keeps enough digits for a small result
+        assert_eq!(sin(&pi(30), 20).unwrap(), num("-4.9711580283060062489e-31"));
+        assert_eq!(sin(&num("0"), 30).unwrap(), num("0"));
+        assert_eq!(atan(&num("1"), 30), num("0.785398163397448309615660845820"));
+        assert_eq!(atan(&num("-10"), 30), num("-1.47112767430373459185287557176"));
+        assert_eq!(atan2(&num("1"), &num("-1"), 30), num("2.35619449019234492884698253746"));
+        assert_eq!(atan2(&num("-1"), &num("-1"), 30), num("-2.35619449019234492884698253746"));
+        assert_eq!(atan2(&num("0"), &num("-1"), 30), num("3.14159265358979323846264338328"));
+        assert_eq!(atan2(&num("-2"), &num("0"), 30), num("-1.57079632679489661923132169164"));
+        assert!(sin(&num("1e20000"), 30).is_err());
+    }
+
+    #[test]
+    fn test_sqrt() {
+        assert_eq!(sqrt(&num("2"), 30), num("1.41421356237309504880168872421"));
+        assert_eq!(sqrt(&num("0.0004"), 30), num("0.02"));
+        assert_eq!(sqrt(&num("1e-101"), 20), num("3.1622776601683793320e-51"));
+        assert_eq!(sqrt(&num("1e300"), 20), num("1e150"));
+        assert_eq!(sqrt(&num("144"), 50), num("12"));
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(pow(&num("2"), &num("10"), 50).unwrap(), num("1024"));
+        assert_eq!(pow(&num("-3"), &num("3"), 50).unwrap(), num("-27"));
+        assert_eq!(pow(&num("2"), &num("-2"), 50).unwrap(), num("0.25"));
+        assert_eq!(pow(&num("1.5"), &num("2"), 50).unwrap(), num("2.25"));
+        assert_eq!(pow(&num("0"), &num("0"), 50).unwrap(), num("1"));
+        assert_eq!(pow(&num("2"), &num("0.5"), 30).unwrap(), num("1.41421356237309504880168872421"));
+        assert_eq!(pow(&num("27"), &num("0.5"), 20).unwrap(), num("5.1961524227066318806"));
+        assert_eq!(pow(&num("10"), &num("-1.5"), 20).unwrap(), num("0.031622776601683793320"));
+        // too large to compute exactly, so it is approximated
+        assert_eq!(pow(&num("3"), &num("100000"), 20).unwrap(), num("1.3349714142304014695e47712"));
+        assert!(pow(&num("10"), &num("1e20"), 20).is_err());
+        // results with millions of digits are rejected up front rather than computed in one step
+        assert!(pow(&num("2"), &num("30000000"), 50).is_err());
+        assert!(pow(&num("0.5"), &num("300000000"), 50).is_err());
+        assert!(exp(&num("1000001"), 50).is_err());
+    }
+}
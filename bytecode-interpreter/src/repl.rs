@@ -0,0 +1,143 @@
+use std::env;
+use std::path::PathBuf;
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+use crate::session::{Session, Entry, is_complete};
+use crate::vm::InterpretResult;
+
+/// file in the home directory that keeps the REPL history between runs
+const HISTORY_FILE: &str = ".mathscript_history";
+
+const HELP: &str = "\
+:dis [code]   disassemble the latest input, or compile code without running it
+:vars         list the globals and their values
+:reset        forget every input
+:load <file>  run a script file in the session
+:history      list the inputs of this session
+:help         show this message
+:quit         leave the REPL";
+
+/// reads and runs inputs until the input ends or `:quit`
+pub fn repl() {
+    let mut editor = Editor::<()>::new();
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(path) = &history {
+        // there is no history the first time the REPL runs
+        let _ = editor.load_history(path);
+    }
+    let mut session = Session::new();
+    let mut inputs = Vec::new();
+    while let Some(input) = read_input(&mut editor) {
+        if input.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(input.as_str());
+        match input.trim().strip_prefix(':') {
+            Some(command) => {
+                if !meta_command(&mut session, command.trim(), &inputs) {
+                    break;
+                }
+            }
+            None => {
+                let entry = session.run(&input);
+                report(&session, &entry);
+            }
+        }
+        inputs.push(input);
+    }
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("could not save the history: {}", err);
+        }
+    }
+}
+
+/// reads lines until they form a complete input, returns None once the input ends
+fn read_input(editor: &mut Editor<()>) -> Option<String> {
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { ". " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                input.push_str(&line);
+                if is_complete(&input) {
+                    return Some(input);
+                }
+                input.push('\n');
+            }
+            // ctrl-c drops the input being typed
+            Err(ReadlineError::Interrupted) => { return Some(String::new()); }
+            Err(ReadlineError::Eof) => { return None; }
+            Err(err) => {
+                eprintln!("{}", err);
+                return None;
+            }
+        }
+    }
+}
+
+/// runs a command starting with ':', returns false to leave the REPL
+fn meta_command(session: &mut Session, command: &str, inputs: &[String]) -> bool {
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => { (name, argument.trim()) }
+        None => { (command, "") }
+    };
+    match name {
+        "dis" if argument.is_empty() => {
+            for line in session.disassemble_last() {
+                print!("{}", line);
+            }
+        }
+        "dis" => {
+            match session.disassemble(argument) {
+                Ok(disassembly) => { disassembly.iter().for_each(|line| print!("{}", line)); }
+                Err(diagnostics) => { diagnostics.iter().for_each(|diagnostic| eprintln!("error: {}", diagnostic)); }
+            }
+        }
+        "vars" => {
+            for (name, value) in session.variables() {
+                println!("{} = {}", name, value);
+            }
+        }
+        "reset" => { session.reset(); }
+        "load" if argument.is_empty() => { eprintln!("usage: :load <file>"); }
+        "load" => {
+            match session.load(argument) {
+                Ok(entry) => { report(session, &entry); }
+                Err(err) => { eprintln!("could not read '{}': {}", argument, err); }
+            }
+        }
+        "history" => {
+            for (i, input) in inputs.iter().enumerate() {
+                println!("{:>4}  {}", i + 1, input.replace('\n', "\n      "));
+            }
+        }
+        "help" => { println!("{}", HELP); }
+        "quit" | "q" => { return false; }
+        _ => { eprintln!("unknown command ':{}', try :help", name); }
+    }
+    true
+}
+
+/// prints what an input printed and the error it stopped at
+fn report(session: &Session, entry: &Entry) {
+    for (_, value) in entry.output.iter() {
+        println!("{}", value);
+    }
+    match &entry.result {
+        InterpretResult::Ok => {}
+        InterpretResult::CompileError(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("error: {}", diagnostic);
+            }
+        }
+        InterpretResult::RuntimeError(err) => {
+            eprintln!("error: {}", err);
+            for frame in session.interpreter.trace.iter() {
+                eprintln!("  {}", frame);
+            }
+        }
+        InterpretResult::ResourceLimitExceeded(limit) => { eprintln!("error: {}", limit); }
+        InterpretResult::Cancelled => { eprintln!("cancelled"); }
+    }
+}
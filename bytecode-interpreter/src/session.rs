@@ -0,0 +1,203 @@
+use std::fs;
+use std::io;
+use crate::compiler::Compiler;
+use crate::interpreter::Interpreter;
+use crate::lexer::{Lexer, TokenType};
+use crate::errors::Diagnostic;
+use crate::natives::Registry;
+use crate::vm::{VM, SymbolValue, InterpretResult};
+
+/// Entry struct, what one input of a session printed and how it ended
+#[derive(Debug)]
+pub struct Entry {
+    pub output: Vec<(usize, String)>,
+    pub result: InterpretResult,
+}
+
+/// Session struct, compiles and runs inputs one after another as parts of the same program, so
+/// each input sees the globals and functions of the ones before it
+pub struct Session {
+    vm: VM,
+    pub interpreter: Interpreter,
+    // first instruction of the latest input that compiled
+    start: usize,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::with_registry(Registry::standard())
+    }
+
+    /// a session whose inputs can call the natives of `registry` instead of the standard ones
+    pub fn with_registry(registry: Registry) -> Session {
+        let mut vm = VM::new();
+        vm.registry = registry;
+        Session { vm, interpreter: Interpreter::new(), start: 0 }
+    }
+
+    /// compiles an input after the inputs before it and runs it, an input that does not compile
+    /// leaves the session as it was
+    pub fn run(&mut self, text: &str) -> Entry {
+        let start = self.vm.instructions.len();
+        let diagnostics = self.compile(text);
+        if !diagnostics.is_empty() {
+            return Entry { output: Vec::new(), result: InterpretResult::CompileError(diagnostics) };
+        }
+        self.vm._ip = start;
+        self.start = start;
+        let result = self.interpreter.execute(&mut self.vm);
+        Entry { output: std::mem::take(&mut self.interpreter.output), result }
+    }
+
+    /// runs a script file as a single input
+    pub fn load(&mut self, path: &str) -> io::Result<Entry> {
+        Ok(self.run(&fs::read_to_string(path)?))
+    }
+
+    /// disassembly of the latest input that compiled
+    pub fn disassemble_last(&mut self) -> Vec<String> {
+        let ip = self.vm._ip;
+        self.vm._ip = self.start;
+        let disassembly = self.vm.disassemble(false);
+        self.vm._ip = ip;
+        disassembly
+    }
+
+    /// disassembly of an input compiled after the inputs before it, without running it
+    pub fn disassemble(&mut self, text: &str) -> Result<Vec<String>, Vec<Diagnostic>> {
+        let (start, ip) = (self.vm.instructions.len(), self.vm._ip);
+        let checkpoint = self.vm.checkpoint();
+        let diagnostics = self.compile(text);
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        self.vm._ip = start;
+        let disassembly = self.vm.disassemble(false);
+        self.vm.rollback(checkpoint);
+        self.vm._ip = ip;
+        Ok(disassembly)
+    }
+
+    /// compiles an input after the inputs before it, leaving the program as it was if the input
+    /// does not compile
+    fn compile(&mut self, text: &str) -> Vec<Diagnostic> {
+        let checkpoint = self.vm.checkpoint();
+        let mut compiler = Compiler::resume(text, std::mem::replace(&mut self.vm, VM::new()));
+        compiler.compile();
+        self.vm = compiler.vm;
+        if !compiler.diagnostics.is_empty() {
+            self.vm.rollback(checkpoint);
+        }
+        compiler.diagnostics
+    }
+
+    /// the globals holding a value with the value they hold, natives and constants are left out
+    /// until an input assigns them
+    pub fn variables(&self) -> Vec<(String, String)> {
+        let mut variables = Vec::new();
+        for (name, slot) in self.vm.global_symbols() {
+            let value = match self.interpreter.global(slot) {
+                Some(SymbolValue::Null) | None => { continue; }
+                Some(value) => { value }
+            };
+            let native = self.vm.natives.iter().any(|(s, native)| *s == slot && *value == SymbolValue::Native(*native));
+            if !native && !self.interpreter.holds_constant(slot) {
+                variables.push((name.to_string(), value.to_string()));
+            }
+        }
+        variables
+    }
+
+    /// forgets every input, keeping the natives the session can call
+    pub fn reset(&mut self) {
+        *self = Session::with_registry(self.vm.registry.clone());
+    }
+}
+
+/// whether an input is complete, an input with brackets or braces still open continues on the
+/// next line
+pub fn is_complete(text: &str) -> bool {
+    let mut lexer = Lexer::new(text);
+    let mut depth = 0;
+    let mut quoted = false;
+    loop {
+        match lexer.next_token() {
+            Ok(token) => {
+                match token.token {
+                    TokenType::DQuote => { quoted = !quoted; }
+                    TokenType::Eof => { return depth <= 0; }
+                    _ if quoted => {}
+                    TokenType::LParen | TokenType::LBracket | TokenType::LBrace => { depth += 1; }
+                    TokenType::RParen | TokenType::RBracket | TokenType::RBrace => { depth -= 1; }
+                    _ => {}
+                }
+            }
+            // the compiler reports malformed input
+            Err(_) => { return true; }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorCode;
+
+    fn values(entry: &Entry) -> Vec<&str> {
+        entry.output.iter().map(|(_, value)| value.as_str()).collect()
+    }
+
+    #[test]
+    fn test_session_state() {
+        let mut session = Session::new();
+        assert_eq!(values(&session.run("x = 2")), ["2"]);
+        session.run("f(a) = a * x");
+        assert_eq!(values(&session.run("f(3)\nx = 5\nf(3)")), ["6", "5", "15"]);
+        assert_eq!(values(&session.run("unit league = 3 mi\n2 league -> mi")), ["6 mi"]);
+        assert_eq!(values(&session.run("3 league -> mi")), ["9 mi"]);
+        // natives keep the slot they were declared in, constants are computed once
+        assert_eq!(values(&session.run("det([2, 0; 0, 3])")), ["6"]);
+        assert_eq!(values(&session.run("det([1, 2; 3, 4])\npi > 3")), ["-2", "true"]);
+
+        // an input that does not compile changes nothing, one that fails keeps what it assigned
+        assert!(matches!(session.run("y = \nz = 1").result, InterpretResult::CompileError(_)));
+        let entry = session.run("y = 4\ny / 0");
+        assert!(matches!(entry.result, InterpretResult::RuntimeError(ref err) if err.code == ErrorCode::DivideByZero));
+        assert_eq!(values(&session.run("y + x")), ["9"]);
+        let variables = session.variables();
+        assert_eq!(variables.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["x", "f", "y"]);
+        assert_eq!((variables[0].1.as_str(), variables[2].1.as_str()), ("5", "4"));
+
+        assert!(session.disassemble_last().iter().any(|line| line.contains("op '+'")));
+        assert!(session.disassemble("q = x").is_ok());
+        assert_eq!(values(&session.run("q")), ["NULL"]);
+        assert!(session.disassemble("q = ").is_err());
+        // the globals, constants and units of an input that does not compile are dropped with it
+        assert!(matches!(session.run("unit rod = 5 m\nw = e\nv = ").result, InterpretResult::CompileError(_)));
+        assert_eq!(values(&session.run("unit rod = 16.5 ft\n2 rod -> ft\nw")), ["33 ft", "NULL"]);
+
+        // constants follow a precision the host changes between inputs
+        assert_eq!(values(&session.run("e")), ["2.7182818284590452353602874713526624977572470937"]);
+        session.interpreter.precision = 10;
+        assert_eq!(values(&session.run("e\npi")), ["2.718281828", "3.141592654"]);
+
+        session.reset();
+        assert!(session.variables().is_empty());
+        assert_eq!(values(&session.run("x = 1\nx")), ["1", "1"]);
+    }
+
+    #[test]
+    fn test_multi_line_input() {
+        assert!(is_complete("x = 1"));
+        assert!(is_complete(""));
+        assert!(!is_complete("f(x) = {"));
+        assert!(!is_complete("f(x) = {\n    y = [1, 2"));
+        assert!(is_complete("f(x) = {\n    y = [1, 2]\n}"));
+        assert!(!is_complete("max(1,"));
+        assert!(is_complete("\"{\""));
+
+        let mut session = Session::new();
+        session.run("f(n) = {\n    m = n * 2\n    m + 1\n}");
+        assert_eq!(values(&session.run("f(4)")), ["9"]);
+    }
+}
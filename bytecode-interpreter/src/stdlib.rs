@@ -0,0 +1,346 @@
+use std::cmp::Ordering;
+use bigdecimal::{BigDecimal, Zero, Signed, ToPrimitive};
+use crate::vm::SymbolValue;
+use crate::interpreter::{Interpreter, Halt};
+use crate::errors::{RuntimeError, ErrorCode};
+use crate::natives::{Arity, NativeFn, ConstantFn};
+use crate::numeric;
+
+/// the math natives, with the number of arguments each expects
+pub const FUNCTIONS: &[(&str, Arity, NativeFn)] = &[
+    ("abs", Arity::Exact(1), abs),
+    ("sign", Arity::Exact(1), sign),
+    ("floor", Arity::Exact(1), floor),
+    ("ceil", Arity::Exact(1), ceil),
+    ("round", Arity::Exact(1), round),
+    ("sqrt", Arity::Exact(1), sqrt),
+    ("exp", Arity::Exact(1), exp),
+    ("ln", Arity::Exact(1), ln),
+    ("log10", Arity::Exact(1), log10),
+    ("sin", Arity::Exact(1), sin),
+    ("cos", Arity::Exact(1), cos),
+    ("tan", Arity::Exact(1), tan),
+    ("atan", Arity::Exact(1), atan),
+    ("atan2", Arity::Exact(2), atan2),
+    ("max", Arity::AtLeast(1), max),
+    ("min", Arity::AtLeast(1), min),
+    ("sum", Arity::Exact(1), sum),
+    ("len", Arity::Exact(1), len),
+    ("size", Arity::Exact(1), size),
+    ("linspace", Arity::Exact(3), linspace),
+    ("digits", Arity::Exact(1), digits),
+];
+
+/// the math constants, computed to the precision of the run
+pub const CONSTANTS: &[(&str, ConstantFn)] = &[
+    ("pi", numeric::pi),
+    ("e", numeric::e),
+];
+
+/// most significant digits a script may ask for
+const MAX_PRECISION: u64 = 10_000;
+
+fn type_error(message: String) -> RuntimeError {
+    RuntimeError::new(ErrorCode::Type, message)
+}
+
+/// applies a function of a number to a number or to every element of a matrix, its errors are
+/// domain errors
+fn map(interpreter: &mut Interpreter, name: &str, value: &SymbolValue, f: impl Fn(&BigDecimal, u64) -> Result<BigDecimal, String>) -> Result<SymbolValue, Halt> {
+    let prec = interpreter.precision;
+    let apply = |value: &SymbolValue| match value {
+        SymbolValue::Num(n) => { f(n, prec).map(SymbolValue::Num).map_err(|err| RuntimeError::new(ErrorCode::Domain, err)) }
+        _ => { Err(type_error(format!("'{}' expects a number or a matrix of numbers, found '{}'", name, value))) }
+    };
+    match value {
+        SymbolValue::Matrix(m) => {
+            let mut output = Vec::new();
+            for row in m.iter() {
+                interpreter.consume(row.len())?;
+                output.push(row.iter().map(apply).collect::<Result<Vec<_>, _>>()?);
+            }
+            Ok(SymbolValue::Matrix(output))
+        }
+        value => { Ok(apply(value)?) }
+    }
+}
+
+/// the elements of a matrix or tuple, or the value itself
+fn elements(value: &SymbolValue) -> Vec<&SymbolValue> {
+    match value {
+        SymbolValue::Matrix(m) => { m.iter().flatten().collect() }
+        SymbolValue::Tuple(t) => { t.iter().collect() }
+        value => { vec![value] }
+    }
+}
+
+pub fn abs(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "abs", &args[0], |x, _| Ok(x.abs()))
+}
+
+pub fn sign(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "sign", &args[0], |x, _| Ok(BigDecimal::from(x.signum().to_i64().unwrap_or(0))))
+}
+
+pub fn floor(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "floor", &args[0], |x, _| Ok(numeric::floor(x)))
+}
+
+pub fn ceil(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "ceil", &args[0], |x, _| Ok(-numeric::floor(&-x)))
+}
+
+/// rounds to the nearest integer, halves away from zero
+pub fn round(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let half = BigDecimal::new(5.into(), 1);
+    map(interpreter, "round", &args[0], |x, _| Ok(if x.is_negative() { -numeric::floor(&(&half - x)) } else { numeric::floor(&(x + &half)) }))
+}
+
+pub fn sqrt(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "sqrt", &args[0], |x, prec| {
+        if x.is_negative() {
+            return Err(format!("'sqrt' expects a non-negative number, found '{}'", x));
+        }
+        Ok(numeric::sqrt(x, prec))
+    })
+}
+
+pub fn exp(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "exp", &args[0], numeric::exp)
+}
+
+pub fn ln(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "ln", &args[0], |x, prec| {
+        if !x.is_positive() {
+            return Err(format!("'ln' expects a positive number, found '{}'", x));
+        }
+        Ok(numeric::ln(x, prec))
+    })
+}
+
+pub fn log10(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "log10", &args[0], |x, prec| {
+        if !x.is_positive() {
+            return Err(format!("'log10' expects a positive number, found '{}'", x));
+        }
+        Ok(numeric::log10(x, prec))
+    })
+}
+
+pub fn sin(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "sin", &args[0], numeric::sin)
+}
+
+pub fn cos(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "cos", &args[0], numeric::cos)
+}
+
+pub fn tan(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "tan", &args[0], numeric::tan)
+}
+
+pub fn atan(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    map(interpreter, "atan", &args[0], |x, prec| Ok(numeric::atan(x, prec)))
+}
+
+/// `atan2(y, x)`, the angle of the point (x, y)
+pub fn atan2(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    match args {
+        [SymbolValue::Num(y), SymbolValue::Num(x)] => { Ok(SymbolValue::Num(numeric::atan2(y, x, interpreter.precision))) }
+        _ => { Err(type_error(format!("'atan2' expects two numbers, found '{}' and '{}'", args[0], args[1])).into()) }
+    }
+}
+
+/// sets the significant digits of the results that cannot be computed exactly, including the
+/// constants not reassigned since, returning the previous number of digits
+pub fn digits(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let prec = match &args[0] {
+        SymbolValue::Num(n) if n.is_integer() => { n.to_u64().filter(|prec| (1..=MAX_PRECISION).contains(prec)) }
+        _ => { None }
+    };
+    let prec = prec.ok_or_else(|| RuntimeError::new(ErrorCode::Domain, format!("'digits' expects a whole number from 1 to {}, found '{}'", MAX_PRECISION, args[0])))?;
+    let previous = interpreter.precision;
+    interpreter.precision = prec;
+    interpreter.refresh_constants();
+    Ok(SymbolValue::Num(BigDecimal::from(previous)))
+}
+
+/// the argument that compares as `wanted` to every other, from several numbers or quantities or
+/// the elements of a single matrix or tuple
+fn extreme(interpreter: &mut Interpreter, name: &str, args: &[SymbolValue], wanted: Ordering) -> Result<SymbolValue, Halt> {
+    let values = if args.len() == 1 { elements(&args[0]) } else { args.iter().collect() };
+    interpreter.consume(values.len())?;
+    let mut best = match values.first() {
+        Some(value) => { *value }
+        None => { return Err(type_error(format!("'{}' expects at least one value", name)).into()); }
+    };
+    for value in values.into_iter().skip(1) {
+        if Interpreter::compare(value, best)? == wanted {
+            best = value;
+        }
+    }
+    if let SymbolValue::Num(_) | SymbolValue::Quantity(_) = best {
+        Ok(best.clone())
+    } else {
+        Err(type_error(format!("'{}' expects numbers, found '{}'", name, best)).into())
+    }
+}
+
+pub fn max(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    extreme(interpreter, "max", args, Ordering::Greater)
+}
+
+pub fn min(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    extreme(interpreter, "min", args, Ordering::Less)
+}
+
+/// the sum of the numbers in a matrix or tuple
+pub fn sum(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let values = elements(&args[0]);
+    interpreter.consume(values.len())?;
+    let mut total = BigDecimal::zero();
+    for value in values {
+        match value {
+            SymbolValue::Num(n) => { total += n; }
+            value => { return Err(type_error(format!("'sum' expects numbers, found '{}'", value)).into()); }
+        }
+    }
+    Ok(SymbolValue::Num(total))
+}
+
+/// the number of elements of a matrix or tuple
+pub fn len(_: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    match &args[0] {
+        SymbolValue::Matrix(_) | SymbolValue::Tuple(_) => { Ok(SymbolValue::Num(BigDecimal::from(elements(&args[0]).len() as u64))) }
+        value => { Err(type_error(format!("'len' expects a matrix or a tuple, found '{}'", value)).into()) }
+    }
+}
+
+/// the rows and columns of a matrix, as a tuple
+pub fn size(_: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    match &args[0] {
+        SymbolValue::Matrix(m) => {
+            let (rows, cols) = (m.len(), m.first().map_or(0, |row| row.len()));
+            Ok(SymbolValue::Tuple(vec![SymbolValue::Num(BigDecimal::from(rows as u64)), SymbolValue::Num(BigDecimal::from(cols as u64))]))
+        }
+        value => { Err(type_error(format!("'size' expects a matrix, found '{}'", value)).into()) }
+    }
+}
+
+/// `linspace(a, b, n)`, the row vector of `n` evenly spaced numbers from `a` to `b`
+pub fn linspace(interpreter: &mut Interpreter, args: &[SymbolValue]) -> Result<SymbolValue, Halt> {
+    let (a, b, n) = match args {
+        [SymbolValue::Num(a), SymbolValue::Num(b), SymbolValue::Num(n)] => { (a, b, n) }
+        _ => { return Err(type_error(format!("'linspace' expects three numbers, found '{}', '{}' and '{}'", args[0], args[1], args[2])).into()); }
+    };
+    let count = if n.is_integer() { n.to_usize().filter(|&count| count > 0) } else { None };
+    let count = count.ok_or_else(|| RuntimeError::new(ErrorCode::Domain, format!("'linspace' expects a positive whole number of points, found '{}'", n)))?;
+    interpreter.consume(count)?;
+    if count == 1 {
+        return Ok(SymbolValue::Matrix(vec![vec![SymbolValue::Num(b.clone())]]));
+    }
+    let prec = interpreter.precision;
+    let intervals = BigDecimal::from(count as u64 - 1);
+    let row = (0..count).map(|i| {
+        // the last point is `b` itself rather than the sum of the steps
+        let point = if i + 1 == count { b.clone() } else { a + numeric::div(&((b - a) * BigDecimal::from(i as u64)), &intervals, prec) };
+        SymbolValue::Num(numeric::round(&point, prec).normalized())
+    }).collect();
+    Ok(SymbolValue::Matrix(vec![row]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::compiler::Compiler;
+    use crate::natives::Registry;
+    use crate::vm::InterpretResult;
+
+    fn num(s: &str) -> SymbolValue {
+        SymbolValue::Num(BigDecimal::from_str(s).unwrap())
+    }
+
+    fn error(result: Result<SymbolValue, Halt>) -> RuntimeError {
+        match result {
+            Err(Halt::Error(err)) => { err }
+            Ok(value) => { panic!("expected a runtime error, found '{}'", value) }
+            Err(_) => { panic!("expected a runtime error, found a halt") }
+        }
+    }
+
+    fn value(result: Result<SymbolValue, Halt>) -> String {
+        match result {
+            Ok(value) => { value.to_string() }
+            Err(_) => { panic!("expected a value") }
+        }
+    }
+
+    /// the runtime error of a script that calls natives
+    fn runtime_error(source: &str) -> RuntimeError {
+        let mut c = Compiler::new(source);
+        c.compile();
+        assert!(c.diagnostics.is_empty(), "{:?}", c.diagnostics);
+        c.vm.reset();
+        match Interpreter::new().interpret(c.vm) {
+            InterpretResult::RuntimeError(err) => { err }
+            result => { panic!("expected a runtime error, found {:?}", result) }
+        }
+    }
+
+    #[test]
+    fn test_arity() {
+        let registry = Registry::standard();
+        for &(name, arity, _) in FUNCTIONS.iter() {
+            assert_eq!(registry.get(name).map(|native| native.arity), Some(arity));
+        }
+        assert!(Arity::AtLeast(1).accepts(3) && !Arity::AtLeast(1).accepts(0));
+        assert!(Arity::Exact(2).accepts(2) && !Arity::Exact(2).accepts(1));
+        for (source, message) in [
+            ("abs()", "'abs' expects 1 arguments, found 0"),
+            ("sqrt(4, 9)", "'sqrt' expects 1 arguments, found 2"),
+            ("atan2(1)", "'atan2' expects 2 arguments, found 1"),
+            ("linspace(0, 1)", "'linspace' expects 3 arguments, found 2"),
+            ("min()", "'min' expects at least 1 arguments, found 0"),
+        ] {
+            let err = runtime_error(source);
+            assert_eq!((err.code, err.message.as_str()), (ErrorCode::Arity, message));
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut interpreter = Interpreter::new();
+        let err = error(sqrt(&mut interpreter, &[num("-4")]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'sqrt' expects a non-negative number, found '-4'"));
+        let err = error(ln(&mut interpreter, &[num("0")]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'ln' expects a positive number, found '0'"));
+        let err = error(abs(&mut interpreter, &[SymbolValue::Tuple(vec![num("1")])]));
+        assert_eq!(err.code, ErrorCode::Type);
+        let err = error(max(&mut interpreter, &[SymbolValue::Matrix(Vec::new())]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "'max' expects at least one value"));
+        let err = error(linspace(&mut interpreter, &[num("0"), num("1"), num("0")]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'linspace' expects a positive whole number of points, found '0'"));
+        let err = error(digits(&mut interpreter, &[num("10001")]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'digits' expects a whole number from 1 to 10000, found '10001'"));
+        assert_eq!(value(max(&mut interpreter, &[num("2"), num("-3"), num("7")])), "7");
+        assert_eq!(value(round(&mut interpreter, &[num("-2.5")])), "-3");
+        assert_eq!(value(linspace(&mut interpreter, &[num("0"), num("1"), num("5")])), "[[0, 0.25, 0.5, 0.75, 1]]");
+    }
+
+    #[test]
+    fn test_trigonometry() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(value(digits(&mut interpreter, &[num("5")])), "50");
+        assert_eq!(value(log10(&mut interpreter, &[num("1000")])), "3");
+        assert_eq!(value(sin(&mut interpreter, &[num("0")])), "0");
+        assert_eq!(value(cos(&mut interpreter, &[num("0")])), "1");
+        assert_eq!(value(atan2(&mut interpreter, &[num("1"), num("1")])), "0.7854");
+        assert_eq!(value(atan(&mut interpreter, &[SymbolValue::Matrix(vec![vec![num("0"), num("1")]])])), "[[0, 0.7854]]");
+        assert_eq!(numeric::pi(interpreter.precision).to_string(), "3.1416");
+        let err = error(log10(&mut interpreter, &[num("-1")]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Domain, "'log10' expects a positive number, found '-1'"));
+        let err = error(atan2(&mut interpreter, &[num("1"), SymbolValue::Matrix(vec![vec![num("1")]])]));
+        assert_eq!((err.code, err.message.as_str()), (ErrorCode::Type, "'atan2' expects two numbers, found '1' and '[[1]]'"));
+    }
+}
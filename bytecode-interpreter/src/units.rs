@@ -0,0 +1,518 @@
+use bigdecimal::{BigDecimal, One, Zero};
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use crate::numeric;
+
+/// SI base units, one per base dimension
+pub const SI_BASE_UNITS: [&str; 7] = ["m", "kg", "s", "A", "K", "mol", "cd"];
+
+/// SI prefixes and their powers of ten, `u` stands in for micro
+pub const SI_PREFIXES: [(&str, i64); 20] = [
+    ("Y", 24), ("Z", 21), ("E", 18), ("P", 15), ("T", 12), ("G", 9), ("M", 6), ("k", 3), ("h", 2), ("da", 1),
+    ("d", -1), ("c", -2), ("m", -3), ("u", -6), ("n", -9), ("p", -12), ("f", -15), ("a", -18), ("z", -21), ("y", -24),
+];
+
+/// built-in units as (name, definition, accepts SI prefixes)
+const BUILTIN_UNITS: [(&str, &str, bool); 24] = [
+    // SI derived units
+    ("g", "0.001*kg", true),
+    ("N", "kg*m/s^2", true),
+    ("Pa", "N/m^2", true),
+    ("J", "N*m", true),
+    ("W", "J/s", true),
+    ("Hz", "1/s", true),
+    ("C", "A*s", true),
+    ("V", "W/A", true),
+    ("ohm", "V/A", true),
+    ("L", "0.001*m^3", true),
+    // time
+    ("min", "60*s", false),
+    ("h", "60*min", false),
+    ("day", "24*h", false),
+    // imperial and US customary
+    ("inch", "0.0254*m", false),
+    ("ft", "12*inch", false),
+    ("yd", "3*ft", false),
+    ("mi", "5280*ft", false),
+    ("mph", "mi/h", false),
+    ("lb", "0.45359237*kg", false),
+    ("oz", "lb/16", false),
+    ("lbf", "4.4482216152605*N", false),
+    ("psi", "lbf/inch^2", false),
+    ("gal", "3.785411784*L", false),
+    ("atm", "101325*Pa", false),
+];
+
+/// derived units a canonical dimension is simplified into when printed
+const CANONICAL_UNITS: [&str; 6] = ["N", "Pa", "J", "W", "C", "V"];
+
+/// significant digits shown when printing a quantity, hides the rounding error
+/// left behind by dividing conversion factors such as `km/h`
+pub const DISPLAY_DIGITS: u64 = 32;
+
+lazy_static! {
+    static ref CANONICAL: Vec<(Dimension, &'static str)> = {
+        let table = UnitTable::new();
+        CANONICAL_UNITS.iter().map(|name| (table.get(name).unwrap().dim, *name)).collect()
+    };
+}
+
+/// Dimension struct, maps each base unit symbol to its exponent
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Dimension {
+    exponents: BTreeMap<String, i64>,
+}
+
+/// Unit struct, a scale factor into base units and the resulting dimension
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unit {
+    pub factor: BigDecimal,
+    pub dim: Dimension,
+}
+
+/// Quantity struct, a value stored in base units with its dimension and
+/// optionally the unit it was explicitly converted into for display
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Quantity {
+    pub value: BigDecimal,
+    pub dim: Dimension,
+    pub display: Option<(String, BigDecimal)>,
+}
+
+/// UnitTable struct, stores every unit known to a program
+#[derive(Clone)]
+pub struct UnitTable {
+    units: HashMap<String, Unit>,
+    prefixable: HashSet<String>,
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut num = Vec::new();
+        let mut den = Vec::new();
+        for (base, exp) in self.exponents.iter() {
+            let (list, exp) = if *exp > 0 { (&mut num, *exp) } else { (&mut den, -*exp) };
+            if exp == 1 {
+                list.push(base.clone());
+            } else {
+                list.push(format!("{}^{}", base, exp));
+            }
+        }
+
+        let num = if num.is_empty() { String::from("1") } else { num.join("*") };
+        match den.len() {
+            0 => { write!(f, "{}", num) }
+            1 => { write!(f, "{}/{}", num, den[0]) }
+            _ => { write!(f, "{}/({})", num, den.join("*")) }
+        }
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((name, factor)) = &self.display {
+            return write!(f, "{} {}", display_value(&(&self.value / factor)), name);
+        }
+        match CANONICAL.iter().find(|(dim, _)| *dim == self.dim) {
+            Some((_, name)) => { write!(f, "{} {}", display_value(&self.value), name) }
+            None => { write!(f, "{} {}", display_value(&self.value), self.dim) }
+        }
+    }
+}
+
+/// Dimension implementation
+impl Dimension {
+    /// creates the dimension of a single base unit
+    pub fn base(symbol: &str) -> Dimension {
+        let mut exponents = BTreeMap::new();
+        exponents.insert(String::from(symbol), 1);
+        Dimension { exponents }
+    }
+
+    /// checks if every exponent cancelled out
+    pub fn is_dimensionless(&self) -> bool {
+        self.exponents.is_empty()
+    }
+
+    /// each base unit symbol with its exponent
+    pub fn exponents(&self) -> impl Iterator<Item = (&str, i64)> {
+        self.exponents.iter().map(|(base, exp)| (base.as_str(), *exp))
+    }
+
+    /// combines two dimensions, adding exponents of `other` scaled by `sign`
+    fn combine(&self, other: &Dimension, sign: i64) -> Dimension {
+        let mut exponents = self.exponents.clone();
+        for (base, exp) in other.exponents.iter() {
+            let entry = exponents.entry(base.clone()).or_insert(0);
+            *entry += sign * exp;
+            if *entry == 0 {
+                exponents.remove(base);
+            }
+        }
+        Dimension { exponents }
+    }
+
+    pub fn mul(&self, other: &Dimension) -> Dimension {
+        self.combine(other, 1)
+    }
+
+    pub fn div(&self, other: &Dimension) -> Dimension {
+        self.combine(other, -1)
+    }
+
+    pub fn powi(&self, n: i64) -> Result<Dimension, String> {
+        if n == 0 {
+            return Ok(Dimension::default());
+        }
+        let mut exponents = BTreeMap::new();
+        for (base, exp) in self.exponents.iter() {
+            let exp = exp.checked_mul(n).ok_or_else(|| format!("exponent of '{}' is too large", base))?;
+            exponents.insert(base.clone(), exp);
+        }
+        Ok(Dimension { exponents })
+    }
+}
+
+/// Unit implementation
+impl Unit {
+    /// creates a unit that is a multiple of a base unit
+    pub fn base(symbol: &str) -> Unit {
+        Unit { factor: BigDecimal::one(), dim: Dimension::base(symbol) }
+    }
+
+    pub fn mul(&self, other: &Unit) -> Unit {
+        Unit { factor: &self.factor * &other.factor, dim: self.dim.mul(&other.dim) }
+    }
+
+    pub fn div(&self, other: &Unit) -> Unit {
+        Unit { factor: &self.factor / &other.factor, dim: self.dim.div(&other.dim) }
+    }
+
+    pub fn powi(&self, n: i64) -> Result<Unit, String> {
+        Ok(Unit { factor: powi(&self.factor, n)?, dim: self.dim.powi(n)? })
+    }
+}
+
+/// Quantity implementation
+impl Quantity {
+    /// creates a quantity in base units without a display unit
+    fn of(value: BigDecimal, dim: Dimension) -> Quantity {
+        Quantity { value, dim, display: None }
+    }
+
+    /// tags a plain number with a unit, converting it into base units
+    pub fn new(value: &BigDecimal, unit: &Unit) -> Quantity {
+        Quantity::of(value * &unit.factor, unit.dim.clone())
+    }
+
+    /// creates a quantity without a dimension
+    pub fn dimensionless(value: BigDecimal) -> Quantity {
+        Quantity::of(value, Dimension::default())
+    }
+
+    /// fails unless both quantities share a dimension
+    fn check_dims(&self, other: &Quantity, op: &str) -> Result<(), String> {
+        if self.dim == other.dim {
+            Ok(())
+        } else {
+            Err(format!("could not {} '{}' and '{}': dimensions '{}' and '{}' do not match", op, self, other, self.dim, other.dim))
+        }
+    }
+
+    pub fn add(&self, other: &Quantity) -> Result<Quantity, String> {
+        self.check_dims(other, "add")?;
+        Ok(Quantity::of(&self.value + &other.value, self.dim.clone()))
+    }
+
+    pub fn sub(&self, other: &Quantity) -> Result<Quantity, String> {
+        self.check_dims(other, "subtract")?;
+        Ok(Quantity::of(&self.value - &other.value, self.dim.clone()))
+    }
+
+    pub fn mul(&self, other: &Quantity) -> Quantity {
+        Quantity::of(&self.value * &other.value, self.dim.mul(&other.dim))
+    }
+
+    pub fn div(&self, other: &Quantity) -> Quantity {
+        Quantity::of(&self.value / &other.value, self.dim.div(&other.dim))
+    }
+
+    /// remainder of the floored division of two quantities of the same dimension
+    pub fn modulo(&self, other: &Quantity) -> Result<Quantity, String> {
+        self.check_dims(other, "mod")?;
+        Ok(Quantity::of(numeric::modulo(&self.value, &other.value)?, self.dim.clone()))
+    }
+
+    /// floored quotient of two quantities of the same dimension, a plain number
+    pub fn int_div(&self, other: &Quantity) -> Result<Quantity, String> {
+        self.check_dims(other, "divide")?;
+        Ok(Quantity::dimensionless(numeric::int_div(&self.value, &other.value)?))
+    }
+
+    /// raises a quantity to a dimensionless integer power
+    pub fn pow(&self, other: &Quantity) -> Result<Quantity, String> {
+        if !other.dim.is_dimensionless() {
+            return Err(format!("could not pow '{}' and '{}': exponent must be dimensionless", self, other));
+        }
+        if !other.value.is_integer() {
+            return Err(format!("could not pow '{}' and '{}': exponent of a quantity must be an integer", self, other));
+        }
+        let n = integer(&other.value)?;
+        Ok(Quantity::of(powi(&self.value, n)?, self.dim.powi(n)?))
+    }
+
+    pub fn neg(&self) -> Quantity {
+        Quantity { value: -self.value.clone(), dim: self.dim.clone(), display: self.display.clone() }
+    }
+
+    pub fn compare(&self, other: &Quantity) -> Result<Ordering, String> {
+        self.check_dims(other, "compare")?;
+        Ok(self.value.cmp(&other.value))
+    }
+
+    /// expresses the quantity in the named unit when printed
+    pub fn convert(&self, name: &str, unit: &Unit) -> Result<Quantity, String> {
+        if self.dim != unit.dim {
+            return Err(format!("could not convert '{}' to '{}': dimensions '{}' and '{}' do not match", self, name, self.dim, unit.dim));
+        }
+        Ok(Quantity { value: self.value.clone(), dim: self.dim.clone(), display: Some((String::from(name), unit.factor.clone())) })
+    }
+}
+
+/// UnitTable implementation
+impl UnitTable {
+    /// constructs a unit table holding the SI base and derived units, SI prefixes
+    /// and common imperial units
+    pub fn new() -> UnitTable {
+        let mut table = UnitTable { units: HashMap::new(), prefixable: HashSet::new() };
+        for symbol in SI_BASE_UNITS.iter() {
+            table.units.insert(String::from(*symbol), Unit::base(symbol));
+            if *symbol != "kg" {
+                table.prefixable.insert(String::from(*symbol));
+            }
+        }
+        for (name, expr, prefixable) in BUILTIN_UNITS.iter() {
+            let unit = table.parse(expr).unwrap();
+            table.units.insert(String::from(*name), unit);
+            if *prefixable {
+                table.prefixable.insert(String::from(*name));
+            }
+        }
+        table
+    }
+
+    /// checks if a unit with the given name exists, including prefixed units
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_ok()
+    }
+
+    /// looks up a single unit by name, falling back to an SI prefix applied to a unit
+    pub fn get(&self, name: &str) -> Result<Unit, String> {
+        if let Some(unit) = self.units.get(name) {
+            return Ok(unit.clone());
+        }
+        for (prefix, exp) in SI_PREFIXES.iter() {
+            if let Some(base) = name.strip_prefix(prefix) {
+                if self.prefixable.contains(base) {
+                    let unit = &self.units[base];
+                    return Ok(Unit { factor: &unit.factor * powi(&BigDecimal::from(10), *exp)?, dim: unit.dim.clone() });
+                }
+            }
+        }
+        Err(format!("unknown unit '{}'", name))
+    }
+
+    /// registers a new unit, names may not shadow an existing unit
+    pub fn define_unit(&mut self, name: &str, unit: Unit) -> Result<(), String> {
+        if self.contains(name) {
+            return Err(format!("unit '{}' is already defined", name));
+        }
+        if unit.factor.is_zero() {
+            return Err(format!("unit '{}' has a zero scale factor", name));
+        }
+        self.units.insert(String::from(name), unit);
+        Ok(())
+    }
+
+    /// registers a new base dimension together with a base unit of the same name
+    pub fn define_dimension(&mut self, name: &str) -> Result<(), String> {
+        if self.contains(name) {
+            return Err(format!("unit '{}' is already defined", name));
+        }
+        self.units.insert(String::from(name), Unit::base(name));
+        self.prefixable.insert(String::from(name));
+        Ok(())
+    }
+
+    /// units a program defined on top of the built-in ones, with whether they accept SI prefixes
+    pub fn defined(&self) -> Vec<(&str, &Unit, bool)> {
+        let builtin = UnitTable::new();
+        let mut defined: Vec<(&str, &Unit, bool)> = self.units.iter()
+            .filter(|(name, _)| !builtin.units.contains_key(name.as_str()))
+            .map(|(name, unit)| (name.as_str(), unit, self.prefixable.contains(name)))
+            .collect();
+        defined.sort_by_key(|&(name, _, _)| name);
+        defined
+    }
+
+    /// registers a unit listed by `defined`, names may not shadow an existing unit
+    pub fn restore(&mut self, name: &str, unit: Unit, prefixable: bool) -> Result<(), String> {
+        if self.contains(name) {
+            return Err(format!("unit '{}' is already defined", name));
+        }
+        self.units.insert(String::from(name), unit);
+        if prefixable {
+            self.prefixable.insert(String::from(name));
+        }
+        Ok(())
+    }
+
+    /// parses a unit expression such as `kg*m/s^2` into a single unit
+    pub fn parse(&self, expr: &str) -> Result<Unit, String> {
+        let mut unit = Unit { factor: BigDecimal::one(), dim: Dimension::default() };
+        let mut divide = false;
+        let mut term = String::new();
+
+        for c in expr.chars().chain(std::iter::once('*')) {
+            if c == '*' || c == '/' {
+                let factor = self.parse_term(term.trim())?;
+                if factor.factor.is_zero() {
+                    return Err(format!("unit '{}' has a zero scale factor", term.trim()));
+                }
+                unit = if divide { unit.div(&factor) } else { unit.mul(&factor) };
+                divide = c == '/';
+                term.clear();
+            } else {
+                term.push(c);
+            }
+        }
+        Ok(unit)
+    }
+
+    /// parses a single unit term with an optional integer exponent, e.g. `s^-2`,
+    /// plain numbers are dimensionless factors
+    fn parse_term(&self, term: &str) -> Result<Unit, String> {
+        let mut parts = term.splitn(2, '^');
+        let name = parts.next().unwrap_or("").trim();
+        let unit = match BigDecimal::from_str(name) {
+            Ok(factor) => { Unit { factor, dim: Dimension::default() } }
+            Err(_) => { self.get(name)? }
+        };
+        match parts.next() {
+            Some(exp) => {
+                let exp = i64::from_str(exp.trim()).map_err(|_| format!("unit exponent '{}' must be an integer", exp.trim()))?;
+                unit.powi(exp)
+            }
+            None => { Ok(unit) }
+        }
+    }
+}
+
+/// rounds a value to the displayed number of significant digits and drops trailing zeros
+fn display_value(value: &BigDecimal) -> BigDecimal {
+    if value.digits() > DISPLAY_DIGITS {
+        value.with_prec(DISPLAY_DIGITS).normalized()
+    } else {
+        value.normalized()
+    }
+}
+
+/// converts an integral BigDecimal into an i64
+fn integer(value: &BigDecimal) -> Result<i64, String> {
+    use bigdecimal::ToPrimitive;
+    value.to_i64().ok_or_else(|| format!("exponent '{}' is too large", value))
+}
+
+/// raises a BigDecimal to an integer power by repeated squaring, failing rather than building a
+/// result with more than `numeric::MAX_EXACT_DIGITS` digits
+pub fn powi(base: &BigDecimal, n: i64) -> Result<BigDecimal, String> {
+    if base.abs().digits().saturating_mul(n.unsigned_abs()) > numeric::MAX_EXACT_DIGITS {
+        return Err(format!("'{}^{}' is too large to compute exactly", base, n));
+    }
+    if n < 0 {
+        if base.is_zero() {
+            return Err(String::from("division by zero"));
+        }
+        return Ok(BigDecimal::one() / powi(base, -n)?);
+    }
+    let mut result = BigDecimal::one();
+    let mut square = base.clone();
+    let mut n = n;
+    while n > 0 {
+        if n & 1 == 1 {
+            result = &result * &square;
+        }
+        n >>= 1;
+        if n > 0 {
+            square = &square * &square;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let table = UnitTable::new();
+        let unit = table.parse("kg*m/s^2").unwrap();
+        assert_eq!(unit.dim.to_string(), "kg*m/s^2");
+        assert_eq!(table.parse("m/s/s").unwrap().dim, unit.dim.div(&Dimension::base("kg")));
+        assert!(table.parse("m^0.5").is_err());
+        assert!(table.parse("parsec").is_err());
+    }
+
+    #[test]
+    fn test_quantity() {
+        let table = UnitTable::new();
+        let speed = Quantity::new(&BigDecimal::from(3), &table.get("m").unwrap())
+            .div(&Quantity::new(&BigDecimal::from(2), &table.get("s").unwrap()));
+        assert_eq!(speed.to_string(), "1.5 m/s");
+        assert!(speed.add(&Quantity::dimensionless(BigDecimal::one())).is_err());
+
+        let area = speed.pow(&Quantity::dimensionless(BigDecimal::from(2))).unwrap();
+        assert_eq!(area.to_string(), "2.25 m^2/s^2");
+        assert_eq!(area.dim.powi(0), Ok(Dimension::default()));
+
+        // huge powers fail rather than building billions of digits or overflowing the exponents
+        let length = Quantity::new(&BigDecimal::from(2), &table.get("m").unwrap());
+        assert!(length.pow(&Quantity::dimensionless(BigDecimal::from(3_000_000_000u64))).is_err());
+        assert!(Dimension::base("m").powi(i64::MAX).unwrap().powi(2).is_err());
+        assert!(powi(&BigDecimal::from(2), i64::MIN).is_err());
+        assert!(table.parse("km^300000000").is_err());
+    }
+
+    #[test]
+    fn test_registry() {
+        let table = UnitTable::new();
+        assert_eq!(table.get("km").unwrap().factor, BigDecimal::from(1000));
+        assert_eq!(table.get("mg").unwrap().factor, BigDecimal::from_str("0.000001").unwrap());
+        assert_eq!(table.get("kPa").unwrap().dim, table.parse("kg/m/s^2").unwrap().dim);
+        assert_eq!(table.get("min").unwrap().factor, BigDecimal::from(60));
+        assert!(table.get("kft").is_err());
+
+        let force = Quantity::new(&BigDecimal::from(2), &table.parse("kg*m/s^2").unwrap());
+        assert_eq!(force.to_string(), "2 N");
+
+        let psi = Quantity::new(&BigDecimal::one(), &table.get("psi").unwrap());
+        assert_eq!(psi.convert("kPa", &table.get("kPa").unwrap()).unwrap().to_string(), "6.8947572931683613367226734453469 kPa");
+        assert!(psi.convert("m", &table.get("m").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_define() {
+        let mut table = UnitTable::new();
+        table.define_unit("furlong", table.parse("201.168*m").unwrap()).unwrap();
+        table.define_dimension("token").unwrap();
+        assert_eq!(table.parse("ktoken/furlong").unwrap().dim.to_string(), "token/m");
+        assert!(table.define_unit("km", Unit::base("m")).is_err());
+        assert!(table.parse("m/0").is_err());
+        assert!(table.define_unit("nothing", Unit { factor: BigDecimal::zero(), dim: Dimension::base("m") }).is_err());
+        assert!(table.define_dimension("token").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use num_traits::FromPrimitive;
+use crate::vm::{self, VM, OpCode};
+use crate::errors::{RuntimeError, ErrorCode};
+
+/// Flow enum, where execution can go after an instruction
+enum Flow {
+    Next,
+    // always continues at the target
+    Jump(usize),
+    // continues at the next instruction or at the target
+    Either(usize),
+    // pushes the next element and continues, or continues at the target without one
+    Iterate(usize),
+    // returns or halts
+    End,
+}
+
+/// checks the instructions of a program before it runs from its instruction pointer: every opcode
+/// is known and has its operands, constants exist, jumps land on an instruction inside the program,
+/// no instruction takes more values than the stack holds and the paths that meet at an
+/// instruction agree on how many values the stack holds there
+pub fn verify(vm: &VM) -> Result<(), RuntimeError> {
+    let code = &vm.instructions;
+    let fail = |ip: usize, code: ErrorCode, message: String| {
+        let mut err = RuntimeError::new(code, message);
+        err.span = vm.span(ip).unwrap_or_default();
+        err
+    };
+    let invalid = |ip: usize, message: String| fail(ip, ErrorCode::InvalidInstruction, message);
+
+    // decode the whole program, each instruction is indexed by its first byte
+    let decoded = vm::decode(code).map_err(|ip| {
+        match FromPrimitive::from_u8(code[ip]) {
+            Some(OpCode::None) | None => { invalid(ip, format!("unknown instruction {}", code[ip])) }
+            Some(_) => { invalid(ip, String::from("instruction is missing an operand")) }
+        }
+    })?;
+    let mut ops: Vec<Option<OpCode>> = vec![None; code.len()];
+    let mut previous = vec![None; code.len()];
+    let mut last = None;
+    for (ip, op) in decoded {
+        let constant = match op {
+            OpCode::Constant | OpCode::Unit | OpCode::Convert => { Some(vm.operand(ip, 0)) }
+            OpCode::Symbol | OpCode::FnDecl => { Some(vm.operand(ip, 2)) }
+            _ => { None }
+        };
+        if let Some(idx) = constant {
+            vm.constant(idx).map_err(|err| invalid(ip, err.message))?;
+        }
+        ops[ip] = Some(op);
+        previous[ip] = last;
+        last = Some(ip);
+    }
+    let target = |ip: usize, addr: usize| {
+        match ops.get(addr) {
+            Some(Some(_)) => { Ok(addr) }
+            Some(None) => { Err(invalid(ip, format!("jump to {} is not the start of an instruction", addr))) }
+            None => { Err(invalid(ip, format!("jump to {} is outside of the program", addr))) }
+        }
+    };
+
+    // follow every path from the entry point, and from the body of every function declared on the
+    // way, with the number of values each instruction finds on the stack
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    if vm._ip >= code.len() {
+        return Err(invalid(vm._ip, String::from("expected a return")));
+    }
+    let mut pending = vec![(target(vm._ip, vm._ip)?, 0)];
+    while let Some((ip, depth)) = pending.pop() {
+        match depths[ip] {
+            Some(known) if known == depth => { continue; }
+            Some(known) => {
+                return Err(invalid(ip, format!("the stack holds {} values on one path to {} and {} on another", known, ip, depth)));
+            }
+            None => { depths[ip] = Some(depth); }
+        }
+        let op = ops[ip].unwrap();
+        let operand = |i: usize| vm.operand(ip, i);
+        let underflow = || fail(ip, ErrorCode::StackUnderflow, String::from("stack underflow"));
+
+        let (pops, pushes) = match op {
+            OpCode::Print | OpCode::UnaryPos | OpCode::BeginScope | OpCode::EndScope | OpCode::Jump
+            | OpCode::EndProgram | OpCode::None => { (0, 0) }
+            OpCode::Constant | OpCode::Symbol | OpCode::Null => { (0, 1) }
+            OpCode::Load | OpCode::UnaryNeg | OpCode::UnaryNot | OpCode::Factorial | OpCode::Transpose
+            | OpCode::Unit | OpCode::Convert | OpCode::Unpack | OpCode::JumpIfFalse | OpCode::JumpIfTrue => { (1, 1) }
+            OpCode::And | OpCode::Or | OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Pow
+            | OpCode::Mod | OpCode::IntDiv | OpCode::ElemMul | OpCode::ElemDiv | OpCode::ElemPow
+            | OpCode::LessThan | OpCode::MoreThan | OpCode::LessEqThan | OpCode::MoreEqThan | OpCode::Eq
+            | OpCode::NotEq | OpCode::Assign => { (2, 1) }
+            OpCode::Branch => { (1, 0) }
+            OpCode::Tuple => { (operand(0), 1) }
+            OpCode::Matrix => { (operand(0).checked_mul(operand(1)).ok_or_else(underflow)?, 1) }
+            OpCode::FnCall | OpCode::Slide => { (operand(0).saturating_add(1), 1) }
+            OpCode::Pop => { (operand(0), 0) }
+            OpCode::Index | OpCode::IndexAssign => {
+                let (count, mask) = (operand(0), operand(1));
+                if count == 0 || count > 2 {
+                    return Err(invalid(ip, format!("expected 1 or 2 indices, found {}", count)));
+                }
+                // indices given as `:` take no value off the stack
+                let indices = (0..count).filter(|i| (mask >> i) & 1 == 0).count();
+                (indices + if op == OpCode::IndexAssign { 2 } else { 1 }, 1)
+            }
+            OpCode::Range => {
+                match operand(0) {
+                    parts @ (2 | 3) => { (parts, 1) }
+                    parts => { return Err(invalid(ip, format!("expected a range of 2 or 3 parts, found {}", parts))); }
+                }
+            }
+            // the loop's iterable and index sit beneath the loop variable's pointer
+            OpCode::Iterate => {
+                if depth < 3 {
+                    return Err(underflow());
+                }
+                (0, 0)
+            }
+            // the returned value, the locals of the call and the return address, which have to be
+            // everything the call left on the stack
+            OpCode::Return => {
+                let frame = operand(0).saturating_add(2);
+                if depth > frame {
+                    return Err(invalid(ip, format!("return leaves {} values of the call on the stack", depth - frame)));
+                }
+                (frame, 0)
+            }
+            // the values the function captures sit beneath the pointer it is stored at
+            OpCode::FnDecl => {
+                if depth <= operand(1) {
+                    return Err(underflow());
+                }
+                (1, 1)
+            }
+        };
+        let depth = depth.checked_sub(pops).ok_or_else(underflow)? + pushes;
+
+        let flow = match op {
+            OpCode::Jump => { Flow::Jump(operand(0)) }
+            OpCode::Branch | OpCode::JumpIfFalse | OpCode::JumpIfTrue => { Flow::Either(operand(0)) }
+            OpCode::Iterate => { Flow::Iterate(operand(0)) }
+            OpCode::Return | OpCode::EndProgram => { Flow::End }
+            OpCode::FnDecl => {
+                // the declaration is followed by a jump over the function's body, which starts
+                // with the return address, the captured values and the arguments on the stack
+                let skip = ip + op.size();
+                if ops.get(skip) != Some(&Some(OpCode::Jump)) {
+                    return Err(invalid(ip, String::from("expected a jump over the function body")));
+                }
+                let global = match previous[ip] {
+                    Some(symbol) if ops[symbol] == Some(OpCode::Symbol) => { vm.operand(symbol, 1) == 1 }
+                    _ => { return Err(invalid(ip, String::from("expected the name of the function before its declaration"))); }
+                };
+                // a local function also captures itself, in place of the first captured value
+                let captured = if global { operand(1) } else { operand(1).max(1) };
+                let body = target(ip, skip + OpCode::Jump.size())?;
+                pending.push((body, captured.saturating_add(operand(0)).saturating_add(1)));
+                Flow::Next
+            }
+            _ => { Flow::Next }
+        };
+        let next = ip + op.size();
+        let fallthrough = match flow {
+            Flow::Next => { Some(depth) }
+            Flow::Jump(addr) => {
+                pending.push((target(ip, addr)?, depth));
+                None
+            }
+            Flow::Either(addr) => {
+                pending.push((target(ip, addr)?, depth));
+                Some(depth)
+            }
+            Flow::Iterate(addr) => {
+                pending.push((target(ip, addr)?, depth));
+                Some(depth + 1)
+            }
+            Flow::End => { None }
+        };
+        if let Some(depth) = fallthrough {
+            if next >= code.len() {
+                return Err(invalid(ip, String::from("expected a return")));
+            }
+            pending.push((next, depth));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigdecimal::BigDecimal;
+    use crate::compiler::Compiler;
+    use crate::lexer::{TokenValue, Span};
+    use crate::session::Session;
+    use crate::vm::InterpretResult;
+
+    /// verifies hand-written bytecode with a number and a name in its constant pool
+    fn check(code: Vec<u8>) -> Result<(), RuntimeError> {
+        let mut vm = VM::new();
+        // each instruction that decodes spans the column of its first byte
+        vm.starts = vm::decode(&code).map_or(Vec::new(), |ops| ops.iter().map(|(start, _)| *start).collect());
+        vm.spans = vm.starts.iter().map(|&i| Span { start: i, end: i + 1, line: 1, column: i + 1 }).collect();
+        vm.instructions = code;
+        vm.consts = vec![TokenValue::Number(BigDecimal::from(2)), TokenValue::String(String::from("x"))];
+        verify(&vm)
+    }
+
+    #[test]
+    fn test_compiled_programs() {
+        let programs = [
+            "x = 2\nx * 3",
+            "f(n) = { if n < 2 { n } else { f(n - 1) + f(n - 2) } }\nf(10)",
+            "g(x) = {\n  h(y) = x + y\n  h(2)\n}\ng(5)",
+            "s = 0\nfor i in 1:5 {\n  if i == 3 { continue }\n  if i == 5 { break }\n  s = s + i\n}\ns",
+            "w(n) = {\n  c = 0\n  while c < n {\n    c = c + 1\n    if c > 10 { break }\n  }\n  c\n}\nw(20)",
+            "a = [1, 2; 3, 4]\na[1, :] = [5, 6]\na[2, 1]\np, q = (1, 2)\n1 < 2 && 2 < 1 || p > q",
+            "f(x) = {\n  y = x * 2\n  g(z) = z + y\n  g\n}\nf(3)(4)",
+        ];
+        for program in programs.iter() {
+            let mut c = Compiler::new(program);
+            c.compile();
+            assert!(c.diagnostics.is_empty());
+            c.vm.reset();
+            assert_eq!(verify(&c.vm), Ok(()), "rejected {:?}", program);
+        }
+
+        // a session verifies each input from where it starts
+        let mut session = Session::new();
+        session.run("f(n) = n * 2");
+        assert_eq!(session.run("x = f(2)\nx + 1").result, InterpretResult::Ok);
+    }
+
+    #[test]
+    fn test_rejected_bytecode() {
+        let (constant, print, end) = ((OpCode::Constant, &[0][..]), (OpCode::Print, &[][..]), (OpCode::EndProgram, &[][..]));
+        let message = |code: Vec<u8>| check(code).unwrap_err().message;
+        assert_eq!(check(vm::assemble(&[constant, print, end])), Ok(()));
+
+        assert_eq!(message(vec![200, OpCode::EndProgram as u8]), "unknown instruction 200");
+        assert_eq!(message(vec![OpCode::None as u8]), format!("unknown instruction {}", OpCode::None as u8));
+        assert_eq!(message(vec![OpCode::EndProgram as u8, OpCode::Tuple as u8, 1]), "instruction is missing an operand");
+        assert_eq!(message(vm::assemble(&[(OpCode::Constant, &[2]), end])), "unknown constant 2");
+        assert_eq!(message(vm::assemble(&[(OpCode::Jump, &[9]), end])), "jump to 9 is outside of the program");
+        assert_eq!(message(vm::assemble(&[(OpCode::Jump, &[6]), constant, end])), "jump to 6 is not the start of an instruction");
+        assert_eq!(message(vm::assemble(&[constant])), "expected a return");
+        assert_eq!(message(vm::assemble(&[(OpCode::Index, &[3, 0]), end])), "expected 1 or 2 indices, found 3");
+
+        let err = check(vm::assemble(&[constant, (OpCode::Add, &[]), end])).unwrap_err();
+        assert_eq!((err.code, err.span.column), (ErrorCode::StackUnderflow, OpCode::Constant.size() + 1));
+        let err = check(vm::assemble(&[(OpCode::Matrix, &[1 << 20, 1 << 20]), end])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::StackUnderflow);
+
+        // a branch that pushes a value on only one of the paths that meet after it
+        let merge = 2 * OpCode::Constant.size() + OpCode::Branch.size();
+        let code = vm::assemble(&[constant, (OpCode::Branch, &[merge]), constant, print, end]);
+        assert_eq!(message(code), format!("the stack holds 1 values on one path to {} and 0 on another", merge));
+
+        // a function declared with a body that returns, and one whose body returns with a value of
+        // its own still on the stack
+        let declare = |extra: &[(OpCode, &[usize])]| {
+            let body = OpCode::Symbol.size() + OpCode::FnDecl.size() + OpCode::Jump.size();
+            let after = body + extra.iter().map(|(op, _)| op.size()).sum::<usize>() + OpCode::Constant.size() + OpCode::Return.size();
+            let skip = [after];
+            let mut code = vec![(OpCode::Symbol, &[0, 1, 1][..]), (OpCode::FnDecl, &[0, 0, 1]), (OpCode::Jump, &skip[..])];
+            code.extend_from_slice(extra);
+            code.extend_from_slice(&[constant, (OpCode::Return, &[0]), end]);
+            vm::assemble(&code)
+        };
+        assert_eq!(check(declare(&[])), Ok(()));
+        assert_eq!(message(declare(&[constant])), "return leaves 1 values of the call on the stack");
+        let mut unnamed = declare(&[]);
+        unnamed[0] = OpCode::Null as u8;
+        unnamed[1..OpCode::Symbol.size()].iter_mut().for_each(|byte| *byte = OpCode::UnaryPos as u8);
+        assert_eq!(message(unnamed), "expected the name of the function before its declaration");
+    }
+}
@@ -1,17 +1,19 @@
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use crate::lexer::TokenValue;
+use crate::lexer::{TokenValue, Span};
 
 use std::fmt;
 use std::collections::HashMap;
 use bigdecimal::BigDecimal;
 use std::fmt::Formatter;
-use crate::vm::InterpretResult::RuntimeError;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::ptr::NonNull;
 use std::rc::Rc;
 use std::cell::RefCell;
 use serde::Serialize;
+use crate::units::{Quantity, UnitTable};
+use crate::natives::{Native, Constant, Registry};
+use crate::errors::{Diagnostic, RuntimeError, ErrorCode, Limit};
 
 type Value = TokenValue;
 
@@ -24,6 +26,9 @@ impl fmt::Display for Value {
             TokenValue::String(val) => {
                 write!(f, "{}", val)
             }
+            TokenValue::Bool(val) => {
+                write!(f, "{}", val)
+            }
         };
     }
 }
@@ -41,26 +46,36 @@ impl fmt::Display for SymbolValue {
                 write!(f, "{}", s)
             }
             SymbolValue::Matrix(m) => {
-                write!(f, "{:?}", m)
+                let rows: Vec<String> = m.iter().map(|row| format!("[{}]", SymbolValue::join(row))).collect();
+                write!(f, "[{}]", rows.join(", "))
             }
             SymbolValue::Func(func) => {
                 let func = func.borrow();
                 let mut stack = Vec::new();
                 for item in func.closure_stack.iter() {
                     if let SymbolValue::Func(func) = item {
-                        stack.push(String::from("fn<>"));
+                        stack.push(format!("fn<{}>", func.borrow().name));
                     } else {
                         stack.push(item.to_string());
                     }
                 }
-                write!(f, "fn<> @{}\targs: {}\tclosure: {:?}", func.ptr, func.args, stack)
+                write!(f, "fn<{}> @{}\targs: {}\tclosure: {:?}", func.name, func.ptr, func.args, stack)
+            }
+            SymbolValue::Native(native) => {
+                write!(f, "fn<{}> native\targs: {}", native.name, native.arity)
             }
             SymbolValue::Tuple(tuple) => {
-                write!(f, "{:?}", tuple)
+                write!(f, "({})", SymbolValue::join(tuple))
+            }
+            SymbolValue::Quantity(q) => {
+                write!(f, "{}", q)
             }
             SymbolValue::Pointer { ptr, global } => {
                 write!(f, "{}", ptr)
             }
+            SymbolValue::Return { ptr, func, .. } => {
+                write!(f, "ret<{}> @{}", func.borrow().name, ptr)
+            }
             SymbolValue::Null => {
                 write!(f, "NULL")
             }
@@ -68,15 +83,23 @@ impl fmt::Display for SymbolValue {
     }
 }
 
+impl SymbolValue {
+    /// comma separated elements of a matrix row or tuple
+    fn join(values: &[SymbolValue]) -> String {
+        values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(", ")
+    }
+}
+
 impl fmt::Display for Symbol {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         return write!(f, "{}", self.name);
     }
 }
 
-#[derive(FromPrimitive, Eq, PartialEq, Clone)]
+#[derive(FromPrimitive, Eq, PartialEq, Clone, Copy, Debug)]
+#[repr(u8)]
 pub enum OpCode {
-    Print = INSTR_OFFSET,
+    Print = 0,
     Return,
     Constant,
     And,
@@ -109,20 +132,131 @@ pub enum OpCode {
     MoreEqThan,
     Eq,
     NotEq,
+    Unit,
+    Convert,
+    Pop,
+    Slide,
+    Null,
+    Iterate,
+    JumpIfFalse,
+    JumpIfTrue,
+    IntDiv,
+    ElemMul,
+    ElemDiv,
+    ElemPow,
+    Index,
+    IndexAssign,
+    Range,
+    Transpose,
+    Unpack,
     None,
 }
 
-const INSTR_OFFSET: isize = isize::max_value() - 0xffff;
+/// bytes of every operand, operands follow their opcode unsigned and little endian
+pub const OPERAND_SIZE: usize = 4;
 
-#[derive(Eq, PartialEq, Serialize)]
+impl OpCode {
+    /// number of operands that follow the opcode
+    pub fn operands(self) -> usize {
+        match self {
+            OpCode::Symbol | OpCode::FnDecl => { 3 }
+            OpCode::Matrix | OpCode::Index | OpCode::IndexAssign => { 2 }
+            OpCode::Constant | OpCode::Return | OpCode::Tuple | OpCode::FnCall | OpCode::Jump | OpCode::Branch
+            | OpCode::Unit | OpCode::Convert | OpCode::Pop | OpCode::Slide | OpCode::Iterate | OpCode::JumpIfFalse
+            | OpCode::JumpIfTrue | OpCode::Range | OpCode::Unpack => { 1 }
+            _ => { 0 }
+        }
+    }
+
+    /// number of bytes of the opcode and its operands
+    pub fn size(self) -> usize {
+        1 + self.operands() * OPERAND_SIZE
+    }
+}
+
+/// appends an operand to an instruction stream
+pub fn encode_operand(code: &mut Vec<u8>, value: usize) {
+    let value = match value {
+        // the -1 of a pointer without a slot
+        usize::MAX => { u32::MAX }
+        value => {
+            // constants, slots and offsets come from the source, which is far shorter than 2^32
+            // bytes, so a larger value is a compiler bug rather than something to fold into -1
+            assert!(value < u32::MAX as usize, "operand {} does not fit in an instruction", value);
+            value as u32
+        }
+    };
+    code.extend_from_slice(&value.to_le_bytes());
+}
+
+/// reads the operand at `at`, if the stream holds all of its bytes
+pub fn decode_operand(code: &[u8], at: usize) -> Option<usize> {
+    let bytes = code.get(at..at.checked_add(OPERAND_SIZE)?)?;
+    match u32::from_le_bytes(bytes.try_into().unwrap()) {
+        u32::MAX => { Some(usize::MAX) }
+        value => { Some(value as usize) }
+    }
+}
+
+/// splits an instruction stream into its instructions, returning where each one starts along with
+/// its opcode, or where the first one that isn't a known opcode followed by its operands starts
+pub fn decode(code: &[u8]) -> Result<Vec<(usize, OpCode)>, usize> {
+    let mut instructions = Vec::new();
+    let mut at = 0;
+    while at < code.len() {
+        match FromPrimitive::from_u8(code[at]) {
+            Some(op) if op != OpCode::None && at + OpCode::size(op) <= code.len() => {
+                instructions.push((at, op));
+                at += op.size();
+            }
+            _ => { return Err(at); }
+        }
+    }
+    Ok(instructions)
+}
+
+/// encodes instructions given as opcodes and operands
+#[cfg(test)]
+pub fn assemble(code: &[(OpCode, &[usize])]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (op, operands) in code.iter() {
+        bytes.push(*op as u8);
+        for operand in operands.iter() {
+            encode_operand(&mut bytes, *operand);
+        }
+    }
+    bytes
+}
+
+/// small deterministic generator for the fuzz tests
+#[cfg(test)]
+pub struct Lcg(pub u64);
+
+#[cfg(test)]
+impl Lcg {
+    /// the next number below `bound`
+    pub fn next(&mut self, bound: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % bound
+    }
+
+    pub fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.next(items.len())]
+    }
+}
+
+#[derive(Eq, PartialEq, Serialize, Debug)]
 pub enum InterpretResult {
     Ok,
-    CompileError(String),
-    RuntimeError(String),
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+    ResourceLimitExceeded(Limit),
+    Cancelled,
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Func {
+    pub name: String,
     pub closure_stack: Vec<SymbolValue>,
     pub args: usize,
     pub ptr: usize,
@@ -135,11 +269,20 @@ pub enum SymbolValue {
     Str(String),
     Matrix(Vec<Vec<SymbolValue>>),
     Func(Rc<RefCell<Func>>),
+    Native(Native),
     Tuple(Vec<SymbolValue>),
+    Quantity(Quantity),
     Pointer {
         global: bool,
         ptr: usize,
     },
+    // return address pushed by `FnCall`, linked to the return address of the calling function
+    Return {
+        ptr: usize,
+        func: Rc<RefCell<Func>>,
+        caller: Option<usize>,
+        depth: usize,
+    },
     Null,
 }
 
@@ -154,11 +297,28 @@ pub struct Offset {
     stack_ptr: usize,
 }
 
+#[derive(Clone)]
 pub struct VirtualMachine {
-    pub instructions: Vec<usize>,
+    // each instruction is an opcode byte followed by its operands
+    pub instructions: Vec<u8>,
+    // first byte of every instruction, the line and source span of an instruction are kept at the
+    // same index of `lines` and `spans`
+    pub starts: Vec<usize>,
     pub lines: Vec<usize>,
+    pub spans: Vec<Span>,
     pub consts: Vec<Value>,
     pub offsets: Vec<Offset>,
+    pub units: UnitTable,
+    // source span of the code currently being compiled, recorded for every emitted instruction
+    pub span: Span,
+    // number of global slots declared so far, each global keeps its slot for the whole program
+    pub globals: usize,
+    // native functions the program may refer to by name
+    pub registry: Registry,
+    // global slots of the native functions the program refers to, filled in when it runs
+    pub natives: Vec<(usize, Native)>,
+    // global slots of the constants the program refers to, computed when it runs
+    pub constants: Vec<(usize, Constant)>,
 
     // maps identifier to its corresponding location in the constants vec
     _symbol_id_table: HashMap<String, usize>,
@@ -168,30 +328,103 @@ pub struct VirtualMachine {
 
 pub type VM = VirtualMachine;
 
+/// Checkpoint struct, what compiling more code into a program changes, to undo code that did not
+/// compile without copying the instructions before it
+pub struct Checkpoint {
+    instructions: usize,
+    consts: usize,
+    offsets: Vec<Offset>,
+    units: UnitTable,
+    globals: usize,
+    natives: usize,
+    constants: usize,
+}
+
 /// VM utility implementation (private)
 impl VM {
     /// returns current instruction and increments instruction pointer
-    pub fn next_instruction(&mut self) -> usize {
+    pub fn next_instruction(&mut self) -> u8 {
         self._ip += 1;
         return self.instructions[self._ip - 1];
     }
 
+    /// returns the next operand of the current instruction, failing if the bytecode ends early
+    pub fn next_operand(&mut self) -> Result<usize, RuntimeError> {
+        match decode_operand(&self.instructions, self._ip) {
+            Some(operand) => {
+                self._ip += OPERAND_SIZE;
+                Ok(operand)
+            }
+            None => { Err(RuntimeError::new(ErrorCode::InvalidInstruction, String::from("instruction is missing an operand"))) }
+        }
+    }
+
+    /// returns operand `i` of the instruction starting at `start`
+    pub fn operand(&self, start: usize, i: usize) -> usize {
+        decode_operand(&self.instructions, start + 1 + i * OPERAND_SIZE).unwrap()
+    }
+
+    /// overwrites the operand at `at`
+    pub fn set_operand(&mut self, at: usize, value: usize) {
+        let mut bytes = Vec::new();
+        encode_operand(&mut bytes, value);
+        self.instructions[at..at + OPERAND_SIZE].copy_from_slice(&bytes);
+    }
+
+    /// index in `starts` of the instruction the byte at `ip` belongs to
+    fn instruction(&self, ip: usize) -> Option<usize> {
+        self.starts.partition_point(|&start| start <= ip).checked_sub(1)
+    }
+
+    /// line of the instruction at `ip`
+    pub fn line(&self, ip: usize) -> Option<usize> {
+        self.instruction(ip).and_then(|i| self.lines.get(i)).copied()
+    }
+
+    /// source span of the instruction at `ip`
+    pub fn span(&self, ip: usize) -> Option<Span> {
+        self.instruction(ip).and_then(|i| self.spans.get(i)).copied()
+    }
+
+    /// opcode of the instruction starting at `at`, none if no instruction starts there
+    pub fn opcode_at(&self, at: usize) -> Option<OpCode> {
+        self.starts.binary_search(&at).ok().and_then(|_| FromPrimitive::from_u8(self.instructions[at]))
+    }
+
+    /// opcode of the last instruction emitted
+    pub fn last_opcode(&self) -> Option<OpCode> {
+        self.starts.last().and_then(|&start| self.opcode_at(start))
+    }
+
+    /// removes the instructions from `len` on
+    pub fn truncate(&mut self, len: usize) {
+        let count = self.starts.partition_point(|&start| start < len);
+        self.instructions.truncate(len);
+        self.starts.truncate(count);
+        self.lines.truncate(count);
+        self.spans.truncate(count);
+    }
+
+    /// returns the constant at the given index, failing if it doesn't exist
+    pub fn constant(&self, idx: usize) -> Result<&Value, RuntimeError> {
+        self.consts.get(idx).ok_or_else(|| RuntimeError::new(ErrorCode::InvalidInstruction, format!("unknown constant {}", idx)))
+    }
+
     /// convert opcode into human readable code
     fn disassemble_instruction(&mut self, show_line: bool) -> String {
-        // set the line indicator
-        let mut line = String::from("|");
-        if self._ip == 0 || self.lines[self._ip] != self.lines[self._ip - 1] {
-            line = format!("{}", self.lines[self._ip]);
-        } else if self._ip != 0 {
-            line = format!("{:width$}", " ", width = self.lines[self._ip].to_string().len())
+        // set the line and column indicator
+        let index = self.instruction(self._ip).unwrap_or_default();
+        let span = self.spans.get(index).copied().unwrap_or_default();
+        let mut line = format!("{}:{}", span.line, span.column);
+        if index != 0 && Some(&span) == self.spans.get(index - 1) {
+            line = format!("{:width$}", " ", width = line.len())
         }
-        let instr = self.next_instruction();
-        let print_instr = instr as isize - INSTR_OFFSET;
+        let print_instr = self.next_instruction();
         let ip = if show_line { self._ip.to_string() + "\t" } else { String::from("") };
-        return match FromPrimitive::from_usize(instr) {
+        match FromPrimitive::from_u8(print_instr) {
             Some(OpCode::Print) => { String::from(format!("{}{:#07b} {} print\n", ip, print_instr, line)) }
             Some(OpCode::Constant) => {
-                let c_idx = self.next_instruction();
+                let c_idx = self.next_operand().unwrap_or_default();
                 let constant = &self.consts[c_idx];
                 String::from(format!("{}{:#07b}\t{} const, {:#0x} ('{}')\n", ip, print_instr, line, c_idx, constant))
             }
@@ -217,9 +450,9 @@ impl VM {
                 String::from(format!("{}{:#07b}\t{} op 'u!'\n", ip, print_instr, line))
             }
             Some(OpCode::Symbol) => {
-                let offset = self.next_instruction() as isize;
-                let global = self.next_instruction();
-                let name = self.next_instruction();
+                let offset = self.next_operand().unwrap_or_default() as isize;
+                let global = self.next_operand().unwrap_or_default();
+                let name = self.next_operand().unwrap_or_default();
                 let val = self.consts[name].clone();
                 String::from(format!("{}{:#07b}\t{} ptr {}, {:#0x} ('{}')\n", ip, print_instr, line, if global == 1 { 'g' } else { 'l' }, (offset as isize), val))
             }
@@ -233,29 +466,30 @@ impl VM {
                 String::from(format!("{}{:#07b}\t{} halt\n", ip, print_instr, line))
             }
             Some(OpCode::Tuple) => {
-                let size = self.next_instruction();
+                let size = self.next_operand().unwrap_or_default();
                 String::from(format!("{}{:#07b}\t{} tuple\t {}\n", ip, print_instr, line, size))
             }
             Some(OpCode::Matrix) => {
-                let rows = self.next_instruction();
-                let cols = self.next_instruction();
+                let rows = self.next_operand().unwrap_or_default();
+                let cols = self.next_operand().unwrap_or_default();
                 String::from(format!("{}{:#07b}\t{} matrix\t [{} x {}]\n", ip, print_instr, line, rows, cols))
             }
             Some(OpCode::FnDecl) => {
-                let num_args = self.next_instruction();
-                self.next_instruction();
-                String::from(format!("{}{:#07b}\t{} fn_decl, fn<args: {}>\n", ip, print_instr, line, num_args))
+                let num_args = self.next_operand().unwrap_or_default();
+                self.next_operand().unwrap_or_default();
+                let name = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} fn_decl, fn<{} args: {}>\n", ip, print_instr, line, self.consts[name], num_args)
             }
             Some(OpCode::FnCall) => {
-                let args = self.next_instruction();
+                let args = self.next_operand().unwrap_or_default();
                 String::from(format!("{}{:#07b}\t{} fn_call, fn<args: {}>\n", ip, print_instr, line, args))
             }
             Some(OpCode::Jump) => {
-                let addr = self.next_instruction();
+                let addr = self.next_operand().unwrap_or_default();
                 String::from(format!("{}{:#07b}\t{} jmp, {:#0x}\n", ip, print_instr, line, addr))
             }
             Some(OpCode::Return) => {
-                let offset = self.next_instruction();
+                let offset = self.next_operand().unwrap_or_default();
                 String::from(format!("{}{:#07b}\t{} ret, {:#0x}\n", ip, print_instr, line, (offset as isize)))
             }
             Some(OpCode::BeginScope) => {
@@ -269,6 +503,35 @@ impl VM {
             Some(OpCode::Pow) => {
                 String::from(format!("{}{:#07b}\t{} op '^'\n", ip, print_instr, line))
             }
+            Some(OpCode::Mod) => {
+                format!("{}{:#07b}\t{} op '%'\n", ip, print_instr, line)
+            }
+            Some(OpCode::IntDiv) => {
+                format!("{}{:#07b}\t{} op '//'\n", ip, print_instr, line)
+            }
+            Some(OpCode::ElemMul) => {
+                format!("{}{:#07b}\t{} op '.*'\n", ip, print_instr, line)
+            }
+            Some(OpCode::ElemDiv) => {
+                format!("{}{:#07b}\t{} op './'\n", ip, print_instr, line)
+            }
+            Some(OpCode::ElemPow) => {
+                format!("{}{:#07b}\t{} op '.^'\n", ip, print_instr, line)
+            }
+            Some(OpCode::Index) => {
+                let count = self.next_operand().unwrap_or_default();
+                let mask = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} index\t {}, mask {:#b}\n", ip, print_instr, line, count, mask)
+            }
+            Some(OpCode::IndexAssign) => {
+                let count = self.next_operand().unwrap_or_default();
+                let mask = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} index_store\t {}, mask {:#b}\n", ip, print_instr, line, count, mask)
+            }
+            Some(OpCode::Range) => {
+                let parts = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} range\t {}\n", ip, print_instr, line, parts)
+            }
             Some(OpCode::LessThan) => {
                 String::from(format!("{}{:#07b}\t{} op '<'\n", ip, print_instr, line))
             }
@@ -296,12 +559,52 @@ impl VM {
             Some(OpCode::Factorial) => {
                 String::from(format!("{}{:#07b}\t{} op 'f!'\n", ip, print_instr, line))
             }
+            Some(OpCode::Unpack) => {
+                let count = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} unpack\t {}\n", ip, print_instr, line, count)
+            }
+            Some(OpCode::Transpose) => {
+                format!("{}{:#07b}\t{} op \"'\"\n", ip, print_instr, line)
+            }
             Some(OpCode::Branch) => {
-                let ptr = self.next_instruction();
+                let ptr = self.next_operand().unwrap_or_default();
                 String::from(format!("{}{:#07b}\t{} branch, {}\n", ip, print_instr, line, ptr))
             }
+            Some(OpCode::Unit) => {
+                let u_idx = self.next_operand().unwrap_or_default();
+                let unit = &self.consts[u_idx];
+                format!("{}{:#07b}\t{} unit, {:#0x} ('{}')\n", ip, print_instr, line, u_idx, unit)
+            }
+            Some(OpCode::Convert) => {
+                let u_idx = self.next_operand().unwrap_or_default();
+                let unit = &self.consts[u_idx];
+                format!("{}{:#07b}\t{} convert, {:#0x} ('{}')\n", ip, print_instr, line, u_idx, unit)
+            }
+            Some(OpCode::Pop) => {
+                let count = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} pop\t {}\n", ip, print_instr, line, count)
+            }
+            Some(OpCode::Slide) => {
+                let count = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} slide\t {}\n", ip, print_instr, line, count)
+            }
+            Some(OpCode::Null) => {
+                format!("{}{:#07b}\t{} null\n", ip, print_instr, line)
+            }
+            Some(OpCode::Iterate) => {
+                let addr = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} iter, {:#0x}\n", ip, print_instr, line, addr)
+            }
+            Some(OpCode::JumpIfFalse) => {
+                let addr = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} jmp_false, {:#0x}\n", ip, print_instr, line, addr)
+            }
+            Some(OpCode::JumpIfTrue) => {
+                let addr = self.next_operand().unwrap_or_default();
+                format!("{}{:#07b}\t{} jmp_true, {:#0x}\n", ip, print_instr, line, addr)
+            }
             _ => { String::from(format!("{}unknown code {:#07b}\n", ip, print_instr)) }
-        };
+        }
     }
 }
 
@@ -310,9 +613,17 @@ impl VM {
     pub fn new() -> VM {
         VM {
             instructions: Vec::new(),
+            starts: Vec::new(),
             consts: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
             offsets: vec![Offset { symbols: HashMap::new(), stack_ptr: 0 }],
+            units: UnitTable::new(),
+            span: Span::default(),
+            globals: 0,
+            registry: Registry::standard(),
+            natives: Vec::new(),
+            constants: Vec::new(),
             _symbol_id_table: HashMap::new(),
             _ip: 0,
             _func_decl: false,
@@ -335,26 +646,78 @@ impl VM {
     }
 
 
+    /// marks the end of the program so far, see `rollback`
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            instructions: self.instructions.len(),
+            consts: self.consts.len(),
+            offsets: self.offsets.clone(),
+            units: self.units.clone(),
+            globals: self.globals,
+            natives: self.natives.len(),
+            constants: self.constants.len(),
+        }
+    }
+
+    /// drops the code, constants, globals and units added since `checkpoint`
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        let count = self.starts.partition_point(|&start| start < checkpoint.instructions);
+        self.instructions.truncate(checkpoint.instructions);
+        self.starts.truncate(count);
+        self.lines.truncate(count);
+        self.spans.truncate(count);
+        self.consts.truncate(checkpoint.consts);
+        self._symbol_id_table.retain(|_, idx| *idx < checkpoint.consts);
+        self.offsets = checkpoint.offsets;
+        self.units = checkpoint.units;
+        self.globals = checkpoint.globals;
+        self.natives.truncate(checkpoint.natives);
+        self.constants.truncate(checkpoint.constants);
+        self._func_decl = false;
+    }
+
     /// resets the instruction pointer back to 0
     pub fn reset(&mut self) {
         self._ip = 0;
     }
+
+    /// declares a global symbol in a slot the program already counts in `globals`
+    pub fn declare_global(&mut self, name: String, slot: usize) {
+        self.offsets[0].symbols.insert(name, slot);
+    }
+
+    /// names of the globals declared so far with their slots, in the order they were declared
+    pub fn global_symbols(&self) -> Vec<(&str, usize)> {
+        let mut symbols: Vec<(&str, usize)> = self.offsets[0].symbols.iter().map(|(name, slot)| (name.as_str(), *slot)).collect();
+        symbols.sort_by_key(|&(_, slot)| slot);
+        symbols
+    }
 }
 
 impl VM {
+    /// starts an instruction, recording its line and the current source span
+    fn push_op(&mut self, op: OpCode, line: usize) {
+        self.starts.push(self.instructions.len());
+        self.instructions.push(op as u8);
+        self.lines.push(line);
+        self.spans.push(self.span);
+    }
+
+    /// appends an operand to the instruction being emitted
+    fn push_operand(&mut self, value: usize) {
+        encode_operand(&mut self.instructions, value);
+    }
+
     /// write return instruction into virtual machine
     pub fn emit_return(&mut self, line: usize) {
-        self.instructions.push(OpCode::Print as usize);
-        self.lines.push(line);
+        self.push_op(OpCode::Print, line);
     }
 
     /// write load constant instruction into virtual machine
     pub fn emit_constant(&mut self, val: Value, line: usize) {
         self.consts.push(val);
-        self.instructions.push(OpCode::Constant as usize);
-        self.instructions.push(self.consts.len() - 1);
-        self.lines.push(line);
-        self.lines.push(line);
+        self.push_op(OpCode::Constant, line);
+        self.push_operand(self.consts.len() - 1);
         self.modify_offset(1);
     }
 
@@ -377,15 +740,13 @@ impl VM {
     }
 
     pub fn emit_instruction(&mut self, op: OpCode, line: usize) {
-        self.instructions.push(op.clone() as usize);
-        self.lines.push(line);
+        self.push_op(op, line);
 
         match op {
             OpCode::Print => {}
             OpCode::Return => {
                 let o = self.count_stack_ptrs();
-                self.instructions.push(o);
-                self.lines.push(line);
+                self.push_operand(o);
                 self.offsets.pop();
                 self.modify_offset(-1);
             }
@@ -408,6 +769,13 @@ impl VM {
             OpCode::Mod => {
                 self.modify_offset(-1);
             }
+            OpCode::IntDiv => {
+                self.modify_offset(-1);
+            }
+            OpCode::ElemMul | OpCode::ElemDiv | OpCode::ElemPow => {
+                self.modify_offset(-1);
+            }
+            OpCode::Index | OpCode::IndexAssign | OpCode::Range => {}
             OpCode::Assign => {
                 self.modify_offset(-1);
             }
@@ -457,15 +825,70 @@ impl VM {
             OpCode::NotEq => {
                 self.modify_offset(-1);
             }
-            OpCode::Factorial => {}
+            OpCode::Factorial | OpCode::Transpose | OpCode::Unpack => {}
+            OpCode::Unit => {}
+            OpCode::Convert => {}
             OpCode::Or => {
                 self.modify_offset(-1);
             }
+            OpCode::Pop => {}
+            OpCode::Slide => {}
+            OpCode::Null => {
+                self.modify_offset(1);
+            }
+            OpCode::Iterate => {}
+            OpCode::JumpIfFalse => {}
+            OpCode::JumpIfTrue => {}
+        }
+    }
+
+    /// write a jump or branch to `target`, returning the index of the target so it can be patched
+    pub fn emit_jump(&mut self, op: OpCode, target: usize, line: usize) -> usize {
+        self.emit_instruction(op, line);
+        self.push_operand(target);
+        self.instructions.len() - OPERAND_SIZE
+    }
+
+    /// points a previously emitted jump at the next instruction
+    pub fn patch_jump(&mut self, idx: usize) {
+        self.set_operand(idx, self.instructions.len());
+    }
+
+    /// write a `Pop` discarding values off the top of the stack, or a `Slide` discarding values
+    /// just beneath the top one
+    pub fn emit_pop(&mut self, op: OpCode, count: usize, line: usize) {
+        if count == 0 {
+            return;
+        }
+        self.push_op(op, line);
+        self.push_operand(count);
+        self.modify_offset(-(count as isize));
+    }
+
+    /// write an `Iterate` that pushes the next element of a `for` loop, or jumps to a patched
+    /// exit once the elements run out
+    pub fn emit_iterate(&mut self, line: usize) -> usize {
+        let exit = self.emit_jump(OpCode::Iterate, 0, line);
+        self.modify_offset(1);
+        exit
+    }
+
+    /// number of values the innermost scope holds on the stack
+    pub fn stack_height(&self) -> usize {
+        self.offsets.last().unwrap().stack_ptr
+    }
+
+    /// forgets the locals declared since `table` was saved, once the statements that declared them
+    /// have been popped off the stack; globals keep their slots
+    pub fn restore_symbols(&mut self, table: &[Offset]) {
+        let scope = self.offsets.len() - 1;
+        if scope > 0 && scope < table.len() {
+            self.offsets[scope].symbols = table[scope].symbols.clone();
         }
     }
 
     pub fn emit_symbol(&mut self, id: String, line: usize) {
-        self.instructions.push(OpCode::Symbol as usize);
+        self.push_op(OpCode::Symbol, line);
 
 
         let mut loc = 0;
@@ -484,7 +907,7 @@ impl VM {
             let offset = &self.offsets[i as usize];
             if offset.symbols.contains_key(id.as_str()) {
                 let off = ptr + offset.stack_ptr as isize - offset.symbols[id.as_str()] as isize;
-                self.instructions.push(off as usize);
+                self.push_operand(off as usize);
                 break;
             }
             ptr += offset.stack_ptr as isize - 1;
@@ -497,60 +920,95 @@ impl VM {
             if offset.symbols.contains_key(id.as_str()) {
                 // symbol is in global scope, pull
                 let off = offset.symbols[id.as_str()];
-                self.instructions.push(off as usize);
-                self.instructions.push(1);
+                self.push_operand(off);
+                self.push_operand(1);
+            } else if self.registry.get(id.as_str()).is_some() || self.registry.constant(id.as_str()).is_some() {
+                // a native function or constant, declared as a global wherever it is first used
+                let off = self.globals;
+                self.globals += 1;
+                match self.registry.get(id.as_str()) {
+                    Some(native) => { self.natives.push((off, native)); }
+                    None => { self.constants.push((off, self.registry.constant(id.as_str()).unwrap())); }
+                }
+                self.offsets.first_mut().unwrap().symbols.insert(id, off);
+                self.push_operand(off);
+                self.push_operand(1);
             } else if self.offsets.len() == 1 {
                 // add symbol to global scope
-                let off: isize = -1;
+                let off = self.globals;
+                self.globals += 1;
                 let offset = self.offsets.last_mut().unwrap();
-                offset.symbols.insert(id, offset.symbols.len());
-                self.instructions.push(off as usize);
-                self.instructions.push(1);
+                offset.symbols.insert(id, off);
+                self.push_operand(off);
+                self.push_operand(1);
             } else {
                 // add symbol to a scope
                 let offset = self.offsets.last_mut().unwrap();
                 let off: isize = -1;
                 offset.symbols.insert(id, offset.stack_ptr);
-                self.instructions.push(off as usize);
-                self.instructions.push(0);
+                self.push_operand(off as usize);
+                self.push_operand(0);
             }
         } else {
             // symbol has already been found in a scope above global
-            self.instructions.push(0);
+            self.push_operand(0);
         }
 
 
         // add name pointer
-        self.instructions.push(loc);
+        self.push_operand(loc);
         self.modify_offset(1);
-        self.lines.push(line);
-        self.lines.push(line);
-        self.lines.push(line);
-        self.lines.push(line);
+    }
+
+    /// write a unit instruction (`OpCode::Unit` or `OpCode::Convert`) that applies a unit
+    /// expression to the value on top of the stack
+    pub fn emit_unit(&mut self, op: OpCode, unit: String, line: usize) {
+        self.consts.push(Value::String(unit));
+        self.push_op(op, line);
+        self.push_operand(self.consts.len() - 1);
     }
 
     pub fn emit_tuple(&mut self, size: usize, line: usize) {
-        self.instructions.push(OpCode::Tuple as usize);
-        self.instructions.push(size);
-        self.lines.push(line);
-        self.lines.push(line);
+        self.push_op(OpCode::Tuple, line);
+        self.push_operand(size);
         self.modify_offset(-(size as isize) + 1);
     }
 
+    /// write an `Index` or `IndexAssign` with `count` indices, the bits of `mask` mark the indices
+    /// given as `:`, which select everything and take no value off the stack
+    pub fn emit_index(&mut self, op: OpCode, count: usize, mask: usize, line: usize) {
+        self.push_op(op, line);
+        self.push_operand(count);
+        self.push_operand(mask);
+        let popped = (0..count).filter(|i| (mask >> i) & 1 == 0).count() as isize;
+        // an assignment also takes the value off the stack, and leaves it in place of the target
+        self.modify_offset(if op == OpCode::IndexAssign { -(popped + 1) } else { -popped });
+    }
+
+    /// write a `Range` of a start and an end, with a step between them if `parts` is 3
+    pub fn emit_range(&mut self, parts: usize, line: usize) {
+        self.push_op(OpCode::Range, line);
+        self.push_operand(parts);
+        self.modify_offset(1 - parts as isize);
+    }
+
+    /// write an `Unpack` that checks the value on top of the stack is a tuple of `count` elements
+    pub fn emit_unpack(&mut self, count: usize, line: usize) {
+        self.push_op(OpCode::Unpack, line);
+        self.push_operand(count);
+    }
+
     pub fn emit_matrix(&mut self, rows: usize, cols: usize, line: usize) {
-        self.instructions.push(OpCode::Matrix as usize);
-        self.instructions.push(rows);
-        self.instructions.push(cols);
-        self.lines.push(line);
-        self.lines.push(line);
-        self.lines.push(line);
+        self.push_op(OpCode::Matrix, line);
+        self.push_operand(rows);
+        self.push_operand(cols);
         self.modify_offset((rows * cols) as isize * -1);
         self.modify_offset(1); // matrix call
     }
 
 
-    pub fn emit_func_decl(&mut self, ids: Vec<String>, line: usize) {
-        self.instructions.push(OpCode::FnDecl as usize);
+    pub fn emit_func_decl(&mut self, name: String, ids: Vec<String>, line: usize) {
+        self.push_op(OpCode::FnDecl, line);
         self.modify_offset(1);
         let last_off = if self.offsets.len() == 1 { 0 } else { self.count_stack_ptrs() };
         self.offsets.push(Offset { symbols: HashMap::new(), stack_ptr: 0 });
@@ -559,18 +1017,15 @@ impl VM {
             offset.symbols.insert(ids[i].clone(), i);
         }
         offset.stack_ptr += ids.len(); // arguments and return address and previous stack
-        self.instructions.push(ids.len());
-        self.instructions.push(last_off);
-        self.lines.push(line);
-        self.lines.push(line);
-        self.lines.push(line);
+        self.push_operand(ids.len());
+        self.push_operand(last_off);
+        self.consts.push(Value::String(name));
+        self.push_operand(self.consts.len() - 1);
         self._func_decl = true;
     }
     pub fn emit_func_call(&mut self, args: usize, line: usize) {
-        self.instructions.push(OpCode::FnCall as usize);
-        self.instructions.push(args);
-        self.lines.push(line);
-        self.lines.push(line);
+        self.push_op(OpCode::FnCall, line);
+        self.push_operand(args);
 
         // modify offset
         self.modify_offset(-(args as isize)); // removing all arguments